@@ -0,0 +1,125 @@
+use core::mem::MaybeUninit;
+use arduino_hal::port::{mode::{Input, Output, PullUp}, Pin};
+use library::{DriverSlots, LimitSide, MappedAxes, SlotId, AUX_PORTS};
+
+// The board is picked at build time, RAMPS by default, `--no-default-features --features cnc-shield` for the
+// Arduino CNC Shield. Each board file lists its driver slots and other pins, and maps the logical axes onto
+// the slots.
+#[cfg(all(feature = "ramps", feature = "cnc-shield"))]
+compile_error!("Pick one board, either `ramps` or `cnc-shield`.");
+#[cfg(not(any(feature = "ramps", feature = "cnc-shield")))]
+compile_error!("No board picked, enable the `ramps` or `cnc-shield` feature.");
+
+#[cfg(feature = "ramps")]
+mod ramps;
+#[cfg(feature = "ramps")]
+pub use ramps::*;
+#[cfg(feature = "cnc-shield")]
+mod cnc_shield;
+#[cfg(feature = "cnc-shield")]
+pub use cnc_shield::*;
+
+/// Normally open switches to ground, triggered reads low.
+pub enum SlotLimits {
+    None,
+    Separate { min: Option<Pin<Input<PullUp>>>, max: Option<Pin<Input<PullUp>>> },
+    /// One input for both ends of travel. Only the side the axis is stepping toward is checked.
+    Shared(Pin<Input<PullUp>>),
+}
+
+impl SlotLimits {
+    pub fn triggered(&self, side: LimitSide) -> bool {
+        match (self, side) {
+            (SlotLimits::None, _) => false,
+            (SlotLimits::Separate { min, .. }, LimitSide::Min) => min.as_ref().is_some_and(|pin| pin.is_low()),
+            (SlotLimits::Separate { max, .. }, LimitSide::Max) => max.as_ref().is_some_and(|pin| pin.is_low()),
+            (SlotLimits::Shared(pin), _) => pin.is_low(),
+        }
+    }
+}
+
+/// One stepper driver socket.
+pub struct DriverSlot {
+    pub step: Pin<Output>,
+    pub dir: Pin<Output>,
+    /// `None` on boards with one enable for every driver.
+    pub enable: Option<Pin<Output>>,
+    pub limits: SlotLimits,
+}
+
+/// Everything but the serial port and the PWM outputs, those keep their peripheral in the type.
+pub struct BoardPins {
+    pub slots: [DriverSlot; SLOT_COUNT],
+    pub shared_enable: Option<Pin<Output>>,
+    pub flood: Pin<Output>,
+    pub mist: Pin<Output>,
+    pub aux_out: [Pin<Output>; AUX_PORTS],
+    pub aux_in: [Pin<Input<PullUp>>; AUX_PORTS],
+    pub pwm: BoardPwm,
+}
+
+pub static mut BOARD: MaybeUninit<BoardPins> = MaybeUninit::uninit();
+/// Slots held still while homing squares a ganged axis, one bit per slot. Shared by the machine's and the
+/// step interrupt's copies of the axes.
+static mut LOCKED_SLOTS: u8 = 0;
+
+/// The pins, for the length of `f` only. The step interrupt and the main loop both write pins that share a
+/// port, e.g. PORTF or PORTL on RAMPS, and a pin write is a read-modify-write of the whole port. Interrupts
+/// stay off for `f` so the step interrupt can't land in the middle and have its write undone, and no two
+/// `&mut BoardPins` are ever live at once.
+#[allow(static_mut_refs)]
+pub fn with_board<T>(f: impl FnOnce(&mut BoardPins) -> T) -> T {
+    avr_device::interrupt::free(|_| f(unsafe { BOARD.assume_init_mut() }))
+}
+
+pub fn set_pin(pin: &mut Pin<Output>, high: bool) {
+    if high { pin.set_high() } else { pin.set_low() }
+}
+
+// A4988 and DRV8825 drivers, on either board, are enabled with the pin low.
+fn set_enable(pin: &mut Pin<Output>, on: bool) { set_pin(pin, !on) }
+
+/// Every driver off. For the panic handler, doesn't go through the machine.
+pub fn disable_all_motors() {
+    with_board(|board| {
+        for slot in board.slots.iter_mut() {
+            if let Some(enable) = slot.enable.as_mut() {
+                set_enable(enable, false);
+            }
+        }
+        if let Some(enable) = board.shared_enable.as_mut() {
+            set_enable(enable, false);
+        }
+    })
+}
+
+#[derive(Clone, Copy)]
+pub struct DriverStaticSlots;
+impl DriverSlots for DriverStaticSlots {
+    fn step(&mut self, slot: SlotId, high: bool) { with_board(|board| set_pin(&mut board.slots[slot as usize].step, high)) }
+    fn dir(&mut self, slot: SlotId, high: bool) { with_board(|board| set_pin(&mut board.slots[slot as usize].dir, high)) }
+    fn enable(&mut self, slot: SlotId, on: bool) {
+        with_board(|board| {
+            if let Some(enable) = board.slots[slot as usize].enable.as_mut().or(board.shared_enable.as_mut()) {
+                set_enable(enable, on);
+            }
+        })
+    }
+    fn limit(&mut self, slot: SlotId, side: LimitSide) -> bool {
+        with_board(|board| board.slots[slot as usize].limits.triggered(side))
+    }
+    fn has_limits(&self, slot: SlotId) -> bool {
+        with_board(|board| !matches!(board.slots[slot as usize].limits, SlotLimits::None))
+    }
+    fn set_locked(&mut self, slot: SlotId, locked: bool) {
+        avr_device::interrupt::free(|_| unsafe {
+            if locked { LOCKED_SLOTS |= 1 << slot } else { LOCKED_SLOTS &= !(1 << slot) }
+        })
+    }
+    fn locked(&self, slot: SlotId) -> bool { unsafe { LOCKED_SLOTS & 1 << slot != 0 } }
+}
+
+/// Step, direction and enable for the machine and the step interrupt, limits for both.
+pub type DriverAxes = MappedAxes<DriverStaticSlots>;
+
+pub fn driver_axes() -> DriverAxes { MappedAxes::new(DriverStaticSlots, &AXIS_MAP) }
@@ -0,0 +1,108 @@
+use library::{AxisMap, SlotId, SlotMapping, XYZData};
+
+/*
+* Arduino CNC Shield V3 on the mega's uno header, grbl 0.8 layout.
+* X slot
+*   step    D2(PE4)
+*   dir     D5(PE3)
+*   limit   D9(PH6)
+* Y slot
+*   step    D3(PE5)
+*   dir     D6(PH3)
+*   limit   D10(PB4)
+* Z slot
+*   step    D4(PG5)
+*   dir     D7(PH4)
+*   limit   D11(PB5)
+* A slot, with the jumpers set to clone nothing
+*   step    D12(PB6)
+*   dir     D13(PB7)
+* Enable for every driver
+*   D8(PH5)
+* Coolant
+*   A3(PF3) flood
+*   A4(PF4) mist
+* Abort, hold, resume and probe headers, aux inputs 0-3
+*   in 0    A0(PF0)
+*   in 1    A1(PF1)
+*   in 2    A2(PF2)
+*   in 3    A5(PF5)
+* Mega only pins next to the shield, aux digital ports 0-3
+*   out 0   D22(PA0)
+*   out 1   D24(PA2)
+*   out 2   D26(PA4)
+*   out 3   D28(PA6)
+* No aux analog (pwm) ports, the shield takes every pin of timers 3 and 4 and the others are in use.
+*/
+
+pub const X_SLOT: SlotId = 0;
+pub const Y_SLOT: SlotId = 1;
+pub const Z_SLOT: SlotId = 2;
+// A is slot 3, unused.
+pub const SLOT_COUNT: usize = 4;
+
+pub static AXIS_MAP: AxisMap = AxisMap {
+    axes: XYZData {
+        x: &[SlotMapping::new(X_SLOT)],
+        y: &[SlotMapping::new(Y_SLOT)],
+        z: &[SlotMapping::new(Z_SLOT)],
+    },
+};
+
+pub struct BoardPwm;
+
+impl BoardPwm {
+    pub fn set_duty(&mut self, _port: u8, _duty: u8) {}
+}
+
+/// `BoardPins` from the peripherals and pins left after the serial port and timers are taken.
+macro_rules! take_board_pins {
+    ($dp:ident, $pins:ident) => {{
+        use crate::board::{BoardPins, BoardPwm, DriverSlot, SlotLimits};
+        BoardPins {
+            slots: [
+                DriverSlot {
+                    step: $pins.d2.into_output().downgrade(),
+                    dir: $pins.d5.into_output().downgrade(),
+                    enable: None,
+                    limits: SlotLimits::Shared($pins.d9.into_pull_up_input().downgrade()),
+                },
+                DriverSlot {
+                    step: $pins.d3.into_output().downgrade(),
+                    dir: $pins.d6.into_output().downgrade(),
+                    enable: None,
+                    limits: SlotLimits::Shared($pins.d10.into_pull_up_input().downgrade()),
+                },
+                DriverSlot {
+                    step: $pins.d4.into_output().downgrade(),
+                    dir: $pins.d7.into_output().downgrade(),
+                    enable: None,
+                    limits: SlotLimits::Shared($pins.d11.into_pull_up_input().downgrade()),
+                },
+                DriverSlot {
+                    step: $pins.d12.into_output().downgrade(),
+                    dir: $pins.d13.into_output().downgrade(),
+                    enable: None,
+                    limits: SlotLimits::None,
+                },
+            ],
+            shared_enable: Some($pins.d8.into_output().downgrade()),
+            flood: $pins.a3.into_output().downgrade(),
+            mist: $pins.a4.into_output().downgrade(),
+            aux_out: [
+                $pins.d22.into_output().downgrade(),
+                $pins.d24.into_output().downgrade(),
+                $pins.d26.into_output().downgrade(),
+                $pins.d28.into_output().downgrade(),
+            ],
+            aux_in: [
+                $pins.a0.into_pull_up_input().downgrade(),
+                $pins.a1.into_pull_up_input().downgrade(),
+                $pins.a2.into_pull_up_input().downgrade(),
+                $pins.a5.into_pull_up_input().downgrade(),
+            ],
+            pwm: BoardPwm,
+        }
+    }};
+}
+pub(crate) use take_board_pins;
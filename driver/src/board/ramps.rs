@@ -0,0 +1,151 @@
+use arduino_hal::{hal::port::{PE3, PH3}, port::{mode::PwmOutput, Pin}, simple_pwm::{Timer3Pwm, Timer4Pwm}};
+use library::{AxisMap, SlotId, SlotMapping, XYZData};
+
+/*
+* Arduino mega ramps 1.4 pinout.
+* X slot
+*   step    A0(PF0)
+*   dir     A1(PF1)
+*   enable  D38(PD7)
+*   min     D3(PE5)
+*   max     D2(PE4)
+* Y slot
+*   step    A6(PF6)
+*   dir     A7(PF7)
+*   enable  A2(PF2)
+*   min     D14(PJ1)
+*   max     D15(PJ0)
+* Z slot
+*   step    D46(PL3)
+*   dir     D48(PL1)
+*   enable  A8(PK0)
+*   min     D18(PD3)
+*   max     D19(PD2)
+* E0 slot, second Y motor
+*   step    D26(PA4)
+*   dir     D28(PA6)
+*   enable  D24(PA2)
+*   min     A13(PK5) T0 thermistor header, the board's pull up is enough for a switch to ground
+* E1 slot, no endstops
+*   step    D36(PC1)
+*   dir     D34(PC3)
+*   enable  D30(PC7)
+* MOSFET outputs
+*   D8(PH5)  flood coolant
+*   D9(PH6)  mist coolant
+*   D10(PB4) spare
+* AUX-2 header, aux digital ports 0-2 and inputs 0-3
+*   out 0   D40(PG1)
+*   out 1   D42(PL7)
+*   out 2   D44(PL5)
+*   in 0    A5(PF5)
+*   in 1    A9(PK1)
+*   in 2    A10(PK2)
+*   in 3    A11(PK3)
+* AUX-4 header
+*   out 3   D32(PC5)
+* Servo header, aux analog (pwm) ports 0-1
+*   pwm 0   D5(PE3)
+*   pwm 1   D6(PH3)
+*/
+
+pub const X_SLOT: SlotId = 0;
+pub const Y_SLOT: SlotId = 1;
+pub const Z_SLOT: SlotId = 2;
+pub const E0_SLOT: SlotId = 3;
+// E1 is slot 4, unused.
+pub const SLOT_COUNT: usize = 5;
+
+// On my cnc the Z slot drives X and the X slot drives Z, limits go with the slot so they swap too.
+// Y2 is on E0, it steps with Y and homing squares the gantry on the Y and E0 min switches.
+pub static AXIS_MAP: AxisMap = AxisMap {
+    axes: XYZData {
+        x: &[SlotMapping::new(Z_SLOT)],
+        y: &[SlotMapping::new(Y_SLOT), SlotMapping::new(E0_SLOT)],
+        z: &[SlotMapping::new(X_SLOT)],
+    },
+};
+
+pub struct BoardPwm {
+    pwm0: Pin<PwmOutput<Timer3Pwm>, PE3>,
+    pwm1: Pin<PwmOutput<Timer4Pwm>, PH3>,
+}
+
+impl BoardPwm {
+    pub fn new(pwm0: Pin<PwmOutput<Timer3Pwm>, PE3>, pwm1: Pin<PwmOutput<Timer4Pwm>, PH3>) -> Self {
+        Self { pwm0, pwm1 }
+    }
+
+    pub fn set_duty(&mut self, port: u8, duty: u8) {
+        match port {
+            0 => self.pwm0.set_duty(duty),
+            1 => self.pwm1.set_duty(duty),
+            _ => {},
+        }
+    }
+}
+
+/// `BoardPins` from the peripherals and pins left after the serial port and timers are taken.
+macro_rules! take_board_pins {
+    ($dp:ident, $pins:ident) => {{
+        use arduino_hal::simple_pwm::{IntoPwmPin, Prescaler, Timer3Pwm, Timer4Pwm};
+        use crate::board::{BoardPins, BoardPwm, DriverSlot, SlotLimits};
+        let timer3 = Timer3Pwm::new($dp.TC3, Prescaler::Prescale64);
+        let timer4 = Timer4Pwm::new($dp.TC4, Prescaler::Prescale64);
+        let mut pwm0 = $pins.d5.into_output().into_pwm(&timer3);
+        let mut pwm1 = $pins.d6.into_output().into_pwm(&timer4);
+        pwm0.enable();
+        pwm1.enable();
+        BoardPins {
+            slots: [
+                DriverSlot {
+                    step: $pins.a0.into_output().downgrade(),
+                    dir: $pins.a1.into_output().downgrade(),
+                    enable: Some($pins.d38.into_output().downgrade()),
+                    limits: SlotLimits::Separate { min: Some($pins.d3.into_pull_up_input().downgrade()), max: Some($pins.d2.into_pull_up_input().downgrade()) },
+                },
+                DriverSlot {
+                    step: $pins.a6.into_output().downgrade(),
+                    dir: $pins.a7.into_output().downgrade(),
+                    enable: Some($pins.a2.into_output().downgrade()),
+                    limits: SlotLimits::Separate { min: Some($pins.d14.into_pull_up_input().downgrade()), max: Some($pins.d15.into_pull_up_input().downgrade()) },
+                },
+                DriverSlot {
+                    step: $pins.d46.into_output().downgrade(),
+                    dir: $pins.d48.into_output().downgrade(),
+                    enable: Some($pins.a8.into_output().downgrade()),
+                    limits: SlotLimits::Separate { min: Some($pins.d18.into_pull_up_input().downgrade()), max: Some($pins.d19.into_pull_up_input().downgrade()) },
+                },
+                DriverSlot {
+                    step: $pins.d26.into_output().downgrade(),
+                    dir: $pins.d28.into_output().downgrade(),
+                    enable: Some($pins.d24.into_output().downgrade()),
+                    limits: SlotLimits::Separate { min: Some($pins.a13.into_pull_up_input().downgrade()), max: None },
+                },
+                DriverSlot {
+                    step: $pins.d36.into_output().downgrade(),
+                    dir: $pins.d34.into_output().downgrade(),
+                    enable: Some($pins.d30.into_output().downgrade()),
+                    limits: SlotLimits::None,
+                },
+            ],
+            shared_enable: None,
+            flood: $pins.d8.into_output().downgrade(),
+            mist: $pins.d9.into_output().downgrade(),
+            aux_out: [
+                $pins.d40.into_output().downgrade(),
+                $pins.d42.into_output().downgrade(),
+                $pins.d44.into_output().downgrade(),
+                $pins.d32.into_output().downgrade(),
+            ],
+            aux_in: [
+                $pins.a5.into_pull_up_input().downgrade(),
+                $pins.a9.into_pull_up_input().downgrade(),
+                $pins.a10.into_pull_up_input().downgrade(),
+                $pins.a11.into_pull_up_input().downgrade(),
+            ],
+            pwm: BoardPwm::new(pwm0, pwm1),
+        }
+    }};
+}
+pub(crate) use take_board_pins;
@@ -1,85 +1,213 @@
-use core::str::from_utf8_unchecked;
 use arrayvec::ArrayVec;
-use library::{CanSend, CircularBuffer, GcodeCommand, ParseUnion};
-use crate::{pins::{write_uart, READER}, write_uart_u8};
+use library::{parse_system_command, CanSend, CircularBuffer, ErrorCode, Feedback, GcodeCommand, LineAssembler, Message, ParseUnion, RealtimeCommand, Response, SystemCommand, PLANNER_BLOCKS};
+use crate::pins::READER;
 use embedded_hal::serial::Read;
+use core::sync::atomic::{AtomicBool, Ordering};
 
+/*
+* Streaming, the same as grbl's character counting:
+*   Every line gets exactly one `ok` or `error:n`, blank and rejected lines included. It comes once the line has
+*   left the RX buffer, and for gcode once the command is in the channel to the machine.
+*   The RX buffer holds `RX_SIZE` bytes, `$I` reports it as `[OPT:,<planner blocks>,<rx bytes>]`. A sender keeps
+*   the bytes of lines it has no response for at or below that and the buffer never overflows.
+*   Realtime bytes (`?`, ctrl-x) bypass the buffer and don't count. After a reset the buffer is empty.
+*   With bit 1 of `$10` set status reports end in `|Bf:<planner blocks free>,<rx bytes free>`.
+*/
 const INPUT_BUFFER_SIZE: usize = 200;
-const RX_SIZE: usize = 100;
-static mut RX_BUFFER: CircularBuffer<u8, RX_SIZE> = CircularBuffer::<u8, RX_SIZE>{data:[0u8; RX_SIZE], begin: 0, length: 0};
+pub const RX_SIZE: usize = 128;
+/// Bounded, a byte that doesn't fit is counted and reported rather than overwriting unread input.
+static mut RX_BUFFER: CircularBuffer<u8, RX_SIZE> = CircularBuffer::new_bounded(0);
+/// Latched straight from the RX interrupt, however busy the main loop is neither gets lost.
+static RESET: AtomicBool = AtomicBool::new(false);
+static STATUS_REPORT: AtomicBool = AtomicBool::new(false);
+/// The other realtime bytes. A full ring drops one, which the sender sees and sends again.
+static mut REALTIME: CircularBuffer<u8, 4> = CircularBuffer::new_bounded(0);
 
 #[avr_device::interrupt(atmega2560)]
 #[allow(static_mut_refs)]
 fn USART0_RX() {
     if let Ok(b) = unsafe{READER.assume_init_mut().read()} {
-        unsafe{RX_BUFFER.push(b)};
+        match RealtimeCommand::from_byte(b) {
+            Some(RealtimeCommand::Reset) => RESET.store(true, Ordering::Relaxed),
+            Some(RealtimeCommand::StatusReport) => STATUS_REPORT.store(true, Ordering::Relaxed),
+            Some(_) => { let _ = unsafe{REALTIME.push(b)}; },
+            None => { let _ = unsafe{RX_BUFFER.push(b)}; },
+        }
     }
 }
 
+/// With interrupts off, the RX interrupt can't set it again in between.
+fn take_flag(flag: &AtomicBool) -> bool {
+    let set = flag.load(Ordering::Relaxed);
+    flag.store(false, Ordering::Relaxed);
+    set
+}
+
+/// Free bytes for `Bf:`.
+#[allow(static_mut_refs)]
+pub fn rx_free() -> usize {
+    avr_device::interrupt::free(|_| RX_SIZE - unsafe{RX_BUFFER.len()})
+}
+
 #[allow(unused)]
-pub struct Parser<F>
+pub struct Parser<F, R>
     where 
         F: CanSend<GcodeCommand>,
+        R: CanSend<Response>,
 {
     send: F,
+    responses: R,
     to_send: Option<GcodeCommand>,
-    input_bufer:ArrayVec<u8, INPUT_BUFFER_SIZE>,
+    input_line: LineAssembler<INPUT_BUFFER_SIZE>,
+    realtime: ArrayVec<RealtimeCommand, 4>,
+    system: Option<SystemCommand>,
+    /// Responses the TX ring had no room for yet. They go out in order before the next line is read or parsed.
+    /// Nothing is read or parsed while any are waiting, and one pass of either adds at most two.
+    replies: ArrayVec<Response, 4>,
 }
 
-impl<F> Parser<F>
+impl<F, R> Parser<F, R>
     where
         F: CanSend<GcodeCommand>,
+        R: CanSend<Response>,
 {
-    pub fn new(send: F) -> Self {
+    pub fn new(send: F, responses: R) -> Self {
         Self {
             send,
-            input_bufer: ArrayVec::new(),
+            responses,
+            input_line: LineAssembler::new(),
             to_send: None,
+            realtime: ArrayVec::new(),
+            system: None,
+            replies: ArrayVec::new(),
+        }
+    }
+
+    pub fn take_realtime(&mut self) -> Option<RealtimeCommand> {
+        if self.realtime.is_empty() { None }
+        else { Some(self.realtime.remove(0)) }
+    }
+
+    pub fn take_system(&mut self) -> Option<SystemCommand> { self.system.take() }
+
+    fn reply(&mut self, response: Response) {
+        let queued = self.replies.try_push(response).is_ok();
+        debug_assert!(queued, "reply dropped, a counting sender would lose track");
+        self.flush_replies();
+    }
+
+    fn flush_replies(&mut self) {
+        while let Some(&response) = self.replies.first() {
+            if self.responses.send(response).is_err() {
+                return;
+            }
+            self.replies.remove(0);
+        }
+    }
+
+    fn handle_realtime(&mut self, command: RealtimeCommand) {
+        if command == RealtimeCommand::Reset {
+            self.input_line.reset();
+            self.to_send = None;
+            self.replies.clear();
+            self.realtime.clear();
+        }
+        // A second one pending does nothing the first won't, so there's always room.
+        if !self.realtime.contains(&command) {
+            let _ = self.realtime.try_push(command);
         }
     }
 
+    /// Every pass, whatever else is held up.
     #[allow(static_mut_refs)]
-    pub fn read_serial(&mut self) {
-        if self.input_bufer.remaining_capacity() > 0 && !unsafe{RX_BUFFER.is_empty()} {
-            avr_device::interrupt::free(|_| {
-                for b in unsafe{RX_BUFFER.consume()} {
-                    //write_uart_u8(&[b]);
-                    let r = self.input_bufer.try_push(b);
-                    if r.is_err() {
-                        break;
-                    }
+    pub fn read_realtime(&mut self) {
+        avr_device::interrupt::free(|_| {
+            // Everything before a reset is dropped with it.
+            if take_flag(&RESET) {
+                unsafe {
+                    RX_BUFFER.clear();
+                    REALTIME.clear();
                 }
-            });
+                self.handle_realtime(RealtimeCommand::Reset);
+            }
+            if take_flag(&STATUS_REPORT) {
+                self.handle_realtime(RealtimeCommand::StatusReport);
+            }
+            while let Some(command) = unsafe{REALTIME.pop()}.and_then(RealtimeCommand::from_byte) {
+                self.handle_realtime(command);
+            }
+        });
+    }
+
+    /// Sends the response for the last line and then reads the next one in.
+    #[allow(static_mut_refs)]
+    pub fn read_serial(&mut self) {
+        self.flush_replies();
+        if let Some(command) = self.to_send.take() {
+            match self.send.send(command) {
+                Ok(()) => self.reply(Response::Ok),
+                Err(command) => self.to_send = Some(command),
+            }
         }
-        if self.to_send.is_some() {
-            self.to_send = self.send.send(self.to_send.take().unwrap()).map(|_| {
-                write_uart("ok\r\n");
-                None
-            }).unwrap_or_else(|e| Some(e));
+        // The line's response has to go first. Until it has, the next line's bytes stay in the ring, which
+        // holds everything a counting sender has sent.
+        if self.to_send.is_some() || !self.replies.is_empty() {
+            return;
+        }
+        let (overflows, error) = avr_device::interrupt::free(|_| {
+            let rx = unsafe{&mut RX_BUFFER};
+            // A line waiting to be parsed holds up the rest, which stays in the ring instead of being dropped.
+            // Stops at an error so it's sent with interrupts on.
+            let mut error = None;
+            while self.input_line.line().is_none() && error.is_none() {
+                let Some(b) = rx.pop() else {
+                    break;
+                };
+                error = self.input_line.push(b).err();
+            }
+            (rx.take_overflows(), error)
+        });
+        if let Some(code) = error {
+            self.reply(Response::Error(code));
+        }
+        if overflows > 0 {
+            self.reply(Response::Message(Message::RxOverflow));
         }
     }
 
     pub fn parse_buffer(&mut self) {
-        if self.to_send.is_some() || self.input_bufer.len() == 0 {
+        // Replies still waiting for the TX ring hold up the next line, so nothing is answered out of order.
+        if self.to_send.is_some() || self.system.is_some() || !self.replies.is_empty() {
             return;
         }
-        let endl_index = self.input_bufer.iter().position(|&c| c == b'\n' || c == b'\r');
-        if let Some(endl_index) = endl_index {
-            if endl_index > 0 {
-                let to_parse = self.input_bufer.split_at(endl_index).0;
-                let parse_result = library::parse(unsafe{from_utf8_unchecked(to_parse)});
-                if let Ok(ParseUnion::GCodeCommand(parsed)) = parse_result {
-                    self.to_send = Some(parsed);
-                }
-                else if let Err(err) = parse_result {
-                    write_uart("unrecognized command: ");
-                    write_uart(&err);
-                    write_uart("\n");
-                    write_uart_u8(&to_parse);
-                }
+        let Some(to_parse) = self.input_line.line() else {
+            return;
+        };
+        if to_parse.starts_with('$') {
+            match parse_system_command(to_parse.as_bytes()) {
+                // The RX size is only known here, the machine never sees it.
+                Some(SystemCommand::BuildInfo) => {
+                    self.reply(Response::Feedback(Feedback::BuildInfo { planner_blocks: PLANNER_BLOCKS, rx_size: RX_SIZE }));
+                    self.reply(Response::Ok);
+                },
+                Some(command) => self.system = Some(command),
+                None => self.reply(Response::Error(ErrorCode::InvalidStatement)),
+            }
+        }
+        else if to_parse.is_empty() {
+            self.reply(Response::Ok);
+        }
+        else {
+            match library::parse(to_parse) {
+                Ok(ParseUnion::GCodeCommand(parsed)) => self.to_send = Some(parsed),
+                Ok(_) => self.reply(Response::Error(ErrorCode::UnsupportedCommand)),
+                Err(err) => {
+                    self.reply(Response::Message(Message::Text(err)));
+                    self.reply(Response::Error(ErrorCode::UnsupportedCommand));
+                },
             }
-            self.input_bufer.drain(0..endl_index+1);
         }
+        self.input_line.finish_line();
         //if let Ok(parsed) = parsed {
             //ufmt::uwriteln!(&mut serial_writer, "parsed gcode {}.{}", parsed.command_id.major, parsed.command_id.minor).unwrap();
         //}
@@ -2,19 +2,26 @@
 #![no_main]
 #![feature(abi_avr_interrupt)]
 
+mod board;
 mod my_clock;
 mod gcode_parser;
 mod pins;
+mod stepper_interrupt;
+mod uart_tx;
 
 use arduino_hal::delay_ms;
-use my_clock::micros;
+use board::{disable_all_motors, driver_axes};
+use my_clock::DriverClock;
+use uart_tx::UartResponses;
 use pins::*;
 use library::*;
 use core::panic::PanicInfo;
 
 #[panic_handler]
 fn panic(_: &PanicInfo) -> ! {
-    write_uart("!panic handler!\n");
+    avr_device::interrupt::disable();
+    disable_all_motors();
+    write_uart_blocking(b"!panic handler!\n");
     let dp = unsafe{arduino_hal::Peripherals::steal()};
     let pins = arduino_hal::pins!(dp);
     let mut led = pins.d13.into_output();
@@ -31,6 +38,12 @@ fn panic(_: &PanicInfo) -> ! {
     }
 }
 
+/// Banner then buffer sizes, a sender knows the RX buffer is empty from here.
+fn welcome(responses: &impl CanSend<Response>) {
+    let _ = responses.send(Response::Feedback(Feedback::Welcome));
+    let _ = responses.send(Response::Feedback(Feedback::BuildInfo { planner_blocks: PLANNER_BLOCKS, rx_size: gcode_parser::RX_SIZE }));
+}
+
 struct PollCounter {
     counter: u8,
     target: u8,
@@ -50,13 +63,15 @@ impl PollCounter {
 #[arduino_hal::entry]
 #[allow(static_mut_refs)]
 fn main() -> ! {
-    unsafe{init_static_pins()};
+    let mut segments = unsafe{init_static_pins()};
 
-    let reciever = SplitChannel::new(library::Channel::<GcodeCommand, 3>::default());
-    let sender = reciever.create_sender();
+    let mut commands = SpscQueue::<GcodeCommand, 3>::new();
+    let (sender, reciever) = commands.split();
 
-    let mut parse_input = gcode_parser::Parser::new(sender);
-    let mut machine = Machine::new(DriverStaticStepDir{});
+    let responses = UartResponses;
+    let mut parse_input = gcode_parser::Parser::new(sender, responses);
+    let mut machine = Machine::new(driver_axes(), DriverStaticCoolant{}, DriverStaticAuxIo{}, driver_axes(), DriverStaticStorage{});
+    let clock = DriverClock;
 
     // command is g0 x100
     //let mut parsed = GcodeCommand::default();
@@ -70,38 +85,51 @@ fn main() -> ! {
 
 
     unsafe { avr_device::interrupt::enable(); }
+    welcome(&responses);
 
     //let mut buffer: str_buf::StrBuf<100> = str_buf::StrBuf::new();
             //ufmt::uwriteln!(buffer, "time for step monitor{}", diff).unwrap();
             //write_uart(buffer.as_str());
-    delay_ms(1); // stepper polling requires that now(time) != 0.
     let mut task_serial = PollCounter::new(1);
     let mut task_parse = PollCounter::new(255);
     let mut task_calc = PollCounter::new(10);
-    let mut task_step_counter:u8 = 0u8;
     loop {
-        if let Some(_) = task_serial.poll_check() {
-            parse_input.read_serial();
+        // A `$` reply still going out would get the next line's answers mixed into it.
+        if task_serial.poll_check().is_some() {
+            parse_input.read_realtime();
+            if !machine.replying() {
+                parse_input.read_serial();
+            }
         }
-        if let Some(_) = task_parse.poll_check() {
+        if task_parse.poll_check().is_some() && !machine.replying() {
             parse_input.parse_buffer();
         }
         if let Some(_) = task_calc.poll_check() {
-            machine.poll_task(&reciever);
+            machine.poll_task(clock.micros(), &reciever);
         }
-
-        let tsc = task_step_counter;
-        let axis = match tsc {
-            1 => Some(XYZId::X),
-            2 => Some(XYZId::Y),
-            3 => {task_step_counter = 0; Some(XYZId::Z)},
-            _ => {task_step_counter = 0; None},
-        };
-        if let Some(axis) = axis {
-            let now = micros();
-            machine.step_monitor(now, axis);
+        // Every pass, the ring only holds a few segments.
+        machine.prep_segments(&mut segments);
+        while let Some(command) = parse_input.take_realtime() {
+            match command {
+                RealtimeCommand::StatusReport => {
+                    let rx_free = machine.settings().report_buffer().then(gcode_parser::rx_free);
+                    let _ = responses.send(Response::StatusReport(machine.status(), rx_free));
+                },
+                RealtimeCommand::Reset => {
+                    machine.reset(&reciever);
+                    // Output from before the reset is stale, and the banner has to fit.
+                    uart_tx::clear();
+                    welcome(&responses);
+                },
+                RealtimeCommand::CycleStart => machine.cycle_start(),
+            }
+        }
+        if !machine.replying() {
+            if let Some(command) = parse_input.take_system() {
+                machine.respond_system_command(command, &responses);
+            }
         }
-        task_step_counter += 1;
+        machine.report_task(&responses);
 
         //next_command = sender2.send(next_command).map(|()| {
             //write_uart("next command!\n");
@@ -1,73 +1,59 @@
-// Possible Values for ms:
-// ╔═══════════╦══════════════╦═══════════════════╗
-// ║ PRESCALER ║ TIMER_COUNTS ║ Overflow Interval ║
-// ╠═══════════╬══════════════╬═══════════════════╣
-// ║        64 ║          250 ║              1 ms ║
-// ║       256 ║          125 ║              2 ms ║
-// ║       256 ║          250 ║              4 ms ║
-// ║      1024 ║          125 ║              8 ms ║
-// ║      1024 ║          250 ║             16 ms ║
-// ╚═══════════╩══════════════╩═══════════════════╝
-const PRESCALER: u32 = 64;
-const TIMER_COUNTS: u32 = 250;
+use library::{Clock, TimerExtension};
 
-#[allow(non_camel_case_types)]
-type ms = u32;
+// TC5 free running at the CPU clock, 16 ticks per us. It overflows every 65536 cycles, 4.096ms, and that's
+// the only interrupt timekeeping takes.
+const TICKS_PER_US: u32 = 16;
 
-const MILLIS_INCREMENT: ms = PRESCALER as ms * TIMER_COUNTS as ms / 16000;
+static mut TIMEBASE: TimerExtension<TICKS_PER_US> = TimerExtension::new();
+static mut TC5: core::mem::MaybeUninit<arduino_hal::pac::TC5> = core::mem::MaybeUninit::uninit();
 
-static mut MICROS_COUNTER: u32 = 0;
-static mut MILLIS_COUNTER: ms = 0;
-
-pub fn clock_init(tc0: arduino_hal::pac::TC0, tc2: arduino_hal::pac::TC2) {
-    //arduino_hal::pac::TC Configure the timer for the above interval (in CTC mode)
-    tc0.tccr0a.write(|w| w.wgm0().ctc());
-    tc0.ocr0a.write(|w| unsafe { w.bits(TIMER_COUNTS as u8) });
-    tc0.tccr0b.write(|w| match PRESCALER {
-        8 => w.cs0().prescale_8(),
-        64 => w.cs0().prescale_64(),
-        256 => w.cs0().prescale_256(),
-        1024 => w.cs0().prescale_1024(),
-        _ => panic!(),
-    });
-    tc0.timsk0.write(|w| w.ocie0a().set_bit());
-
-    // micros registers
-    tc2.tccr2a.write(|w| w.wgm2().ctc());
-    tc2.ocr2a.write(|w| unsafe { w.bits(125) });
-    tc2.tccr2b.write(|w| w.cs2().direct());
-    tc2.timsk2.write(|w| w.ocie2a().set_bit());
-
-    //tc1.tccr1a.write(|w| w.wgm1().bits(1));
-    //tc1.ocr1a.write(|w| unsafe { w.bits(125 as u16) });
-    //tc1.tccr1b.write(|w| w.cs1().direct());
-    //tc1.timsk1.write(|w| w.ocie1a().set_bit());
+pub fn clock_init(tc5: arduino_hal::pac::TC5) {
+    tc5.tccr5a.write(|w| w.wgm5().bits(0));
+    tc5.tccr5b.write(|w| w.cs5().direct());
+    tc5.timsk5.write(|w| w.toie5().set_bit());
+    #[allow(static_mut_refs)]
+    unsafe{TC5.write(tc5)};
     reset_time();
 }
 
+#[allow(static_mut_refs)]
 pub fn reset_time() {
-    unsafe{MILLIS_COUNTER = 0};
-    unsafe{MICROS_COUNTER = 0;}
+    avr_device::interrupt::free(|_| unsafe {
+        let tc5 = TC5.assume_init_mut();
+        tc5.tcnt5.write(|w| w.bits(0));
+        tc5.tifr5.write(|w| w.tov5().set_bit()); // writing 1 clears it.
+        TIMEBASE = TimerExtension::new();
+    });
 }
 
 #[avr_device::interrupt(atmega2560)]
-fn TIMER0_COMPA() {
-    unsafe{MILLIS_COUNTER+=MILLIS_INCREMENT};
+#[allow(static_mut_refs)]
+fn TIMER5_OVF() {
+    unsafe{TIMEBASE.overflow()};
 }
 
-#[avr_device::interrupt(atmega2560)]
-fn TIMER2_COMPA() {
-    unsafe{MICROS_COUNTER+=1};
+/// Counter then overflow flag, with interrupts off so the overflow count can't change in between.
+#[allow(static_mut_refs)]
+fn read<R>(f: impl FnOnce(&TimerExtension<TICKS_PER_US>, u16, bool) -> R) -> R {
+    avr_device::interrupt::free(|_| unsafe {
+        let tc5 = TC5.assume_init_ref();
+        let count = tc5.tcnt5.read().bits();
+        let pending = tc5.tifr5.read().tov5().bit_is_set();
+        f(&TIMEBASE, count, pending)
+    })
 }
 
 #[allow(unused)]
 pub fn millis() -> u32 {
-    (unsafe {MILLIS_COUNTER}) as u32
+    read(|timebase, count, pending| timebase.millis(count, pending))
+}
+
+pub fn micros() -> u32 {
+    read(|timebase, count, pending| timebase.micros(count, pending))
 }
 
-//const POST_SCALAR:f32 = 16.0 * 0.9921;
-pub fn micros() -> u64 {
-    let counter = unsafe {MICROS_COUNTER};
-    //(counter as f32 * POST_SCALAR) as u64
-    counter as u64 * 1000  / 128
+pub struct DriverClock;
+
+impl Clock for DriverClock {
+    fn micros(&self) -> u32 { micros() }
 }
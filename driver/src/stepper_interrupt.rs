@@ -1,22 +1,136 @@
-use library::XYZId;
-
-use crate::my_clock::micros;
-
-static mut TEST_COUNTER: u64 = 0;
-pub fn stepper_interrupt_init(treg: arduino_hal::pac::TC1) {
-    // micros registers
-    treg.tccr1a.write(|w| w.wgm1().bits(2));
-    treg.ocr1a.write(|w| unsafe { w.bits(u16::MAX) });
-    treg.tccr1b.write(|w| w.cs1().direct());
-    treg.timsk1.write(|w| w.ocie1a().set_bit());
-    unsafe{TEST_COUNTER = 0;}
+use core::mem::MaybeUninit;
+use library::{CanSend, Consumer, LimitSwitch, LimitWatch, Producer, Segment, SegmentExecutor, SegmentOutput, SegmentRing, StepSignal, StepTimer, XYZData};
+
+use crate::board::{driver_axes, DriverAxes};
+
+// TC1 free running at clk/8, it wraps every 32.8ms. Each compare is set from the previous one rather than
+// from whenever the interrupt got round to it, so latency and the executor's own time don't add up.
+const TICKS_PER_US: u32 = 2;
+// Half the counter, a compare that's already passed still reads as behind rather than far ahead.
+const MAX_DELAY_US: u32 = i16::MAX as u32 / TICKS_PER_US;
+/// A compare closer than this to the counter may be passed before OCR1A is written.
+const MIN_LEAD_TICKS: u16 = 8;
+
+static mut TC1: MaybeUninit<arduino_hal::pac::TC1> = MaybeUninit::uninit();
+/// Step time of the event being handled, us.
+static mut STEP_TIME: u32 = 0;
+/// Delay programmed into OCR1A, added to `STEP_TIME` when it fires.
+static mut PROGRAMMED_US: u32 = 0;
+/// Counter value the programmed event is due at, the base for the next one.
+static mut DUE_TICKS: u16 = 0;
+static mut ARMED: bool = false;
+
+const RING_SIZE: usize = 8;
+/// Split once by `stepper_interrupt_init`, never touched directly after that.
+static mut SEGMENTS: SegmentRing<RING_SIZE> = SegmentRing::new();
+/// The ring's consumer end and the executor. Only the interrupt touches them, the main loop with interrupts off.
+static mut SEGMENT_SOURCE: MaybeUninit<Consumer<'static, Segment, RING_SIZE>> = MaybeUninit::uninit();
+/// Reads the switches itself before every step, hard limits and homing stop from the interrupt.
+static mut EXECUTOR: MaybeUninit<SegmentExecutor<DriverAxes, DriverAxes>> = MaybeUninit::uninit();
+
+/// Call once, the returned `DriverSegments` is the only producer for the ring.
+pub fn stepper_interrupt_init(treg: arduino_hal::pac::TC1) -> DriverSegments {
+    treg.tccr1a.write(|w| w.wgm1().bits(0));
+    treg.tccr1b.write(|w| w.wgm1().bits(0).cs1().prescale_8());
+    treg.timsk1.write(|w| w.ocie1a().clear_bit());
+    #[allow(static_mut_refs)]
+    let ring = unsafe {
+        TC1.write(treg);
+        EXECUTOR.write(SegmentExecutor::new(driver_axes(), driver_axes()));
+        let (ring, source) = SEGMENTS.split();
+        SEGMENT_SOURCE.write(source);
+        ring
+    };
+    DriverSegments { ring }
 }
 
-#[avr_device::interrupt(atmega2560)]
-fn TIMER1_COMPA() {
-    unsafe{TEST_COUNTER+=1};
+pub struct Tc1StepTimer;
+
+impl StepTimer for Tc1StepTimer {
+    fn schedule(&mut self, delay_us: u32) {
+        // Longer delays fire early and the executor reschedules the rest.
+        let delay_us = delay_us.clamp(1, MAX_DELAY_US);
+        #[allow(static_mut_refs)]
+        let tc1 = unsafe{TC1.assume_init_mut()};
+        let now = tc1.tcnt1.read().bits();
+        let armed = unsafe{ARMED};
+        let due = if armed { unsafe{DUE_TICKS} } else { now }.wrapping_add((delay_us * TICKS_PER_US) as u16);
+        // Already passed, or too close to make: fire as soon as possible. `DUE_TICKS` and `STEP_TIME` keep
+        // the programmed time, this event is late but the ones after it aren't.
+        let compare = if (due.wrapping_sub(now) as i16) < MIN_LEAD_TICKS as i16 { now.wrapping_add(MIN_LEAD_TICKS) } else { due };
+        tc1.ocr1a.write(|w| unsafe { w.bits(compare) });
+        if !armed {
+            // Matches from while it was stopped, writing 1 clears it.
+            tc1.tifr1.write(|w| w.ocf1a().set_bit());
+            tc1.timsk1.write(|w| w.ocie1a().set_bit());
+        }
+        unsafe {
+            PROGRAMMED_US = delay_us;
+            DUE_TICKS = due;
+            ARMED = true;
+        }
+    }
+
+    fn stop(&mut self) {
+        #[allow(static_mut_refs)]
+        let tc1 = unsafe{TC1.assume_init_mut()};
+        tc1.timsk1.write(|w| w.ocie1a().clear_bit());
+        unsafe{ARMED = false};
+    }
 }
 
-pub fn counter() -> u64 {
-    unsafe {TEST_COUNTER}
+/// Interrupts are off inside `f`, the executor and the ring's consumer end are the interrupt's.
+#[allow(static_mut_refs)]
+fn with_executor<R>(f: impl FnOnce(&mut SegmentExecutor<DriverAxes, DriverAxes>, &Consumer<'static, Segment, RING_SIZE>) -> R) -> R {
+    avr_device::interrupt::free(|_| unsafe { f(EXECUTOR.assume_init_mut(), SEGMENT_SOURCE.assume_init_ref()) })
+}
+
+/// Main loop side of the segment ring for `Machine::prep_segments`.
+pub struct DriverSegments {
+    ring: Producer<'static, Segment, RING_SIZE>,
+}
+
+impl SegmentOutput for DriverSegments {
+    fn push(&mut self, segment: Segment) {
+        let _ = self.ring.send(segment);
+        // Kicks off a stopped executor, after that the interrupt pops the rest itself.
+        with_executor(|executor, source| {
+            if !unsafe{ARMED} {
+                executor.step_interrupt(unsafe{STEP_TIME}, source, &mut Tc1StepTimer);
+            }
+        });
+    }
+
+    fn is_full(&self) -> bool { self.ring.is_full() }
+
+    fn is_busy(&self) -> bool {
+        !self.ring.is_empty() || with_executor(|executor, _| executor.is_busy())
+    }
+
+    fn abort(&mut self) -> XYZData<i32> {
+        with_executor(|executor, source| executor.abort(source))
+    }
+
+    fn configure(&mut self, signal: StepSignal, step_invert: XYZData<bool>, dir_invert: XYZData<bool>) {
+        with_executor(|executor, _| executor.configure(signal, step_invert, dir_invert))
+    }
+
+    fn watch(&mut self, watch: LimitWatch) {
+        with_executor(|executor, _| executor.watch(watch))
+    }
+
+    fn tripped(&self) -> Option<LimitSwitch> {
+        with_executor(|executor, _| executor.tripped())
+    }
+}
+
+#[avr_device::interrupt(atmega2560)]
+#[allow(static_mut_refs)]
+fn TIMER1_COMPA() {
+    let now = unsafe {
+        STEP_TIME = STEP_TIME.wrapping_add(PROGRAMMED_US);
+        STEP_TIME
+    };
+    let (executor, source) = unsafe{(EXECUTOR.assume_init_mut(), SEGMENT_SOURCE.assume_init_ref())};
+    executor.step_interrupt(now, source, &mut Tc1StepTimer);
 }
@@ -0,0 +1,89 @@
+use core::convert::Infallible;
+use library::{write_response, CanSend, CircularBuffer, Response};
+use ufmt::uWrite;
+
+// At 9600 baud a byte takes ~1ms, the ring holds a status report plus a few responses so the main loop doesn't
+// wait on them.
+const TX_SIZE: usize = 128;
+/// Filled by `write`, emptied a byte at a time by the data register empty interrupt.
+static mut TX_BUFFER: CircularBuffer<u8, TX_SIZE> = CircularBuffer::new_bounded(0);
+
+fn usart0() -> &'static arduino_hal::pac::usart0::RegisterBlock {
+    unsafe{&*arduino_hal::pac::USART0::ptr()}
+}
+
+/// Queues what fits of `bytes` and returns how many that was. Never waits.
+#[allow(static_mut_refs)]
+pub fn write(bytes: &[u8]) -> usize {
+    avr_device::interrupt::free(|_| {
+        let queued = unsafe{TX_BUFFER.push_slice(bytes)};
+        if queued > 0 {
+            usart0().ucsr0b.modify(|_, w| w.udrie0().set_bit());
+        }
+        queued
+    })
+}
+
+/// Room left in the ring. Only grows until the main loop writes again.
+#[allow(static_mut_refs)]
+pub fn free() -> usize {
+    avr_device::interrupt::free(|_| TX_SIZE - unsafe{TX_BUFFER.len()})
+}
+
+/// Drops whatever hasn't gone out yet, for a reset.
+#[allow(static_mut_refs)]
+pub fn clear() {
+    avr_device::interrupt::free(|_| unsafe{TX_BUFFER.clear()})
+}
+
+#[avr_device::interrupt(atmega2560)]
+#[allow(static_mut_refs)]
+fn USART0_UDRE() {
+    let usart = usart0();
+    match unsafe{TX_BUFFER.pop()} {
+        Some(b) => usart.udr0.write(|w| unsafe { w.bits(b) }),
+        // Fires for as long as the register is empty, off until `write` has something again.
+        None => usart.ucsr0b.modify(|_, w| w.udrie0().clear_bit()),
+    }
+}
+
+/// The TX ring as a ufmt writer. A string that doesn't all fit is an error, and what didn't fit is lost.
+pub struct TxRing;
+
+impl uWrite for TxRing {
+    type Error = ();
+
+    fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+        if write(s.as_bytes()) == s.len() { Ok(()) } else { Err(()) }
+    }
+}
+
+/// Counts what a response would write.
+struct Measure(usize);
+
+impl uWrite for Measure {
+    type Error = Infallible;
+
+    fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+/// Whole responses into the TX ring. A full ring hands the response back instead of waiting, the main loop
+/// has segments to keep up with. Callers retry what matters and drop the rest, e.g. status reports.
+#[derive(Clone, Copy)]
+pub struct UartResponses;
+
+impl CanSend<Response> for UartResponses {
+    fn send(&self, item: Response) -> Result<(), Response> {
+        let mut size = Measure(0);
+        let _ = write_response(&mut size, &item);
+        // Nothing goes in unless all of it will. Only the main loop writes, the room can't shrink in between.
+        if free() < size.0 {
+            return Err(item);
+        }
+        let _ = write_response(&mut TxRing, &item);
+        Ok(())
+    }
+}
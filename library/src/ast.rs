@@ -37,6 +37,9 @@ pub enum ArgumentMnumonic {
     F,
     P,
     R,
+    E,
+    L,
+    Q,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -51,6 +54,9 @@ impl FromStr for ArgumentMnumonic {
             Some('F') => Ok(ArgumentMnumonic::F),
             Some('P') => Ok(ArgumentMnumonic::P),
             Some('R') => Ok(ArgumentMnumonic::R),
+            Some('E') => Ok(ArgumentMnumonic::E),
+            Some('L') => Ok(ArgumentMnumonic::L),
+            Some('Q') => Ok(ArgumentMnumonic::Q),
             _ => Err(ParseArgMnumonicError{}),
         }
     }
@@ -91,6 +97,12 @@ pub struct GcodeCommand {
     pub arguments: ArrayVec<CommandArgument, 3>
 }
 
+impl GcodeCommand {
+    pub fn argument(&self, mnumonic: ArgumentMnumonic) -> Option<&MajorMinorNumber> {
+        self.arguments.iter().find(|a| a.mnumonic == mnumonic).map(|a| &a.value)
+    }
+}
+
 //pub trait GetSource<'a> {
     //fn source(&self) -> &'a str;
 //}
@@ -0,0 +1,277 @@
+use arrayvec::ArrayVec;
+
+use crate::elapsed;
+
+pub const AUX_PORTS: usize = 4;
+
+/// General purpose outputs and inputs, addressed by port number.
+pub trait AuxIo {
+    fn digital_out(&mut self, port: u8, on: bool);
+    fn analog_out(&mut self, port: u8, value: f32);
+    fn digital_in(&mut self, port: u8) -> bool;
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AuxValue {
+    Digital(bool),
+    Analog(f32),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AuxChange {
+    pub port: u8,
+    pub value: AuxValue,
+}
+
+/// Last written value of every port, plus changes waiting for the next move to start.
+#[derive(Clone, Default)]
+pub struct AuxTable {
+    pub digital: [bool; AUX_PORTS],
+    pub analog: [f32; AUX_PORTS],
+    /// One slot per port for each kind, so it never fills up.
+    pending: ArrayVec<AuxChange, { 2 * AUX_PORTS }>,
+}
+
+impl AuxTable {
+    pub fn set(&mut self, io: &mut impl AuxIo, change: AuxChange) {
+        let port = change.port as usize;
+        if port >= AUX_PORTS {
+            return;
+        }
+        match change.value {
+            AuxValue::Digital(on) => {
+                self.digital[port] = on;
+                io.digital_out(change.port, on);
+            },
+            AuxValue::Analog(value) => {
+                self.analog[port] = value;
+                io.analog_out(change.port, value);
+            },
+        }
+    }
+
+    /// Queue a change for the start of the next move. A later change to the same port replaces the earlier one.
+    pub fn set_synced(&mut self, change: AuxChange) {
+        if change.port as usize >= AUX_PORTS {
+            return;
+        }
+        let same_port = |c: &AuxChange| c.port == change.port && core::mem::discriminant(&c.value) == core::mem::discriminant(&change.value);
+        if let Some(existing) = self.pending.iter_mut().find(|c| same_port(c)) {
+            *existing = change;
+        }
+        else {
+            self.pending.push(change);
+        }
+    }
+
+    pub fn has_pending(&self) -> bool { !self.pending.is_empty() }
+
+    pub fn apply_synced(&mut self, io: &mut impl AuxIo) {
+        let pending = core::mem::take(&mut self.pending);
+        for change in pending {
+            self.set(io, change);
+        }
+    }
+
+    /// Drop pending changes and switch every output off.
+    pub fn all_off(&mut self, io: &mut impl AuxIo) {
+        self.pending.clear();
+        for port in 0..AUX_PORTS as u8 {
+            self.set(io, AuxChange { port, value: AuxValue::Digital(false) });
+            self.set(io, AuxChange { port, value: AuxValue::Analog(0.0) });
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WaitMode {
+    Immediate,
+    Rise,
+    Fall,
+    High,
+    Low,
+}
+
+impl WaitMode {
+    pub fn from_l_word(l: i32) -> Option<Self> {
+        match l {
+            0 => Some(WaitMode::Immediate),
+            1 => Some(WaitMode::Rise),
+            2 => Some(WaitMode::Fall),
+            3 => Some(WaitMode::High),
+            4 => Some(WaitMode::Low),
+            _ => None,
+        }
+    }
+}
+
+/// An M66 wait on a digital input.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct InputWait {
+    pub port: u8,
+    pub mode: WaitMode,
+    /// None waits until the condition is met or the machine is reset.
+    pub timeout_us: Option<u32>,
+    started: Option<u32>,
+    last_level: Option<bool>,
+}
+
+impl InputWait {
+    pub fn new(port: u8, mode: WaitMode, timeout_us: Option<u32>) -> Self {
+        Self { port, mode, timeout_us, started: None, last_level: None }
+    }
+
+    /// Returns true once the wait is over, either from the input or the timeout.
+    pub fn poll(&mut self, now: u32, level: bool) -> bool {
+        let started = *self.started.get_or_insert(now);
+        let last_level = self.last_level.replace(level);
+        let met = match self.mode {
+            WaitMode::Immediate => true,
+            WaitMode::High => level,
+            WaitMode::Low => !level,
+            WaitMode::Rise => last_level == Some(false) && level,
+            WaitMode::Fall => last_level == Some(true) && !level,
+        };
+        met || self.timeout_us.is_some_and(|t| elapsed(now, started) >= t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct RecordIo {
+        pub digital: [bool; AUX_PORTS],
+        pub analog: [f32; AUX_PORTS],
+        pub writes: u32,
+    }
+    impl AuxIo for RecordIo {
+        fn digital_out(&mut self, port: u8, on: bool) { self.digital[port as usize] = on; self.writes += 1; }
+        fn analog_out(&mut self, port: u8, value: f32) { self.analog[port as usize] = value; self.writes += 1; }
+        fn digital_in(&mut self, port: u8) -> bool { self.digital[port as usize] }
+    }
+
+    #[test]
+    fn table_set_immediate() {
+        let mut io = RecordIo::default();
+        let mut table = AuxTable::default();
+        table.set(&mut io, AuxChange { port: 1, value: AuxValue::Digital(true) });
+        table.set(&mut io, AuxChange { port: 2, value: AuxValue::Analog(0.5) });
+        assert!(io.digital[1]);
+        assert_eq!(io.analog[2], 0.5);
+        assert!(table.digital[1]);
+        assert_eq!(table.analog[2], 0.5);
+    }
+
+    #[test]
+    fn table_ignores_unknown_port() {
+        let mut io = RecordIo::default();
+        let mut table = AuxTable::default();
+        table.set(&mut io, AuxChange { port: AUX_PORTS as u8, value: AuxValue::Digital(true) });
+        table.set_synced(AuxChange { port: AUX_PORTS as u8, value: AuxValue::Digital(true) });
+        assert_eq!(io.writes, 0);
+        assert!(!table.has_pending());
+    }
+
+    #[test]
+    fn table_synced_waits_for_apply() {
+        let mut io = RecordIo::default();
+        let mut table = AuxTable::default();
+        table.set_synced(AuxChange { port: 0, value: AuxValue::Digital(true) });
+        table.set_synced(AuxChange { port: 0, value: AuxValue::Analog(1.0) });
+        assert!(!io.digital[0], "Synced output should not change before the move.");
+        assert!(table.has_pending());
+        table.apply_synced(&mut io);
+        assert!(io.digital[0]);
+        assert_eq!(io.analog[0], 1.0);
+        assert!(!table.has_pending());
+    }
+
+    #[test]
+    fn table_synced_every_port_and_kind() {
+        let mut io = RecordIo::default();
+        let mut table = AuxTable::default();
+        for port in 0..AUX_PORTS as u8 {
+            table.set_synced(AuxChange { port, value: AuxValue::Digital(true) });
+            table.set_synced(AuxChange { port, value: AuxValue::Analog(0.5) });
+        }
+        table.apply_synced(&mut io);
+        assert_eq!(io.writes, 2 * AUX_PORTS as u32, "None of the changes dropped.");
+        assert_eq!(io.digital, [true; AUX_PORTS]);
+        assert_eq!(io.analog, [0.5; AUX_PORTS]);
+    }
+
+    #[test]
+    fn table_synced_same_port_replaces() {
+        let mut io = RecordIo::default();
+        let mut table = AuxTable::default();
+        table.set_synced(AuxChange { port: 3, value: AuxValue::Digital(true) });
+        table.set_synced(AuxChange { port: 3, value: AuxValue::Digital(false) });
+        table.apply_synced(&mut io);
+        assert_eq!(io.writes, 1);
+        assert!(!io.digital[3]);
+    }
+
+    #[test]
+    fn table_all_off_drops_pending() {
+        let mut io = RecordIo::default();
+        let mut table = AuxTable::default();
+        table.set(&mut io, AuxChange { port: 0, value: AuxValue::Digital(true) });
+        table.set_synced(AuxChange { port: 1, value: AuxValue::Digital(true) });
+        table.all_off(&mut io);
+        assert!(!io.digital[0]);
+        assert!(!table.has_pending());
+        table.apply_synced(&mut io);
+        assert!(!io.digital[1]);
+    }
+
+    #[test]
+    fn wait_high_and_low() {
+        let mut wait = InputWait::new(0, WaitMode::High, None);
+        assert!(!wait.poll(10, false));
+        assert!(wait.poll(20, true));
+        let mut wait = InputWait::new(0, WaitMode::Low, None);
+        assert!(!wait.poll(10, true));
+        assert!(wait.poll(20, false));
+    }
+
+    #[test]
+    fn wait_rise_needs_edge() {
+        let mut wait = InputWait::new(0, WaitMode::Rise, None);
+        assert!(!wait.poll(10, true), "Already high is not a rising edge.");
+        assert!(!wait.poll(20, false));
+        assert!(wait.poll(30, true));
+    }
+
+    #[test]
+    fn wait_fall_needs_edge() {
+        let mut wait = InputWait::new(0, WaitMode::Fall, None);
+        assert!(!wait.poll(10, false));
+        assert!(!wait.poll(20, true));
+        assert!(wait.poll(30, false));
+    }
+
+    #[test]
+    fn wait_times_out() {
+        let mut wait = InputWait::new(0, WaitMode::High, Some(100));
+        assert!(!wait.poll(1000, false));
+        assert!(!wait.poll(1099, false));
+        assert!(wait.poll(1100, false));
+    }
+
+    #[test]
+    fn wait_times_out_across_wrap() {
+        let mut wait = InputWait::new(0, WaitMode::High, Some(100));
+        assert!(!wait.poll(u32::MAX - 49, false));
+        assert!(!wait.poll(48, false));
+        assert!(wait.poll(50, false));
+    }
+
+    #[test]
+    fn wait_mode_from_l_word() {
+        assert_eq!(WaitMode::from_l_word(0), Some(WaitMode::Immediate));
+        assert_eq!(WaitMode::from_l_word(3), Some(WaitMode::High));
+        assert_eq!(WaitMode::from_l_word(5), None);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::{LimitInputs, LimitSide, StepDir, XYZData, XYZId, MAX_GANG};
+
+/// Index of a stepper driver socket in the board's list, e.g. RAMPS' X or E0.
+pub type SlotId = u8;
+
+/// One motor of a logical axis.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SlotMapping {
+    pub slot: SlotId,
+    /// Flips this motor's direction pin only, e.g. a second gantry motor facing the other way.
+    pub invert_dir: bool,
+}
+
+impl SlotMapping {
+    pub const fn new(slot: SlotId) -> Self { Self { slot, invert_dir: false } }
+    pub const fn inverted(slot: SlotId) -> Self { Self { slot, invert_dir: true } }
+}
+
+/// Which driver slots each logical axis drives, all of an axis' motors get the same pulses.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AxisMap {
+    pub axes: XYZData<&'static [SlotMapping]>,
+}
+
+/// The board side, pins addressed by driver slot.
+pub trait DriverSlots: Clone {
+    fn step(&mut self, slot: SlotId, high: bool);
+    fn dir(&mut self, slot: SlotId, high: bool);
+    fn enable(&mut self, slot: SlotId, on: bool);
+    /// The slot's switch on `side`, false when there's none.
+    fn limit(&mut self, slot: SlotId, side: LimitSide) -> bool;
+    fn has_limits(&self, slot: SlotId) -> bool;
+    /// A locked slot is left out of the axis' step pulses.
+    fn set_locked(&mut self, slot: SlotId, locked: bool);
+    fn locked(&self, slot: SlotId) -> bool;
+}
+
+/// `StepDir` and `LimitInputs` for logical axes on top of a board's driver slots.
+#[derive(Clone)]
+pub struct MappedAxes<D: DriverSlots> {
+    slots: D,
+    map: &'static AxisMap,
+}
+
+impl<D: DriverSlots> MappedAxes<D> {
+    pub fn new(slots: D, map: &'static AxisMap) -> Self { Self { slots, map } }
+
+    fn mappings(&self, axis: XYZId) -> &'static [SlotMapping] { self.map.axes.match_id(axis) }
+}
+
+impl<D: DriverSlots> StepDir for MappedAxes<D> {
+    fn step(&mut self, axis: XYZId, high: bool) {
+        for m in self.mappings(axis) {
+            if !self.slots.locked(m.slot) {
+                self.slots.step(m.slot, high);
+            }
+        }
+    }
+
+    fn dir(&mut self, axis: XYZId, direction: bool) {
+        for m in self.mappings(axis) {
+            self.slots.dir(m.slot, direction != m.invert_dir);
+        }
+    }
+
+    fn enable(&mut self, axis: XYZId, on: bool) {
+        for m in self.mappings(axis) {
+            self.slots.enable(m.slot, on);
+        }
+    }
+
+    fn lock_motor(&mut self, axis: XYZId, motor: u8, idle: Option<bool>) {
+        let Some(m) = self.mappings(axis).get(motor as usize) else { return };
+        // Parked straight away, a pulse that was going out ends early.
+        if let Some(idle) = idle {
+            self.slots.step(m.slot, idle);
+        }
+        self.slots.set_locked(m.slot, idle.is_some());
+    }
+}
+
+impl<D: DriverSlots> LimitInputs for MappedAxes<D> {
+    fn triggered(&mut self, axis: XYZId, side: LimitSide) -> bool {
+        self.mappings(axis).iter().any(|m| self.slots.limit(m.slot, side))
+    }
+
+    /// An axis is only squared when every one of its motors has a switch.
+    fn motors(&mut self, axis: XYZId) -> u8 {
+        let mappings = self.mappings(axis);
+        if mappings.len() > 1 && mappings.iter().all(|m| self.slots.has_limits(m.slot)) {
+            mappings.len().min(MAX_GANG) as u8
+        }
+        else {
+            1
+        }
+    }
+
+    fn motor_triggered(&mut self, axis: XYZId, motor: u8, side: LimitSide) -> bool {
+        self.mappings(axis).get(motor as usize).is_some_and(|m| self.slots.limit(m.slot, side))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use core::cell::RefCell;
+    use std::{rc::Rc, vec::Vec};
+    use super::*;
+
+    #[derive(Default, Debug)]
+    struct Pins {
+        step: [bool; 4],
+        dir: [bool; 4],
+        enable: [bool; 4],
+        min: [bool; 4],
+        /// Slots with a switch.
+        switches: [bool; 4],
+        locked: [bool; 4],
+        writes: Vec<SlotId>,
+    }
+    #[derive(Default, Clone)]
+    struct TestSlots(Rc<RefCell<Pins>>);
+    impl DriverSlots for TestSlots {
+        fn step(&mut self, slot: SlotId, high: bool) {
+            let mut pins = self.0.borrow_mut();
+            pins.step[slot as usize] = high;
+            pins.writes.push(slot);
+        }
+        fn dir(&mut self, slot: SlotId, high: bool) { self.0.borrow_mut().dir[slot as usize] = high; }
+        fn enable(&mut self, slot: SlotId, on: bool) { self.0.borrow_mut().enable[slot as usize] = on; }
+        fn limit(&mut self, slot: SlotId, side: LimitSide) -> bool {
+            side == LimitSide::Min && self.0.borrow().min[slot as usize]
+        }
+        fn has_limits(&self, slot: SlotId) -> bool { self.0.borrow().switches[slot as usize] }
+        fn set_locked(&mut self, slot: SlotId, locked: bool) { self.0.borrow_mut().locked[slot as usize] = locked; }
+        fn locked(&self, slot: SlotId) -> bool { self.0.borrow().locked[slot as usize] }
+    }
+
+    // X and Z swapped, Y on slots 1 and 3 with the second one facing the other way.
+    static MAP: AxisMap = AxisMap {
+        axes: XYZData {
+            x: &[SlotMapping::new(2)],
+            y: &[SlotMapping::new(1), SlotMapping::inverted(3)],
+            z: &[SlotMapping::new(0)],
+        },
+    };
+
+    #[test]
+    fn mapped_axes_fan_out() {
+        let slots = TestSlots::default();
+        let mut axes = MappedAxes::new(slots.clone(), &MAP);
+        axes.step(XYZId::X, true);
+        axes.step(XYZId::Y, true);
+        assert_eq!(slots.0.borrow().writes, [2, 1, 3]);
+        assert_eq!(slots.0.borrow().step, [false, true, true, true]);
+        axes.enable(XYZId::Y, true);
+        assert_eq!(slots.0.borrow().enable, [false, true, false, true]);
+    }
+
+    #[test]
+    fn mapped_axes_slot_dir_invert() {
+        let slots = TestSlots::default();
+        let mut axes = MappedAxes::new(slots.clone(), &MAP);
+        axes.dir(XYZId::Y, true);
+        axes.dir(XYZId::Z, true);
+        assert_eq!(slots.0.borrow().dir, [true, true, false, false]);
+        axes.dir(XYZId::Y, false);
+        assert_eq!(slots.0.borrow().dir, [true, false, false, true]);
+    }
+
+    #[test]
+    fn mapped_axes_lock_motor() {
+        let slots = TestSlots::default();
+        let mut axes = MappedAxes::new(slots.clone(), &MAP);
+        axes.step(XYZId::Y, true);
+        axes.lock_motor(XYZId::Y, 1, Some(false));
+        assert_eq!(slots.0.borrow().step, [false, true, false, false], "Parked at idle mid pulse.");
+        axes.step(XYZId::Y, false);
+        axes.step(XYZId::Y, true);
+        assert_eq!(slots.0.borrow().step, [false, true, false, false]);
+        axes.lock_motor(XYZId::Y, 1, None);
+        axes.step(XYZId::Y, false);
+        axes.step(XYZId::Y, true);
+        assert_eq!(slots.0.borrow().step, [false, true, false, true]);
+        axes.lock_motor(XYZId::X, 1, Some(false));
+        assert_eq!(slots.0.borrow().locked, [false; 4], "X has no second motor.");
+    }
+
+    #[test]
+    fn mapped_axes_motors() {
+        let slots = TestSlots::default();
+        let mut axes = MappedAxes::new(slots.clone(), &MAP);
+        slots.0.borrow_mut().switches = [true, true, true, false];
+        assert_eq!(axes.motors(XYZId::Y), 1, "The second Y motor has no switch, Y isn't squared.");
+        assert_eq!(axes.motors(XYZId::X), 1);
+        slots.0.borrow_mut().switches[3] = true;
+        assert_eq!(axes.motors(XYZId::Y), 2);
+        slots.0.borrow_mut().min[3] = true;
+        assert!(axes.motor_triggered(XYZId::Y, 1, LimitSide::Min));
+        assert!(!axes.motor_triggered(XYZId::Y, 0, LimitSide::Min));
+    }
+
+    #[test]
+    fn mapped_axes_limits() {
+        let slots = TestSlots::default();
+        let mut axes = MappedAxes::new(slots.clone(), &MAP);
+        slots.0.borrow_mut().min[3] = true;
+        assert!(axes.triggered(XYZId::Y, LimitSide::Min), "Either Y motor's switch.");
+        assert!(!axes.triggered(XYZId::Y, LimitSide::Max));
+        assert!(!axes.triggered(XYZId::X, LimitSide::Min));
+        slots.0.borrow_mut().min[2] = true;
+        assert!(axes.triggered(XYZId::X, LimitSide::Min), "X reads the switches of the slot it's on.");
+    }
+}
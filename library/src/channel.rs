@@ -1,22 +1,9 @@
-use core::{cell::UnsafeCell, marker::PhantomData};
-
-use arrayvec::ArrayVec;
+use core::{cell::{Cell, UnsafeCell}, marker::PhantomData, mem::MaybeUninit, sync::atomic::{AtomicU8, Ordering}};
 
+/// Queue owned by one side, for use through `&mut`.
 #[derive(Default)]
 pub struct Channel<T, const SIZE: usize> {
-    buffer: ArrayVec<T, SIZE>,
-}
-
-fn send<T, const SIZE: usize>(buffer: &mut ArrayVec<T, SIZE>, item: T) -> Result<(), T> {
-    if buffer.remaining_capacity() != 0 {
-        buffer.push(item);
-        Ok(())
-    }
-    else { Err(item) }
-}
-fn recieve<T, const SIZE: usize>(buffer: &mut ArrayVec<T, SIZE>,) -> Option<T> {
-    if buffer.len() == 0 { None }
-    else { buffer.drain(0..1).next() }
+    queue: SpscQueue<T, SIZE>,
 }
 
 pub trait CanSendMut<T> {
@@ -31,55 +18,109 @@ pub trait CanRecieveMut<T> {
 pub trait CanRecieve<T> {
     fn recieve(&self) -> Option<T>;
 }
-pub trait CanCreateSenders<T> {
-    fn create_sender(&self) -> impl CanSend<T>;
-}
 
 impl<T, const SIZE: usize> CanSendMut<T> for Channel<T, SIZE> {
-    fn send_mut(&mut self, item: T) -> Result<(), T> { send(&mut self.buffer, item) }
+    fn send_mut(&mut self, item: T) -> Result<(), T> { self.queue.enqueue(item) }
 }
 impl<T, const SIZE: usize> CanRecieveMut<T> for Channel<T, SIZE> {
-    fn recieve_mut(&mut self) -> Option<T> { recieve(&mut self.buffer) }
+    fn recieve_mut(&mut self) -> Option<T> { self.queue.dequeue() }
 }
 
-pub struct SplitChannel<T, C> where C: CanRecieveMut<T> + CanSendMut<T> {
-    interior: UnsafeCell<C>,
-    phantom: PhantomData<T>,
+/// Fixed size ring with one producer and one consumer, each of which may be in an interrupt. `split` hands
+/// out the two ends. Both indices count to `2 * SIZE` so a full queue can be told from an empty one, and
+/// each is only stored by its own end.
+pub struct SpscQueue<T, const SIZE: usize> {
+    slots: [UnsafeCell<MaybeUninit<T>>; SIZE],
+    /// Next slot to write.
+    head: AtomicU8,
+    /// Next slot to read.
+    tail: AtomicU8,
 }
-impl<T, C> SplitChannel<T, C> where C: CanRecieveMut<T> + CanSendMut<T> {
-    pub fn new(channel: C) -> Self {
-        Self { interior: UnsafeCell::new(channel), phantom: PhantomData::default() }
+
+// A slot belongs to the producer until `head` moves past it, then to the consumer until `tail` does.
+unsafe impl<T: Send, const SIZE: usize> Sync for SpscQueue<T, SIZE> {}
+
+impl<T, const SIZE: usize> SpscQueue<T, SIZE> {
+    pub const fn new() -> Self {
+        assert!(SIZE > 0 && SIZE <= u8::MAX as usize / 2);
+        Self { slots: [const { UnsafeCell::new(MaybeUninit::uninit()) }; SIZE], head: AtomicU8::new(0), tail: AtomicU8::new(0) }
     }
-}
-impl<T, C> CanSend<T> for SplitChannel<T, C> where C: CanRecieveMut<T> + CanSendMut<T> {
-    fn send(&self, item: T) -> Result<(), T> {
-        unsafe{&mut *self.interior.get()}.send_mut(item)
+
+    pub fn split(&mut self) -> (Producer<'_, T, SIZE>, Consumer<'_, T, SIZE>) {
+        (Producer { queue: self, not_shared: PhantomData }, Consumer { queue: self, not_shared: PhantomData })
     }
-}
-impl<T, C> CanRecieve<T> for SplitChannel<T, C> where C: CanRecieveMut<T> + CanSendMut<T> {
-    fn recieve(&self) -> Option<T> {
-        unsafe{&mut *self.interior.get()}.recieve_mut()
+
+    fn next(index: u8) -> u8 { ((index as usize + 1) % (2 * SIZE)) as u8 }
+
+    fn count(head: u8, tail: u8) -> usize { (head as usize + 2 * SIZE - tail as usize) % (2 * SIZE) }
+
+    pub fn len(&self) -> usize { Self::count(self.head.load(Ordering::Acquire), self.tail.load(Ordering::Acquire)) }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    pub fn is_full(&self) -> bool { self.len() == SIZE }
+
+    /// Producer end only.
+    pub(crate) fn enqueue(&self, item: T) -> Result<(), T> {
+        let head = self.head.load(Ordering::Relaxed);
+        if Self::count(head, self.tail.load(Ordering::Acquire)) == SIZE {
+            return Err(item);
+        }
+        unsafe { (*self.slots[head as usize % SIZE].get()).write(item) };
+        self.head.store(Self::next(head), Ordering::Release);
+        Ok(())
     }
-}
-impl<T, C> CanCreateSenders<T> for SplitChannel<T, C> where C: CanRecieveMut<T> + CanSendMut<T> {
-    fn create_sender(&self) -> impl CanSend<T> {
-        SenderSplit::new(self)
+
+    /// Consumer end only.
+    pub(crate) fn dequeue(&self) -> Option<T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+        let item = unsafe { (*self.slots[tail as usize % SIZE].get()).assume_init_read() };
+        self.tail.store(Self::next(tail), Ordering::Release);
+        Some(item)
     }
 }
 
-pub struct SenderSplit<'a, T, C> where C: CanSendMut<T> + CanRecieveMut<T> {
-    interior: &'a SplitChannel<T, C>,
-    phantom: PhantomData<T>,
+impl<T, const SIZE: usize> Default for SpscQueue<T, SIZE> {
+    fn default() -> Self { Self::new() }
 }
-impl<'a, T, C> SenderSplit<'a, T, C> where C: CanSendMut<T> + CanRecieveMut<T> {
-    fn new(channel: &'a SplitChannel<T, C>) -> Self {
-        Self { interior: channel, phantom: PhantomData::default() }
+
+impl<T, const SIZE: usize> Drop for SpscQueue<T, SIZE> {
+    fn drop(&mut self) {
+        while self.dequeue().is_some() {}
     }
 }
-impl<'a, T, C> CanSend<T> for SenderSplit<'a, T, C> where C: CanSendMut<T> + CanRecieveMut<T> {
-    fn send(&self, item: T) -> Result<(), T> {
-        self.interior.send(item)
-    }
+
+/// Sending end of a `SpscQueue`. Can be moved to an interrupt but not shared, there's only ever one.
+pub struct Producer<'a, T, const SIZE: usize> {
+    queue: &'a SpscQueue<T, SIZE>,
+    not_shared: PhantomData<Cell<()>>,
+}
+
+impl<T, const SIZE: usize> Producer<'_, T, SIZE> {
+    pub fn is_full(&self) -> bool { self.queue.is_full() }
+    pub fn is_empty(&self) -> bool { self.queue.is_empty() }
+}
+
+impl<T, const SIZE: usize> CanSend<T> for Producer<'_, T, SIZE> {
+    fn send(&self, item: T) -> Result<(), T> { self.queue.enqueue(item) }
+}
+
+/// Recieving end of a `SpscQueue`.
+pub struct Consumer<'a, T, const SIZE: usize> {
+    queue: &'a SpscQueue<T, SIZE>,
+    not_shared: PhantomData<Cell<()>>,
+}
+
+impl<T, const SIZE: usize> Consumer<'_, T, SIZE> {
+    pub fn len(&self) -> usize { self.queue.len() }
+    pub fn is_empty(&self) -> bool { self.queue.is_empty() }
+}
+
+impl<T, const SIZE: usize> CanRecieve<T> for Consumer<'_, T, SIZE> {
+    fn recieve(&self) -> Option<T> { self.queue.dequeue() }
 }
 
 
@@ -124,11 +165,61 @@ mod tests {
     }
 
     #[test]
-    fn split_channel_send_revieve() {
-        let c = SplitChannel::new(Channel::<u32, 2>::default());
-        let sender = c.create_sender();
+    fn spsc_send_recieve() {
+        let mut queue = SpscQueue::<u32, 2>::new();
+        let (sender, reciever) = queue.split();
         assert_eq!(sender.send(123), Ok(()));
-        assert_eq!(c.recieve(), Some(123));
-        assert_eq!(c.recieve(), None);
+        assert_eq!(reciever.recieve(), Some(123));
+        assert_eq!(reciever.recieve(), None);
+    }
+
+    #[test]
+    fn spsc_full_and_wrap() {
+        let mut queue = SpscQueue::<u32, 3>::new();
+        let (sender, reciever) = queue.split();
+        for round in 0..5 {
+            for i in 0..3 {
+                assert_eq!(sender.send(round * 10 + i), Ok(()));
+            }
+            assert!(sender.is_full());
+            assert_eq!(sender.send(99), Err(99), "Holds exactly SIZE.");
+            assert_eq!(reciever.len(), 3);
+            for i in 0..3 {
+                assert_eq!(reciever.recieve(), Some(round * 10 + i), "In order.");
+            }
+            assert!(reciever.is_empty());
+        }
+    }
+
+    #[test]
+    fn spsc_interleaved() {
+        let mut queue = SpscQueue::<u32, 4>::new();
+        let (sender, reciever) = queue.split();
+        let mut expected = 0;
+        for i in 0..100 {
+            assert_eq!(sender.send(i), Ok(()));
+            if i % 3 != 0 {
+                assert_eq!(reciever.recieve(), Some(expected));
+                expected += 1;
+            }
+            while sender.is_full() {
+                assert_eq!(reciever.recieve(), Some(expected));
+                expected += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn spsc_drops_leftovers() {
+        extern crate std;
+        use std::rc::Rc;
+        let item = Rc::new(());
+        {
+            let mut queue = SpscQueue::<Rc<()>, 2>::new();
+            let (sender, _) = queue.split();
+            assert!(sender.send(item.clone()).is_ok());
+            assert_eq!(Rc::strong_count(&item), 2);
+        }
+        assert_eq!(Rc::strong_count(&item), 1);
     }
 }
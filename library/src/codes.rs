@@ -0,0 +1,28 @@
+/// Alarm reasons, numbered like grbl's `ALARM:n` codes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AlarmCode {
+    HardLimit = 1,
+    HomingFailReset = 6,
+    HomingFailPullOff = 8,
+    HomingFailApproach = 9,
+}
+
+impl AlarmCode {
+    pub fn code(&self) -> u8 { *self as u8 }
+}
+
+/// Command rejections, numbered like grbl's `error:n` codes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorCode {
+    ExpectedCommandLetter = 1,
+    InvalidStatement = 3,
+    NegativeValue = 4,
+    HomingDisabled = 5,
+    IdleError = 8,
+    LineOverflow = 11,
+    UnsupportedCommand = 20,
+}
+
+impl ErrorCode {
+    pub fn code(&self) -> u8 { *self as u8 }
+}
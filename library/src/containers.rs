@@ -5,6 +5,10 @@ pub struct CircularBuffer<T, const SIZE: usize> {
     pub data: [T; SIZE],
     pub begin: usize,
     pub length: usize,
+    /// Refuse pushes when full instead of overwriting the oldest item.
+    pub bounded: bool,
+    /// Pushes refused since the last `take_overflows`.
+    pub overflows: usize,
 }
 
 impl<T, const SIZE: usize> Default for CircularBuffer<T, SIZE> where T: Copy + Default + Sized {
@@ -14,19 +18,32 @@ impl<T, const SIZE: usize> Default for CircularBuffer<T, SIZE> where T: Copy + D
             data,
             length: 0,
             begin: 0,
+            bounded: false,
+            overflows: 0,
         }
     }
 }
 
 impl<T, const SIZE: usize> CircularBuffer<T, SIZE> {
+    /// Bounded buffer usable in a static, `fill` only initializes the storage.
+    pub const fn new_bounded(fill: T) -> Self where T: Copy {
+        Self { data: [fill; SIZE], begin: 0, length: 0, bounded: true, overflows: 0 }
+    }
+
     fn wrap_index(i: usize) -> usize {
         match i {
-            i if i >= SIZE => 0,
+            i if i >= SIZE => i - SIZE,
             i => i,
         }
     }
 
-    pub fn push(&mut self, data: T) {
+    /// When full a bounded buffer hands `data` back and counts an overflow, otherwise the oldest item is
+    /// overwritten.
+    pub fn push(&mut self, data: T) -> Result<(), T> {
+        if self.length == SIZE && self.bounded {
+            self.overflows = self.overflows.saturating_add(1);
+            return Err(data);
+        }
         let i = Self::wrap_index(self.begin + self.length);
         self.data[i] = data;
         if self.length == SIZE {
@@ -35,12 +52,54 @@ impl<T, const SIZE: usize> CircularBuffer<T, SIZE> {
         else {
             self.length += 1;
         }
+        Ok(())
+    }
+
+    /// Pushes what fits without overwriting anything, in either mode, and returns how many that was.
+    pub fn push_slice(&mut self, data: &[T]) -> usize where T: Clone {
+        let accepted = data.len().min(SIZE - self.length);
+        for item in &data[..accepted] {
+            let i = Self::wrap_index(self.begin + self.length);
+            self.data[i] = item.clone();
+            self.length += 1;
+        }
+        accepted
+    }
+
+    pub fn pop(&mut self) -> Option<T> where T: Clone {
+        let item = self.peek()?.clone();
+        self.begin = Self::wrap_index(self.begin + 1);
+        self.length -= 1;
+        Some(item)
+    }
+
+    pub fn peek(&self) -> Option<&T> {
+        if self.length == 0 { None }
+        else { Some(&self.data[self.begin]) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
     }
 
     pub fn length(&self) -> usize {
         self.length
     }
 
+    pub fn is_full(&self) -> bool {
+        self.length == SIZE
+    }
+
+    pub fn clear(&mut self) {
+        self.begin = 0;
+        self.length = 0;
+    }
+
+    /// Overflow count since the last call.
+    pub fn take_overflows(&mut self) -> usize {
+        core::mem::take(&mut self.overflows)
+    }
+
     pub fn is_empty(&self) -> bool {
         self.length == 0
     }
@@ -57,12 +116,13 @@ impl<T, const SIZE: usize> CircularBuffer<T, SIZE> {
 
 #[cfg(test)]
 mod tests {
+    extern crate std;
     use super::*;
 
     #[test]
     fn push_small() {
         let mut c = CircularBuffer::<u32, 1>::default();
-        c.push(123);
+        c.push(123).unwrap();
         assert_eq!(c.length(), 1);
         assert_eq!(c.data[0], 123);
     }
@@ -70,7 +130,7 @@ mod tests {
     #[test]
     fn push_overwrites() {
         let mut c = CircularBuffer::<u32, 1>::default();
-        c.push(321);
+        c.push(321).unwrap();
         assert_eq!(c.length(), 1);
         assert_eq!(c.data[0], 321);
     }
@@ -80,13 +140,13 @@ mod tests {
         let mut c = CircularBuffer::<u32, 2>::default();
         assert_eq!(c.begin, 0);
         assert_eq!(c.length, 0);
-        c.push(111);
+        c.push(111).unwrap();
         assert_eq!(c.begin, 0);
         assert_eq!(c.length, 1);
-        c.push(222);
+        c.push(222).unwrap();
         assert_eq!(c.begin, 0);
         assert_eq!(c.length, 2);
-        c.push(321);
+        c.push(321).unwrap();
         assert_eq!(c.begin, 1);
         assert_eq!(c.length, 2);
     }
@@ -94,12 +154,67 @@ mod tests {
     #[test]
     fn push_size2_overwrites() {
         let mut c = CircularBuffer::<u32, 2>::default();
-        c.push(111);
-        c.push(222);
-        c.push(321);
+        c.push(111).unwrap();
+        c.push(222).unwrap();
+        c.push(321).unwrap();
         assert_eq!(c.length(), 2);
         assert_eq!(c.data[0], 321);
         assert_eq!(c.data[1], 222);
     }
-}
 
+    #[test]
+    fn push_wraps_past_end() {
+        let mut c = CircularBuffer::<u32, 4>::default();
+        for i in 0..6 {
+            c.push(i).unwrap();
+        }
+        assert_eq!(c.consume().collect::<std::vec::Vec<_>>(), [2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn push_rejects_when_full() {
+        let mut c = CircularBuffer::<u8, 3>::new_bounded(0);
+        for b in 1..=3 {
+            c.push(b).unwrap();
+        }
+        assert!(c.is_full());
+        assert_eq!(c.push(4), Err(4));
+        assert_eq!(c.push(5), Err(5));
+        assert_eq!(c.take_overflows(), 2);
+        assert_eq!(c.take_overflows(), 0);
+        assert_eq!(c.consume().collect::<std::vec::Vec<_>>(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn push_slice_takes_what_fits() {
+        let mut c = CircularBuffer::<u8, 4>::new_bounded(0);
+        assert_eq!(c.push_slice(b"ab"), 2);
+        assert_eq!(c.pop(), Some(b'a'));
+        assert_eq!(c.push_slice(b"cdef"), 3);
+        assert_eq!(c.push_slice(b"g"), 0);
+        assert_eq!(c.overflows, 0);
+        assert_eq!(c.consume().collect::<std::vec::Vec<_>>(), b"bcde");
+
+        let mut c = CircularBuffer::<u8, 2>::default();
+        c.push(1).unwrap();
+        assert_eq!(c.push_slice(&[2, 3]), 1, "Doesn't overwrite in the overwriting mode either.");
+        assert_eq!(c.consume().collect::<std::vec::Vec<_>>(), [1, 2]);
+    }
+
+    #[test]
+    fn pop_and_peek() {
+        let mut c = CircularBuffer::<u8, 3>::new_bounded(0);
+        assert_eq!(c.peek(), None);
+        assert_eq!(c.pop(), None);
+        for round in 0..4u8 {
+            c.push(round).unwrap();
+            c.push(round + 10).unwrap();
+            assert_eq!(c.len(), 2);
+            assert_eq!(c.peek(), Some(&round));
+            assert_eq!(c.pop(), Some(round));
+            assert_eq!(c.pop(), Some(round + 10));
+            assert!(c.is_empty());
+        }
+        assert_eq!(c.overflows, 0);
+    }
+}
@@ -0,0 +1,16 @@
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct CoolantState {
+    pub mist: bool,
+    pub flood: bool,
+}
+
+impl CoolantState {
+    pub const OFF: CoolantState = CoolantState { mist: false, flood: false };
+
+    pub fn is_off(&self) -> bool { !self.mist && !self.flood }
+}
+
+pub trait Coolant {
+    fn mist(&mut self, on: bool);
+    fn flood(&mut self, on: bool);
+}
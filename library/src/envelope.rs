@@ -0,0 +1,57 @@
+use crate::{LimitSide, Settings, XYZData, XYZId, XYZ_ID_LIST};
+
+/// Allowed machine positions in steps, both ends inclusive.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TravelEnvelope {
+    pub min: XYZData<i32>,
+    pub max: XYZData<i32>,
+}
+
+impl TravelEnvelope {
+    /// Machine zero is the pulled off homing position, so an axis homed to its min switch travels
+    /// positive from zero and one homed to max travels negative.
+    pub fn from_settings(settings: &Settings) -> Self {
+        let mut envelope = Self { min: Default::default(), max: Default::default() };
+        for axis in XYZ_ID_LIST {
+            let travel = settings.mm_to_steps(axis, *settings.max_travel.match_id(axis));
+            match settings.homing.side.match_id(axis) {
+                LimitSide::Min => *envelope.max.match_id_mut(axis) = travel,
+                LimitSide::Max => *envelope.min.match_id_mut(axis) = -travel,
+            }
+        }
+        envelope
+    }
+
+    /// First axis of `target` outside the envelope.
+    pub fn violation(&self, target: &XYZData<i32>) -> Option<XYZId> {
+        XYZ_ID_LIST.into_iter().find(|&axis| {
+            let p = *target.match_id(axis);
+            p < *self.min.match_id(axis) || p > *self.max.match_id(axis)
+        })
+    }
+
+    pub fn contains(&self, target: &XYZData<i32>) -> bool { self.violation(target).is_none() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RESOLUTION;
+
+    #[test]
+    fn envelope_from_homing_sides() {
+        let envelope = TravelEnvelope::from_settings(&Settings::default());
+        let travel = 200 * RESOLUTION as i32;
+        assert_eq!(envelope.min, XYZData { x: 0, y: 0, z: -travel });
+        assert_eq!(envelope.max, XYZData { x: travel, y: travel, z: 0 });
+    }
+
+    #[test]
+    fn envelope_violation() {
+        let envelope = TravelEnvelope { min: XYZData { x: 0, y: 0, z: -10 }, max: XYZData { x: 10, y: 10, z: 0 } };
+        assert!(envelope.contains(&XYZData { x: 0, y: 10, z: -10 }), "Edges are inside.");
+        assert_eq!(envelope.violation(&XYZData { x: 11, y: 0, z: 0 }), Some(XYZId::X));
+        assert_eq!(envelope.violation(&XYZData { x: 5, y: -1, z: 0 }), Some(XYZId::Y));
+        assert_eq!(envelope.violation(&XYZData { x: 5, y: 5, z: 1 }), Some(XYZId::Z));
+    }
+}
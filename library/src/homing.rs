@@ -0,0 +1,334 @@
+use arrayvec::ArrayVec;
+
+use crate::{AlarmCode, Settings, XYZData, XYZId};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LimitSide {
+    Min,
+    Max,
+}
+
+impl LimitSide {
+    pub fn direction(&self) -> i32 {
+        match self {
+            LimitSide::Min => -1,
+            LimitSide::Max => 1,
+        }
+    }
+}
+
+/// A single end stop, e.g. the X max switch.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LimitSwitch {
+    pub axis: XYZId,
+    pub side: LimitSide,
+}
+
+pub trait LimitInputs {
+    /// Any of the axis' switches on that side.
+    fn triggered(&mut self, axis: XYZId, side: LimitSide) -> bool;
+    /// Motors of a ganged axis that each have their own switch, homing squares them. 1 for a plain axis.
+    fn motors(&mut self, _axis: XYZId) -> u8 { 1 }
+    /// The switch of one motor of the axis.
+    fn motor_triggered(&mut self, axis: XYZId, _motor: u8, side: LimitSide) -> bool { self.triggered(axis, side) }
+}
+
+/// Most motors one axis can drive.
+pub const MAX_GANG: usize = 2;
+
+/// Motors of a ganged axis held still while the rest of the axis steps on, one bit per motor.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Gang {
+    locked: u8,
+}
+
+impl Gang {
+    pub fn is_locked(&self, motor: u8) -> bool { self.locked & 1 << motor != 0 }
+
+    pub fn all_locked(&self, motors: u8) -> bool { (0..motors).all(|m| self.is_locked(m)) }
+
+    pub fn lock(&mut self, motor: u8) { self.locked |= 1 << motor; }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct HomingConfig {
+    /// Axes are homed one at a time in this order.
+    pub order: ArrayVec<XYZId, 3>,
+    /// mm/min
+    pub seek_rate: f32,
+    /// mm/min
+    pub locate_rate: f32,
+    /// mm
+    pub pull_off: f32,
+    /// Which switch each axis homes against.
+    pub side: XYZData<LimitSide>,
+    /// Slow locate passes after the first seek.
+    pub passes: u8,
+    /// mm, per motor of a ganged axis, how much further than the pull off each motor backs off to square it.
+    pub trim: [XYZData<f32>; MAX_GANG],
+}
+
+impl Default for HomingConfig {
+    fn default() -> Self {
+        let mut order = ArrayVec::new();
+        order.push(XYZId::Z);
+        order.push(XYZId::X);
+        order.push(XYZId::Y);
+        Self {
+            order,
+            seek_rate: 500.0,
+            locate_rate: 25.0,
+            pull_off: 1.0,
+            side: XYZData { x: LimitSide::Min, y: LimitSide::Min, z: LimitSide::Max },
+            passes: 1,
+            trim: Default::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HomingPhase {
+    Start,
+    Seek,
+    PullOff,
+    Locate,
+    /// Backing a ganged axis' motor off by its trim.
+    Trim(u8),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HomingStep {
+    /// Relative move in steps. Stop as soon as the switch triggers when `until_switch` is set.
+    Move { axis: XYZId, distance: i32, speed: u32, until_switch: bool },
+    /// Relative move in steps of a single motor, the axis' other motors held still.
+    Trim { axis: XYZId, motor: u8, distance: i32, speed: u32 },
+    /// The axis is pulled off its switch, this is machine zero.
+    Zero(XYZId),
+    Fail(AlarmCode),
+    Done,
+}
+
+/// Sequencing for one homing cycle. `next_step` is called each time the previous move has finished.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct HomingCycle {
+    axis_index: usize,
+    phase: HomingPhase,
+    pass: u8,
+}
+
+impl Default for HomingCycle {
+    fn default() -> Self { Self::new() }
+}
+
+impl HomingCycle {
+    pub fn new() -> Self {
+        Self { axis_index: 0, phase: HomingPhase::Start, pass: 0 }
+    }
+
+    pub fn axis(&self, settings: &Settings) -> Option<XYZId> {
+        settings.homing.order.get(self.axis_index).copied()
+    }
+
+    pub fn phase(&self) -> HomingPhase { self.phase }
+
+    /// True while the axis is moving toward its switch.
+    pub fn watching(&self, settings: &Settings, axis: XYZId) -> bool {
+        matches!(self.phase, HomingPhase::Seek | HomingPhase::Locate) && self.axis(settings) == Some(axis)
+    }
+
+    fn pull_off(&mut self, settings: &Settings, axis: XYZId) -> HomingStep {
+        self.phase = HomingPhase::PullOff;
+        let config = &settings.homing;
+        let side = *config.side.match_id(axis);
+        HomingStep::Move {
+            axis,
+            distance: -side.direction() * settings.mm_to_steps(axis, config.pull_off),
+            speed: settings.mm_per_min_to_steps(axis, config.seek_rate),
+            until_switch: false,
+        }
+    }
+
+    /// After the last pull off, each motor of a ganged axis with a trim backs off on its own, then zero.
+    fn trim_or_zero(&mut self, settings: &Settings, axis: XYZId, motors: u8, from: u8) -> HomingStep {
+        let config = &settings.homing;
+        let side = *config.side.match_id(axis);
+        if motors > 1 {
+            let trim = (from..motors.min(MAX_GANG as u8))
+                .map(|motor| (motor, settings.mm_to_steps(axis, *config.trim[motor as usize].match_id(axis))))
+                .find(|(_, steps)| *steps != 0);
+            if let Some((motor, steps)) = trim {
+                self.phase = HomingPhase::Trim(motor);
+                return HomingStep::Trim {
+                    axis,
+                    motor,
+                    distance: -side.direction() * steps,
+                    speed: settings.mm_per_min_to_steps(axis, config.locate_rate),
+                };
+            }
+        }
+        self.axis_index += 1;
+        self.phase = HomingPhase::Start;
+        self.pass = 0;
+        HomingStep::Zero(axis)
+    }
+
+    /// `triggered` is the state of the homing switch for the current axis, every motor's switch for a
+    /// ganged axis. `motors` is how many motors with their own switch the axis has.
+    pub fn next_step(&mut self, settings: &Settings, triggered: bool, motors: u8) -> HomingStep {
+        let Some(axis) = self.axis(settings) else {
+            return HomingStep::Done;
+        };
+        let config = &settings.homing;
+        let side = *config.side.match_id(axis);
+        match self.phase {
+            HomingPhase::Start if triggered => self.pull_off(settings, axis),
+            HomingPhase::Start => {
+                self.phase = HomingPhase::Seek;
+                HomingStep::Move {
+                    axis,
+                    // The seek gives up after 1.5 times the axis travel.
+                    distance: side.direction() * settings.mm_to_steps(axis, settings.max_travel.match_id(axis) * 1.5),
+                    speed: settings.mm_per_min_to_steps(axis, config.seek_rate),
+                    until_switch: true,
+                }
+            },
+            HomingPhase::Seek | HomingPhase::Locate if !triggered => HomingStep::Fail(AlarmCode::HomingFailApproach),
+            HomingPhase::Seek | HomingPhase::Locate => self.pull_off(settings, axis),
+            HomingPhase::PullOff if triggered => HomingStep::Fail(AlarmCode::HomingFailPullOff),
+            HomingPhase::PullOff if self.pass < config.passes => {
+                self.pass += 1;
+                self.phase = HomingPhase::Locate;
+                HomingStep::Move {
+                    axis,
+                    distance: side.direction() * settings.mm_to_steps(axis, config.pull_off) * 2,
+                    speed: settings.mm_per_min_to_steps(axis, config.locate_rate),
+                    until_switch: true,
+                }
+            },
+            HomingPhase::PullOff => self.trim_or_zero(settings, axis, motors, 0),
+            HomingPhase::Trim(motor) => self.trim_or_zero(settings, axis, motors, motor + 1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn single_axis_config(axis: XYZId, passes: u8) -> Settings {
+        let mut order = ArrayVec::new();
+        order.push(axis);
+        Settings { homing: HomingConfig { order, passes, ..Default::default() }, ..Default::default() }
+    }
+
+    #[test]
+    fn homing_single_axis_sequence() {
+        let config = single_axis_config(XYZId::X, 1);
+        let mut cycle = HomingCycle::new();
+        let pull_off = config.mm_to_steps(XYZId::X, config.homing.pull_off);
+        let seek_speed = config.mm_per_min_to_steps(XYZId::X, config.homing.seek_rate);
+        let locate_speed = config.mm_per_min_to_steps(XYZId::X, config.homing.locate_rate);
+        assert!(matches!(cycle.next_step(&config, false, 1), HomingStep::Move { axis: XYZId::X, distance: d, until_switch: true, .. } if d < 0));
+        assert!(cycle.watching(&config, XYZId::X));
+        assert!(!cycle.watching(&config, XYZId::Y));
+        assert_eq!(cycle.next_step(&config, true, 1), HomingStep::Move { axis: XYZId::X, distance: pull_off, speed: seek_speed, until_switch: false });
+        assert!(!cycle.watching(&config, XYZId::X), "Pull off ignores the switch.");
+        assert_eq!(cycle.next_step(&config, false, 1), HomingStep::Move { axis: XYZId::X, distance: -2 * pull_off, speed: locate_speed, until_switch: true });
+        assert_eq!(cycle.next_step(&config, true, 1), HomingStep::Move { axis: XYZId::X, distance: pull_off, speed: seek_speed, until_switch: false });
+        assert_eq!(cycle.next_step(&config, false, 1), HomingStep::Zero(XYZId::X));
+        assert_eq!(cycle.next_step(&config, false, 1), HomingStep::Done);
+    }
+
+    #[test]
+    fn homing_max_side_moves_positive() {
+        let config = single_axis_config(XYZId::Z, 0);
+        let mut cycle = HomingCycle::new();
+        assert!(matches!(cycle.next_step(&config, false, 1), HomingStep::Move { axis: XYZId::Z, distance: d, .. } if d > 0));
+        assert!(matches!(cycle.next_step(&config, true, 1), HomingStep::Move { axis: XYZId::Z, distance: d, .. } if d < 0));
+        assert_eq!(cycle.next_step(&config, false, 1), HomingStep::Zero(XYZId::Z), "No locate passes.");
+    }
+
+    #[test]
+    fn homing_multiple_passes() {
+        let config = single_axis_config(XYZId::Y, 3);
+        let mut cycle = HomingCycle::new();
+        cycle.next_step(&config, false, 1); // seek
+        let mut locates = 0;
+        let mut triggered = true;
+        loop {
+            match cycle.next_step(&config, triggered, 1) {
+                HomingStep::Move { until_switch: true, .. } => { locates += 1; triggered = true; },
+                HomingStep::Move { until_switch: false, .. } => triggered = false,
+                HomingStep::Zero(_) => break,
+                step => panic!("unexpected {:?}", step),
+            }
+        }
+        assert_eq!(locates, 3);
+    }
+
+    #[test]
+    fn homing_starts_on_switch() {
+        let config = single_axis_config(XYZId::X, 0);
+        let mut cycle = HomingCycle::new();
+        assert!(matches!(cycle.next_step(&config, true, 1), HomingStep::Move { until_switch: false, .. }), "Already on the switch, pull off first.");
+    }
+
+    #[test]
+    fn homing_fail_approach() {
+        let config = single_axis_config(XYZId::X, 1);
+        let mut cycle = HomingCycle::new();
+        cycle.next_step(&config, false, 1);
+        assert_eq!(cycle.next_step(&config, false, 1), HomingStep::Fail(AlarmCode::HomingFailApproach));
+    }
+
+    #[test]
+    fn homing_fail_pull_off() {
+        let config = single_axis_config(XYZId::X, 1);
+        let mut cycle = HomingCycle::new();
+        cycle.next_step(&config, false, 1);
+        cycle.next_step(&config, true, 1);
+        assert_eq!(cycle.next_step(&config, true, 1), HomingStep::Fail(AlarmCode::HomingFailPullOff));
+    }
+
+    #[test]
+    fn homing_trims_ganged_motors() {
+        let mut config = single_axis_config(XYZId::Y, 0);
+        config.homing.trim[1].y = 0.5;
+        let trim = config.mm_to_steps(XYZId::Y, 0.5);
+        let speed = config.mm_per_min_to_steps(XYZId::Y, config.homing.locate_rate);
+        let mut cycle = HomingCycle::new();
+        cycle.next_step(&config, false, 2);
+        cycle.next_step(&config, true, 2);
+        assert_eq!(cycle.next_step(&config, false, 2), HomingStep::Trim { axis: XYZId::Y, motor: 1, distance: trim, speed }, "Motor 0 has no trim.");
+        assert!(!cycle.watching(&config, XYZId::Y));
+        assert_eq!(cycle.next_step(&config, true, 2), HomingStep::Zero(XYZId::Y), "The switch doesn't matter while trimming.");
+    }
+
+    #[test]
+    fn homing_trim_needs_a_gang() {
+        let mut config = single_axis_config(XYZId::X, 0);
+        config.homing.trim[0].x = 0.5;
+        let mut cycle = HomingCycle::new();
+        cycle.next_step(&config, false, 1);
+        cycle.next_step(&config, true, 1);
+        assert_eq!(cycle.next_step(&config, false, 1), HomingStep::Zero(XYZId::X));
+    }
+
+    #[test]
+    fn homing_default_order() {
+        let config = Settings::default();
+        let mut cycle = HomingCycle::new();
+        let mut zeroed = ArrayVec::<XYZId, 3>::new();
+        let mut triggered = false;
+        for _ in 0..100 {
+            match cycle.next_step(&config, triggered, 1) {
+                HomingStep::Move { until_switch, .. } => triggered = until_switch,
+                HomingStep::Trim { .. } => panic!("no gang"),
+                HomingStep::Zero(axis) => { zeroed.push(axis); triggered = false; },
+                HomingStep::Done => break,
+                HomingStep::Fail(code) => panic!("unexpected {:?}", code),
+            }
+        }
+        assert_eq!(zeroed.as_slice(), &[XYZId::Z, XYZId::X, XYZId::Y]);
+    }
+}
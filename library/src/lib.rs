@@ -1,27 +1,57 @@
 #![no_std]
 
 mod util;
+mod clock;
 mod ast;
 mod lexer;
 mod parser;
 mod rules;
 mod stepper_math;
+mod s_curve;
 mod numbers;
 mod xyz;
 mod channel;
+mod line;
+mod response;
 mod containers;
 mod stepper;
+mod segment;
 mod machine;
 mod settings;
+mod coolant;
+mod realtime;
+mod aux_io;
+mod codes;
+mod system_command;
+mod homing;
+mod envelope;
+mod tool_table;
+mod storage;
+mod board;
 
+pub use crate::clock::*;
 pub use crate::lexer::*;
 pub use crate::parser::*;
 pub use crate::rules::*;
 pub use crate::ast::*;
 pub use crate::xyz::*;
 pub use crate::stepper_math::*;
+pub use crate::s_curve::*;
 pub use crate::channel::*;
+pub use crate::line::*;
+pub use crate::response::*;
 pub use crate::containers::*;
 pub use crate::stepper::*;
+pub use crate::segment::*;
 pub use crate::machine::*;
 pub use crate::settings::*;
+pub use crate::coolant::*;
+pub use crate::realtime::*;
+pub use crate::aux_io::*;
+pub use crate::codes::*;
+pub use crate::system_command::*;
+pub use crate::homing::*;
+pub use crate::envelope::*;
+pub use crate::tool_table::*;
+pub use crate::storage::*;
+pub use crate::board::*;
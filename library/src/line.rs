@@ -0,0 +1,165 @@
+use arrayvec::ArrayVec;
+
+use crate::ErrorCode;
+
+/// Frames serial bytes into lines. `\r`, `\n` and `\r\n` all end a line, a line longer than `SIZE` is dropped
+/// up to its end and so is one that isn't UTF-8. Either is reported once, when its terminator arrives, so every
+/// line gets exactly one response and a character counting sender only frees it once all its bytes are read.
+#[derive(Clone, Default)]
+pub struct LineAssembler<const SIZE: usize> {
+    buffer: ArrayVec<u8, SIZE>,
+    ready: bool,
+    discarding: bool,
+    after_cr: bool,
+}
+
+impl<const SIZE: usize> LineAssembler<SIZE> {
+    pub fn new() -> Self {
+        Self { buffer: ArrayVec::new(), ready: false, discarding: false, after_cr: false }
+    }
+
+    /// Feed one byte. Stop feeding while `line` returns a line, bytes pushed then are ignored.
+    pub fn push(&mut self, byte: u8) -> Result<(), ErrorCode> {
+        if self.ready {
+            return Ok(());
+        }
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        match byte {
+            b'\n' if after_cr => Ok(()),
+            b'\r' | b'\n' => self.end_line(),
+            _ if self.discarding => Ok(()),
+            _ => {
+                if self.buffer.try_push(byte).is_err() {
+                    self.buffer.clear();
+                    self.discarding = true;
+                }
+                Ok(())
+            },
+        }
+    }
+
+    fn end_line(&mut self) -> Result<(), ErrorCode> {
+        if core::mem::take(&mut self.discarding) {
+            return Err(ErrorCode::LineOverflow);
+        }
+        if core::str::from_utf8(&self.buffer).is_err() {
+            self.buffer.clear();
+            return Err(ErrorCode::ExpectedCommandLetter);
+        }
+        self.ready = true;
+        Ok(())
+    }
+
+    /// The complete line without its terminator, possibly empty.
+    pub fn line(&self) -> Option<&str> {
+        if !self.ready {
+            return None;
+        }
+        core::str::from_utf8(&self.buffer).ok()
+    }
+
+    /// Done with the current line, start assembling the next one.
+    pub fn finish_line(&mut self) {
+        self.buffer.clear();
+        self.ready = false;
+    }
+
+    /// Drop everything, including a partial or discarded line.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::{string::String, vec::Vec};
+    use super::*;
+
+    /// Lines and errors in arrival order, `Err` for an error.
+    fn frame<const SIZE: usize>(input: &[u8]) -> Vec<Result<String, ErrorCode>> {
+        let mut assembler = LineAssembler::<SIZE>::new();
+        let mut out = Vec::new();
+        for &b in input {
+            if let Err(e) = assembler.push(b) {
+                out.push(Err(e));
+            }
+            if let Some(line) = assembler.line() {
+                out.push(Ok(line.into()));
+                assembler.finish_line();
+            }
+        }
+        out
+    }
+
+    fn lines(lines: &[&str]) -> Vec<Result<String, ErrorCode>> {
+        lines.iter().map(|&l| Ok(l.into())).collect()
+    }
+
+    #[test]
+    fn terminators() {
+        assert_eq!(frame::<8>(b"G0\nG1\rG2\r\nG3"), lines(&["G0", "G1", "G2"]));
+    }
+
+    #[test]
+    fn crlf_is_one_terminator() {
+        assert_eq!(frame::<8>(b"\r\n\r\nG0\r\n"), lines(&["", "", "G0"]));
+        assert_eq!(frame::<8>(b"\n\r\r\n"), lines(&["", "", ""]));
+        assert_eq!(frame::<8>(b"\n\n"), lines(&["", ""]));
+    }
+
+    #[test]
+    fn overlong_line_discarded_until_newline() {
+        assert_eq!(frame::<4>(b"G0 X100 Y5\r\nG1\n"), [Err(ErrorCode::LineOverflow), Ok("G1".into())]);
+        assert_eq!(frame::<4>(b"1234\n12345\n"), [Ok("1234".into()), Err(ErrorCode::LineOverflow)]);
+    }
+
+    #[test]
+    fn overlong_line_reported_at_its_end() {
+        let mut assembler = LineAssembler::<4>::new();
+        for &b in b"G0 X100" {
+            assert_eq!(assembler.push(b), Ok(()));
+        }
+        assert_eq!(assembler.push(b'\r'), Err(ErrorCode::LineOverflow));
+        assert_eq!(assembler.push(b'\n'), Ok(()));
+        assert_eq!(assembler.line(), None);
+    }
+
+    #[test]
+    fn overlong_line_reported_once() {
+        let mut input = Vec::from([b'x'; 1000]);
+        input.extend_from_slice(b"\nok\n");
+        assert_eq!(frame::<200>(&input), [Err(ErrorCode::LineOverflow), Ok("ok".into())]);
+    }
+
+    #[test]
+    fn invalid_utf8_rejected() {
+        assert_eq!(frame::<8>(b"G0\xff\nG1\n"), [Err(ErrorCode::ExpectedCommandLetter), Ok("G1".into())]);
+        // Cut off mid character.
+        assert_eq!(frame::<8>(b"\xc3\n\xc3\xa9\n"), [Err(ErrorCode::ExpectedCommandLetter), Ok("\u{e9}".into())]);
+    }
+
+    #[test]
+    fn waits_for_finish_line() {
+        let mut assembler = LineAssembler::<8>::new();
+        for &b in b"G0\nG1\n" {
+            assembler.push(b).unwrap();
+        }
+        assert_eq!(assembler.line(), Some("G0"));
+        assembler.finish_line();
+        assert_eq!(assembler.line(), None);
+    }
+
+    #[test]
+    fn reset_drops_partial_line() {
+        let mut assembler = LineAssembler::<4>::new();
+        for &b in b"G0 X1" {
+            let _ = assembler.push(b);
+        }
+        assembler.reset();
+        for &b in b"G1\n" {
+            assembler.push(b).unwrap();
+        }
+        assert_eq!(assembler.line(), Some("G1"));
+    }
+}
@@ -1,44 +1,299 @@
 use arrayvec::ArrayVec;
-use crate::{u32sqrt, ArgumentMnumonic, CanRecieve, CommandId, CommandMnumonics, GcodeCommand, StepDir, Stepper, XYZData, XYZId, ACC_CURVE, RESOLUTION, STEPPER_SPEED};
+#[allow(unused)]
+use micromath::F32Ext;
+use crate::{elapsed, load_settings, load_tool_table, load_work_offset, save_settings, save_tool_table, save_work_offset, AlarmCode, ArgumentMnumonic, AuxChange, AuxIo, AuxTable, AuxValue, CanRecieve, CanSend, CommandId, CommandMnumonics, Coolant, CoolantState, ErrorCode, Feedback, GcodeCommand, HomingCycle, HomingStep, InputWait, LimitInputs, LimitSwitch, LimitWatch, Message, Ramp, Response, SegmentOutput, SegmentPrep, Settings, StepDir, Stepper, Storage, SystemCommand, ToolTable, TravelEnvelope, WaitMode, XYZData, XYZId, RESOLUTION, XYZ_ID_LIST};
 
 pub enum AbsMode {
     Abs,
     Relative,
 }
 
-pub struct Machine<SD: StepDir>
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MachineState {
+    Idle,
+    Run,
+    Home,
+    Alarm,
+    /// Feed hold, queued commands wait for `~`.
+    Hold,
+}
+
+/// Commands the machine holds past the gcode channel, `Bf:` reports how many are free.
+pub const PLANNER_BLOCKS: usize = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MachineStatus {
+    pub state: MachineState,
+    /// Machine position in micrometers.
+    pub position_um: XYZData<i32>,
+    pub coolant: CoolantState,
+    pub alarm: Option<AlarmCode>,
+    /// Free slots out of `PLANNER_BLOCKS`.
+    pub planner_free: usize,
+}
+
+/// What's left to send of a system command's response.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum SystemReply {
+    /// `$$`, from this index into the settings, then `ok`.
+    Settings(usize),
+    Done(Result<(), ErrorCode>),
+}
+
+pub struct Machine<SD: StepDir, C: Coolant, IO: AuxIo, L: LimitInputs, S: Storage>
 {
-    pub steppers: XYZData<Stepper<SD>>,
+    pub steppers: XYZData<Stepper<SD, Ramp>>,
     //motor_max_speed: XYZData<u32>,
-    max_feed_rate: u32,
-    feed_rate: u32,
+    /// mm/min, `None` until an F word is given and moves run at the axis max rates.
+    feed_rate: Option<f32>,
     home_offset: XYZData<i32>,
-    command_buffer: ArrayVec<GcodeCommand, 2>,
+    command_buffer: ArrayVec<GcodeCommand, PLANNER_BLOCKS>,
     abs_mode: AbsMode,
+    alarm: Option<AlarmCode>,
+    /// Last alarm `report_task` sent, so each one goes out once.
+    reported_alarm: Option<AlarmCode>,
+    /// Goes out as `responses` has room, `report_task` carries on with it.
+    system_reply: Option<SystemReply>,
+    coolant: C,
+    coolant_state: CoolantState,
+    aux_io: IO,
+    aux: AuxTable,
+    wait: Option<InputWait>,
+    /// Machine position for a move queued behind the current one, used by G28/G30.
+    follow_up: Option<XYZData<Option<i32>>>,
+    limits: L,
+    settings: Settings,
+    storage: S,
+    tools: ToolTable,
+    homing: Option<HomingCycle>,
+    homed: bool,
+    /// A move was dropped for leaving the envelope, the rest of the stream waits for `cycle_start`.
+    hold: bool,
+    /// `hold` was set and `report_task` hasn't said why yet.
+    report_hold: bool,
+    /// The switch that raised the last hard limit alarm.
+    tripped_limit: Option<LimitSwitch>,
+    /// Set by a hard limit, the machine may have skipped steps. Cleared by homing.
+    position_lost: bool,
+    envelope: TravelEnvelope,
+    g28_position: XYZData<i32>,
+    g30_position: XYZData<i32>,
+    segments: SegmentPrep,
+    /// Segments still queued or stepping, as of the last `prep_segments`.
+    segments_busy: bool,
+    /// Motion was stopped, `prep_segments` throws away what's queued.
+    segments_abort: bool,
+    /// Step signal settings changed, `prep_segments` passes them on once the executor is idle.
+    segments_configure: bool,
+    /// What the executor was last told to stop at.
+    segments_watch: LimitWatch,
+    motors_enabled: bool,
+    /// M17, the drivers stay energised through idle until M18/M84.
+    motors_held: bool,
+    /// Start of the idle period counted against `$1`.
+    idle_since: Option<u32>,
 }
 
 pub const RES_F32: f32 = RESOLUTION as f32;
 
 #[allow(static_mut_refs)]
-impl<SD: StepDir> Machine<SD>
+impl<SD: StepDir, C: Coolant, IO: AuxIo, L: LimitInputs, S: Storage> Machine<SD, C, IO, L, S>
 {
-    pub fn new(step_dir_fn: SD) -> Self {
-        let x = Stepper::new(XYZId::X, step_dir_fn.clone(), ACC_CURVE.as_ref());
-        let y = Stepper::new(XYZId::Y, step_dir_fn.clone(), ACC_CURVE.as_ref());
-        let z = Stepper::new(XYZId::Z, step_dir_fn.clone(), ACC_CURVE.as_ref());
-        Self {
-            feed_rate: STEPPER_SPEED * RESOLUTION,
-            max_feed_rate: STEPPER_SPEED * RESOLUTION,
+    /// Settings, the work offset and the tool table are loaded from `storage`, defaults if it holds nothing valid.
+    pub fn new(step_dir_fn: SD, coolant: C, aux_io: IO, limits: L, mut storage: S) -> Self {
+        let settings = load_settings(&mut storage);
+        let x = Stepper::with_profile(XYZId::X, step_dir_fn.clone(), settings.ramp(XYZId::X));
+        let y = Stepper::with_profile(XYZId::Y, step_dir_fn.clone(), settings.ramp(XYZId::Y));
+        let z = Stepper::with_profile(XYZId::Z, step_dir_fn.clone(), settings.ramp(XYZId::Z));
+        let work_offset = load_work_offset(&mut storage);
+        let tools = load_tool_table(&mut storage);
+        let envelope = TravelEnvelope::from_settings(&settings);
+        let mut machine = Self {
+            feed_rate: None,
             //motor_max_speed: speeds,
             steppers: XYZData { x, y, z },
             command_buffer: Default::default(),
-            home_offset: Default::default(),
+            home_offset: work_offset.map_id(|axis, mm| settings.mm_to_steps(axis, *mm)),
             abs_mode: AbsMode::Abs,
+            alarm: None,
+            reported_alarm: None,
+            system_reply: None,
+            coolant,
+            coolant_state: CoolantState::OFF,
+            aux_io,
+            aux: Default::default(),
+            wait: None,
+            follow_up: None,
+            limits,
+            settings,
+            storage,
+            tools,
+            homing: None,
+            homed: false,
+            hold: false,
+            report_hold: false,
+            tripped_limit: None,
+            position_lost: false,
+            envelope,
+            g28_position: Default::default(),
+            g30_position: Default::default(),
+            segments: SegmentPrep::new(),
+            segments_busy: false,
+            segments_abort: false,
+            segments_configure: false,
+            segments_watch: LimitWatch::Off,
+            motors_enabled: false,
+            motors_held: false,
+            idle_since: None,
+        };
+        machine.apply_settings();
+        machine.enable_motors(false);
+        machine
+    }
+
+    fn apply_settings(&mut self) {
+        for axis in XYZ_ID_LIST {
+            let invert = *self.settings.dir_invert.match_id(axis);
+            let step_invert = *self.settings.step_invert.match_id(axis);
+            let ramp = self.settings.ramp(axis);
+            let stepper = self.steppers.match_id_mut(axis);
+            stepper.set_dir_invert(invert);
+            stepper.set_step_invert(step_invert);
+            stepper.set_signal(self.settings.step_signal());
+            stepper.set_profile(ramp);
+        }
+        self.segments.set_signal(self.settings.step_signal());
+        self.segments_configure = true;
+        self.envelope = TravelEnvelope::from_settings(&self.settings);
+    }
+
+    pub fn settings(&self) -> &Settings { &self.settings }
+
+    pub fn tools(&self) -> &ToolTable { &self.tools }
+
+    fn set_coolant(&mut self, state: CoolantState) {
+        if state.mist != self.coolant_state.mist {
+            self.coolant.mist(state.mist);
+        }
+        if state.flood != self.coolant_state.flood {
+            self.coolant.flood(state.flood);
+        }
+        self.coolant_state = state;
+    }
+
+    fn stop_motion(&mut self) {
+        self.command_buffer.clear();
+        self.wait = None;
+        self.follow_up = None;
+        for s in self.steppers.iter_mut() {
+            s.stop();
+        }
+        // Nothing handed over, nothing to take back.
+        self.segments_abort |= self.segments_busy;
+    }
+
+    fn enable_motors(&mut self, on: bool) {
+        for s in self.steppers.iter_mut() {
+            s.enable(on);
+        }
+        self.motors_enabled = on;
+    }
+
+    pub fn motors_enabled(&self) -> bool { self.motors_enabled }
+
+    /// Releases the drivers after `$1` ms of idle, unless held by M17 or `$1` is 255.
+    fn idle_release(&mut self, now: u32) {
+        let idle = self.state() == MachineState::Idle && self.motion_done();
+        if !idle || !self.motors_enabled || self.motors_held {
+            self.idle_since = None;
+            return;
+        }
+        let delay = self.settings.step_idle_delay;
+        if delay == u8::MAX {
+            return;
+        }
+        let since = *self.idle_since.get_or_insert(now);
+        if elapsed(now, since) >= delay as u32 * 1000 {
+            self.enable_motors(false);
+            self.idle_since = None;
+        }
+    }
+
+    /// Every move planned has been stepped out.
+    fn motion_done(&self) -> bool {
+        !self.segments_busy && self.steppers.all(|s| s.on_target())
+    }
+
+    fn program_end(&mut self) {
+        self.feed_rate = None;
+        self.set_coolant(CoolantState::OFF);
+    }
+
+    /// Soft reset. Motion stops immediately, queued commands are dropped and outputs are switched off.
+    pub fn reset(&mut self, reciever: &impl CanRecieve<GcodeCommand>) {
+        let was_homing = self.homing.take().is_some();
+        self.stop_motion();
+        while reciever.recieve().is_some() {}
+        self.abs_mode = AbsMode::Abs;
+        self.alarm = None;
+        self.hold = false;
+        self.report_hold = false;
+        self.system_reply = None;
+        self.tripped_limit = None;
+        self.program_end();
+        self.aux.all_off(&mut self.aux_io);
+        if was_homing {
+            self.alarm(AlarmCode::HomingFailReset);
+        }
+    }
+
+    /// Resume after a soft limit feed hold, the dropped move is skipped.
+    pub fn cycle_start(&mut self) {
+        self.hold = false;
+    }
+
+    /// Stop everything and lock out new commands until reset.
+    pub fn alarm(&mut self, code: AlarmCode) {
+        self.stop_motion();
+        self.homing = None;
+        self.unlock_motors();
+        self.alarm = Some(code);
+        self.motors_held = false;
+        self.enable_motors(false);
+        self.set_coolant(CoolantState::OFF);
+        self.aux.all_off(&mut self.aux_io);
+    }
+
+    pub fn is_homed(&self) -> bool { self.homed }
+
+    pub fn position_lost(&self) -> bool { self.position_lost }
+
+    pub fn tripped_limit(&self) -> Option<LimitSwitch> { self.tripped_limit }
+
+    pub fn active_alarm(&self) -> Option<AlarmCode> { self.alarm }
+
+    pub fn aux(&self) -> &AuxTable { &self.aux }
+
+    pub fn state(&self) -> MachineState {
+        if self.alarm.is_some() { MachineState::Alarm }
+        else if self.homing.is_some() { MachineState::Home }
+        else if self.hold { MachineState::Hold }
+        else if self.command_buffer.is_empty() { MachineState::Idle }
+        else { MachineState::Run }
+    }
+
+    pub fn status(&self) -> MachineStatus {
+        MachineStatus {
+            state: self.state(),
+            position_um: self.steppers.map_id(|axis, s| (self.settings.steps_to_mm(axis, s.get_position()) * 1000.0) as i32),
+            coolant: self.coolant_state,
+            alarm: self.alarm,
+            planner_free: self.command_buffer.remaining_capacity(),
         }
     }
 
-    fn move_command(&mut self, target: XYZData<Option<i32>>, speed: u32) {
-        if target.all(|v| v.is_none()) || speed == 0 {
+    fn move_command(&mut self, target: XYZData<Option<i32>>, feed: Option<f32>) {
+        if target.all(|v| v.is_none()) || feed == Some(0.0) {
             return;
         }
         let current_position = self.steppers.map(|s| s.get_position());
@@ -46,28 +301,93 @@ impl<SD: StepDir> Machine<SD>
             AbsMode::Relative => target,
             AbsMode::Abs => target + self.home_offset - current_position.clone(),
         }.map(|p| p.unwrap_or_default());
-        let move_distance = u32sqrt(move_vector.iter().map(|v| (v * v) as u32).sum());
-        if move_distance == 0 {
+        self.move_vector_command(move_vector, feed);
+    }
+
+    /// Move to a position in machine coordinates, ignoring offsets and G91.
+    fn machine_move_command(&mut self, target: XYZData<Option<i32>>, feed: Option<f32>) {
+        let current_position = self.steppers.map(|s| s.get_position());
+        self.move_vector_command((target - current_position).map(|p| p.unwrap_or_default()), feed);
+    }
+
+    /// `feed` is the path speed in mm/min, `None` for rapids. No axis goes faster than its max rate either way.
+    fn move_vector_command(&mut self, move_vector: XYZData<i32>, feed: Option<f32>) {
+        let current_position = self.steppers.map(|s| s.get_position());
+        if move_vector.all(|v| *v == 0) {
             return;
         }
-        let move_distance = move_distance as f64;
-        let altered_speed_fn = |distance: i32| -> u32 {
-            (speed as f64 * (distance.abs() as f64 / move_distance)) as u32
-        };
-        if move_vector.x != 0 {
-            self.steppers.x.set_target(move_vector.x + current_position.x, altered_speed_fn(move_vector.x));
+        if self.settings.soft_limits && self.homed && !self.envelope.contains(&(current_position + move_vector)) {
+            // Dropped before any motion so the position is still good, the sender decides whether to go on.
+            self.hold = true;
+            self.report_hold = true;
+            self.follow_up = None;
+            return;
+        }
+        self.aux.apply_synced(&mut self.aux_io);
+        if !self.motors_enabled {
+            self.enable_motors(true);
         }
-        if move_vector.y != 0 {
-            self.steppers.y.set_target(move_vector.y + current_position.y, altered_speed_fn(move_vector.y));
+        let move_mm = move_vector.map_id(|axis, v| self.settings.steps_to_mm(axis, *v).abs());
+        let move_distance = move_mm.iter().map(|v| v * v).sum::<f32>().sqrt();
+        // Seconds for the whole move, stretched until every axis is within its max rate.
+        let mut duration = feed.map(|f| move_distance * 60.0 / f).unwrap_or(0.0);
+        for axis in XYZ_ID_LIST {
+            duration = duration.max(move_mm.match_id(axis) * 60.0 / self.settings.max_rate.match_id(axis));
         }
-        if move_vector.z != 0 {
-            self.steppers.z.set_target(move_vector.z + current_position.z, altered_speed_fn(move_vector.z));
+        for axis in XYZ_ID_LIST {
+            let distance = *move_vector.match_id(axis);
+            if distance != 0 {
+                let speed = ((distance.abs() as f32 / duration) as u32).max(1);
+                self.steppers.match_id_mut(axis).set_target(distance + current_position.match_id(axis), speed);
+            }
+        }
+    }
+
+    /// mm/min
+    fn feed_argument(args: &GcodeCommand) -> Option<f32> {
+        args.argument(ArgumentMnumonic::F).map(|f| f.float)
+    }
+
+    /// M62-M65 use P for the digital port, M67/M68 use E for the analog port and Q for the value.
+    fn aux_change(command: &GcodeCommand) -> Option<AuxChange> {
+        let digital_port = || command.argument(ArgumentMnumonic::P).map(|p| p.major as u8);
+        match command.command_id {
+            CommandId{ mnumonic: CommandMnumonics::M, major: 62 | 64, minor: _ } => {
+                digital_port().map(|port| AuxChange { port, value: AuxValue::Digital(true) })
+            },
+            CommandId{ mnumonic: CommandMnumonics::M, major: 63 | 65, minor: _ } => {
+                digital_port().map(|port| AuxChange { port, value: AuxValue::Digital(false) })
+            },
+            CommandId{ mnumonic: CommandMnumonics::M, major: 67 | 68, minor: _ } => {
+                let port = command.argument(ArgumentMnumonic::E)?.major as u8;
+                let value = command.argument(ArgumentMnumonic::Q)?.float;
+                Some(AuxChange { port, value: AuxValue::Analog(value) })
+            },
+            _ => None,
+        }
+    }
+
+    /// Commands that act as soon as they are read, without waiting on queued motion.
+    fn immediate_command(&mut self, command: &GcodeCommand) -> bool {
+        match command.command_id {
+            CommandId{ mnumonic: CommandMnumonics::M, major: 64 | 65 | 68, minor: _ } => {
+                if let Some(change) = Self::aux_change(command) {
+                    self.aux.set(&mut self.aux_io, change);
+                }
+                true
+            },
+            _ => false,
         }
     }
 
-    fn feed_argument<'a>(args: &GcodeCommand) -> Option<u32> {
-        let args = args.arguments.iter();
-        args.filter(|a| a.mnumonic == ArgumentMnumonic::F).next().map(|a| ((a.value.float * 60.0 / (RESOLUTION as f32)) as u32))
+    fn axis_arguments(&self, command: &GcodeCommand) -> XYZData<Option<i32>> {
+        let mut target = XYZData::<Option<i32>>::default();
+        for arg in command.arguments.iter() {
+            if let Some(id) = XYZId::from_arg(arg.mnumonic) {
+                *target.match_id_mut(id) = Some(self.settings.mm_to_steps(id, arg.value.float));
+            }
+        }
+        target
     }
 
     fn setup_next_target(&mut self) {
@@ -77,28 +397,68 @@ impl<SD: StepDir> Machine<SD>
                     let mut target = XYZData::<Option<i32>>::default();
                     for arg in command.arguments.iter() {
                         if let Some(id) = XYZId::from_arg(arg.mnumonic) {
-                            *target.match_id_mut(id) = Some((arg.value.float * RES_F32) as i32);
+                            *target.match_id_mut(id) = Some(self.settings.mm_to_steps(id, arg.value.float));
                         }
                     }
-                    let feed_rate = Self::feed_argument(&command).unwrap_or(self.max_feed_rate);
+                    let feed_rate = Self::feed_argument(command);
                     self.move_command(target, feed_rate);
                 },
                 CommandId{ mnumonic: CommandMnumonics::G, major: 1, minor: 0 } => {
                     let mut target = XYZData::<Option<i32>>::default();
                     for arg in command.arguments.iter() {
                         if let Some(id) = XYZId::from_arg(arg.mnumonic) {
-                            *target.match_id_mut(id) = Some((arg.value.float * RES_F32) as i32);
+                            *target.match_id_mut(id) = Some(self.settings.mm_to_steps(id, arg.value.float));
                         }
                     }
-                    self.feed_rate = Self::feed_argument(&command).unwrap_or(self.feed_rate);
+                    self.feed_rate = Self::feed_argument(command).or(self.feed_rate);
                     self.move_command(target, self.feed_rate);
                 },
                 CommandId{ mnumonic: CommandMnumonics::G, major: 9, minor: 2 } => {
                     for arg in command.arguments.iter() {
                         if let Some(id) = XYZId::from_arg(arg.mnumonic) {
-                            *self.home_offset.match_id_mut(id) = (arg.value.float * RES_F32) as i32;
+                            *self.home_offset.match_id_mut(id) = self.settings.mm_to_steps(id, arg.value.float);
+                        }
+                    }
+                    let work_offset = self.home_offset.map_id(|axis, steps| self.settings.steps_to_mm(axis, *steps));
+                    save_work_offset(&mut self.storage, &work_offset);
+                },
+                CommandId{ mnumonic: CommandMnumonics::G, major: 10, minor: 0 } => {
+                    // G10 L1 P<tool> sets the tool length from Z and the radius from R.
+                    let tool = command.argument(ArgumentMnumonic::P).map(|p| p.major as u8).unwrap_or(0);
+                    let is_l1 = command.argument(ArgumentMnumonic::L).map(|l| l.major) == Some(1);
+                    if let (true, Some(entry)) = (is_l1, self.tools.get_mut(tool)) {
+                        if let Some(z) = command.argument(ArgumentMnumonic::Z) {
+                            entry.length = z.float;
+                        }
+                        if let Some(r) = command.argument(ArgumentMnumonic::R) {
+                            entry.diameter = r.float * 2.0;
                         }
+                        save_tool_table(&mut self.storage, &self.tools);
+                    }
+                },
+                CommandId{ mnumonic: CommandMnumonics::G, major: major @ (28 | 30), minor: 0 } => {
+                    // Rapid through the optional intermediate point, then to the stored position.
+                    // With axis words only those axes go to the stored position.
+                    let intermediate = self.axis_arguments(command);
+                    let stored = if major == 28 { self.g28_position } else { self.g30_position };
+                    let target = if intermediate.all(|v| v.is_none()) {
+                        stored.map(|p| Some(*p))
                     }
+                    else {
+                        XYZData {
+                            x: intermediate.x.map(|_| stored.x),
+                            y: intermediate.y.map(|_| stored.y),
+                            z: intermediate.z.map(|_| stored.z),
+                        }
+                    };
+                    self.move_command(intermediate, None);
+                    self.follow_up = Some(target);
+                },
+                CommandId{ mnumonic: CommandMnumonics::G, major: 28, minor: 1 } => {
+                    self.g28_position = self.steppers.map(|s| s.get_position());
+                },
+                CommandId{ mnumonic: CommandMnumonics::G, major: 30, minor: 1 } => {
+                    self.g30_position = self.steppers.map(|s| s.get_position());
                 },
                 CommandId{ mnumonic: CommandMnumonics::G, major: 90, minor: _ } => {
                     self.abs_mode = AbsMode::Abs;
@@ -108,8 +468,39 @@ impl<SD: StepDir> Machine<SD>
                 },
                 CommandId{ mnumonic: CommandMnumonics::G, major: 21, minor: _ } => {},
                 CommandId{ mnumonic: CommandMnumonics::G, major: 20, minor: _ } => { todo!("Inch mode not supported.")},
-                CommandId{ mnumonic: CommandMnumonics::M, major: 30, minor: _ } => { 
-                    self.feed_rate = self.max_feed_rate;
+                CommandId{ mnumonic: CommandMnumonics::M, major: 2, minor: _ } |
+                CommandId{ mnumonic: CommandMnumonics::M, major: 30, minor: _ } => {
+                    self.program_end();
+                },
+                CommandId{ mnumonic: CommandMnumonics::M, major: 7, minor: _ } => {
+                    self.set_coolant(CoolantState { mist: true, ..self.coolant_state });
+                },
+                CommandId{ mnumonic: CommandMnumonics::M, major: 8, minor: _ } => {
+                    self.set_coolant(CoolantState { flood: true, ..self.coolant_state });
+                },
+                CommandId{ mnumonic: CommandMnumonics::M, major: 9, minor: _ } => {
+                    self.set_coolant(CoolantState::OFF);
+                },
+                CommandId{ mnumonic: CommandMnumonics::M, major: 17, minor: _ } => {
+                    self.enable_motors(true);
+                    self.motors_held = true;
+                },
+                CommandId{ mnumonic: CommandMnumonics::M, major: 18 | 84, minor: _ } => {
+                    self.motors_held = false;
+                    self.enable_motors(false);
+                },
+                CommandId{ mnumonic: CommandMnumonics::M, major: 62 | 63 | 67, minor: _ } => {
+                    if let Some(change) = Self::aux_change(command) {
+                        self.aux.set_synced(change);
+                    }
+                },
+                CommandId{ mnumonic: CommandMnumonics::M, major: 66, minor: _ } => {
+                    let port = command.argument(ArgumentMnumonic::P).map(|p| p.major as u8);
+                    let mode = WaitMode::from_l_word(command.argument(ArgumentMnumonic::L).map(|l| l.major).unwrap_or(0));
+                    let timeout_us = command.argument(ArgumentMnumonic::Q).map(|q| (q.float * 1_000_000.0) as u32);
+                    if let (Some(port), Some(mode)) = (port, mode) {
+                        self.wait = Some(InputWait::new(port, mode, timeout_us));
+                    }
                 },
                 CommandId{ mnumonic: CommandMnumonics::M, major: _, minor: _ } => {},
                 _ => { /*todo!("do no know how to process command.")*/ },
@@ -122,49 +513,457 @@ impl<SD: StepDir> Machine<SD>
         }
     }
 
-    pub fn poll_task(&mut self, reciever: &impl CanRecieve<GcodeCommand>) {
+    pub fn system_command(&mut self, command: SystemCommand) -> Result<(), ErrorCode> {
+        match command {
+            SystemCommand::Home => {
+                if !self.settings.homing_enabled {
+                    return Err(ErrorCode::HomingDisabled);
+                }
+                if matches!(self.state(), MachineState::Run | MachineState::Home | MachineState::Hold) {
+                    return Err(ErrorCode::IdleError);
+                }
+                self.alarm = None;
+                self.homed = false;
+                self.homing = Some(HomingCycle::new());
+                Ok(())
+            },
+            // Nothing to change, the caller prints `settings()` or the build info.
+            SystemCommand::ReportSettings | SystemCommand::BuildInfo => Ok(()),
+            SystemCommand::SetSetting(id, value) => {
+                if matches!(self.state(), MachineState::Run | MachineState::Home | MachineState::Hold) {
+                    return Err(ErrorCode::IdleError);
+                }
+                self.settings.set(id, value)?;
+                save_settings(&mut self.storage, &self.settings);
+                self.apply_settings();
+                Ok(())
+            },
+        }
+    }
+
+    /// `system_command`, answered through `responses` with any feedback and then `ok` or the error.
+    /// Runs `command` and sends what fits of its response, the rest goes out from `report_task`. Only one
+    /// at a time, wait for `replying` to clear before the next.
+    pub fn respond_system_command(&mut self, command: SystemCommand, responses: &impl CanSend<Response>) {
+        self.system_reply = Some(match self.system_command(command) {
+            Ok(()) if command == SystemCommand::ReportSettings => SystemReply::Settings(0),
+            result => SystemReply::Done(result),
+        });
+        self.system_reply_task(responses);
+    }
+
+    /// A system command's response hasn't all gone out yet.
+    pub fn replying(&self) -> bool { self.system_reply.is_some() }
+
+    fn system_reply_task(&mut self, responses: &impl CanSend<Response>) {
+        while let Some(reply) = self.system_reply {
+            let (response, next) = match reply {
+                SystemReply::Settings(index) => match self.settings.iter().nth(index) {
+                    Some((id, value)) => (Response::Feedback(Feedback::Setting(id, value)), Some(SystemReply::Settings(index + 1))),
+                    None => (Response::Ok, None),
+                },
+                SystemReply::Done(Ok(())) => (Response::Ok, None),
+                SystemReply::Done(Err(code)) => (Response::Error(code), None),
+            };
+            if responses.send(response).is_err() {
+                return;
+            }
+            self.system_reply = next;
+        }
+    }
+
+    /// Sends a newly raised alarm, and for hard limits the switch, or why the stream is on hold, and the rest
+    /// of a system command's response. Retries next time if `responses` is full.
+    pub fn report_task(&mut self, responses: &impl CanSend<Response>) {
+        self.system_reply_task(responses);
+        if self.report_hold && responses.send(Response::Message(Message::SoftLimit)).is_ok() {
+            self.report_hold = false;
+        }
+        if self.alarm == self.reported_alarm {
+            return;
+        }
+        if let Some(code) = self.alarm {
+            if responses.send(Response::Alarm(code)).is_err() {
+                return;
+            }
+            if let Some(limit) = self.tripped_limit.filter(|_| code == AlarmCode::HardLimit) {
+                let _ = responses.send(Response::Message(Message::Limit(limit)));
+            }
+        }
+        self.reported_alarm = self.alarm;
+    }
+
+    /// Hold one motor of a ganged axis at its current position while the rest of the axis moves on.
+    fn lock_motor(&mut self, axis: XYZId, motor: u8) {
+        self.steppers.match_id_mut(axis).lock_motor(motor, true);
+    }
+
+    /// Also the ones the step interrupt parked at their switch.
+    fn unlock_motors(&mut self) {
+        for axis in XYZ_ID_LIST {
+            for motor in 0..self.limits.motors(axis) {
+                self.steppers.match_id_mut(axis).lock_motor(motor, false);
+            }
+        }
+    }
+
+    /// The homing switch of `axis`. For a ganged axis while seeking it's every motor's switch, each side
+    /// stops at its own, otherwise any of them.
+    fn homing_switch(&mut self, axis: XYZId, seeking: bool) -> bool {
+        let side = *self.settings.homing.side.match_id(axis);
+        let motors = self.limits.motors(axis);
+        if motors > 1 && seeking {
+            (0..motors).all(|motor| self.limits.motor_triggered(axis, motor, side))
+        }
+        else {
+            self.limits.triggered(axis, side)
+        }
+    }
+
+    fn homing_task(&mut self) {
+        if !self.motion_done() {
+            return;
+        }
+        self.unlock_motors();
+        while let Some(cycle) = self.homing.as_ref() {
+            let (axis, seeking) = match cycle.axis(&self.settings) {
+                Some(axis) => (Some(axis), cycle.watching(&self.settings, axis)),
+                None => (None, false),
+            };
+            let triggered = axis.is_some_and(|axis| self.homing_switch(axis, seeking));
+            let motors = axis.map_or(1, |axis| self.limits.motors(axis));
+            let Some(cycle) = self.homing.as_mut() else { return };
+            match cycle.next_step(&self.settings, triggered, motors) {
+                HomingStep::Move { axis, distance, speed, .. } => {
+                    if !self.motors_enabled {
+                        self.enable_motors(true);
+                    }
+                    let stepper = self.steppers.match_id_mut(axis);
+                    let target = stepper.get_position() + distance;
+                    stepper.set_target(target, speed);
+                    return;
+                },
+                HomingStep::Trim { axis, motor, distance, speed } => {
+                    for other in (0..self.limits.motors(axis)).filter(|&other| other != motor) {
+                        self.lock_motor(axis, other);
+                    }
+                    let stepper = self.steppers.match_id_mut(axis);
+                    let target = stepper.get_position() + distance;
+                    stepper.set_target(target, speed);
+                    return;
+                },
+                HomingStep::Zero(axis) => self.steppers.match_id_mut(axis).set_position(0),
+                HomingStep::Fail(code) => {
+                    self.alarm(code);
+                    return;
+                },
+                HomingStep::Done => {
+                    self.homing = None;
+                    self.homed = true;
+                    self.position_lost = false;
+                },
+            }
+        }
+    }
+
+    pub fn poll_task(&mut self, now: u32, reciever: &impl CanRecieve<GcodeCommand>) {
+        if self.segments_abort {
+            // Stopped motion comes off the positions in `prep_segments` before anything new starts from them.
+            return;
+        }
+        if self.homing.is_some() {
+            self.homing_task();
+            return;
+        }
+        if self.alarm.is_some() {
+            let _ = reciever.recieve(); // locked out, discard.
+            return;
+        }
         if self.command_buffer.remaining_capacity() != 0 {
             if let Some(next) = reciever.recieve() {
-                self.command_buffer.push(next);
-                if self.command_buffer.len() == 1 {
-                    self.setup_next_target();
+                if !self.immediate_command(&next) {
+                    self.command_buffer.push(next);
+                    if self.command_buffer.len() == 1 {
+                        self.setup_next_target();
+                    }
                 }
             }
         }
-        if self.command_buffer.len() != 0 && self.steppers.all(|s| s.on_target()) {
-            self.command_buffer.remove(0);
-            self.setup_next_target();
+        if let Some(wait) = self.wait.as_mut() {
+            let level = self.aux_io.digital_in(wait.port);
+            if wait.poll(now, level) {
+                self.wait = None;
+            }
+        }
+        if !self.command_buffer.is_empty() && self.wait.is_none() && !self.hold && self.motion_done() {
+            if let Some(target) = self.follow_up.take() {
+                self.machine_move_command(target, None);
+            }
+            else {
+                self.command_buffer.remove(0);
+                self.setup_next_target();
+            }
+        }
+        self.idle_release(now);
+    }
+
+    /// What the step interrupt stops at: the homing switch while seeking it, otherwise the hard limits.
+    fn limit_watch(&self) -> LimitWatch {
+        match &self.homing {
+            Some(cycle) => match cycle.axis(&self.settings) {
+                Some(axis) if cycle.watching(&self.settings, axis) => LimitWatch::Homing { axis, side: *self.settings.homing.side.match_id(axis) },
+                _ => LimitWatch::Off,
+            },
+            None if self.settings.hard_limits => LimitWatch::Hard,
+            None => LimitWatch::Off,
         }
     }
 
-    pub fn step_monitor(&mut self, now: u64, axis: XYZId) {
-        if self.command_buffer.len() != 0 {
-            // poll only one axis at a time for 'niceness'. This code executes in
-            // interrupts so we don't want other interrupts for timekeeping to be missed.
-            self.steppers.one_map_mut(axis, |s| s.poll_task(now));
+    /// Step generation. Called from the main loop next to `poll_task`, it keeps `output` topped up while
+    /// the step interrupt runs a `SegmentExecutor`, and picks up where that stopped at a switch. Stopped
+    /// motion is taken back off the positions by the steps that never went out.
+    pub fn prep_segments(&mut self, output: &mut impl SegmentOutput) {
+        if let Some(switch) = output.tripped() {
+            if self.homing.is_some() {
+                // `homing_task` carries on from the switch.
+                self.steppers.match_id_mut(switch.axis).stop();
+            }
+            else {
+                self.alarm(AlarmCode::HardLimit);
+                self.tripped_limit = Some(switch);
+                self.homed = false;
+                self.position_lost = true;
+            }
+            self.segments_abort = true;
+        }
+        if core::mem::take(&mut self.segments_abort) {
+            let dropped = output.abort() + self.segments.reset();
+            for axis in XYZ_ID_LIST {
+                // Segments carry the direction pin level, not the direction.
+                let dropped = if *self.settings.dir_invert.match_id(axis) { -dropped.match_id(axis) } else { *dropped.match_id(axis) };
+                let stepper = self.steppers.match_id_mut(axis);
+                stepper.set_position(stepper.get_position() - dropped);
+            }
+        }
+        if self.segments_configure && !output.is_busy() {
+            output.configure(self.settings.step_signal(), self.settings.step_invert, self.settings.dir_invert);
+            self.segments_configure = false;
+        }
+        let watch = self.limit_watch();
+        if watch != self.segments_watch {
+            output.watch(watch);
+            self.segments_watch = watch;
         }
+        if !self.command_buffer.is_empty() || self.homing.is_some() {
+            while !output.is_full() {
+                let steppers = &mut self.steppers;
+                match self.segments.prep(|axis| steppers.match_id_mut(axis).next_step()) {
+                    Some(segment) => output.push(segment),
+                    None => break,
+                }
+            }
+        }
+        self.segments_busy = output.is_busy() || !self.segments.is_idle();
     }
 }
 
 #[cfg(test)]
 mod tests {
+    extern crate std;
+    use core::cell::RefCell;
+    use std::rc::Rc;
     use crate::*;
 
     use super::*;
+    /// Physical axis positions, counted from the rising edges the steppers actually output.
+    #[derive(Default, Debug)]
+    struct SimAxes {
+        position: XYZData<i32>,
+        negative: XYZData<bool>,
+        high: XYZData<bool>,
+        enabled: XYZData<bool>,
+        /// Drivers that step on the falling edge.
+        step_invert: XYZData<bool>,
+        /// Step pin changes on any axis.
+        edges: u32,
+        /// Second Y motor, counts Y steps unless it's locked.
+        y2: i32,
+        /// Min switch of the second Y motor, Y is ganged when it's set.
+        y2_min: Option<i32>,
+        /// Y motors held by `lock_motor`.
+        locked: [bool; MAX_GANG],
+    }
+    type Sim = Rc<RefCell<SimAxes>>;
+
+    #[derive(Default, Clone, Debug)]
+    struct SimStepper {
+        sim: Sim,
+    }
+    impl StepDir for SimStepper {
+        fn step(&mut self, axis: XYZId, high: bool) {
+            let mut sim = self.sim.borrow_mut();
+            if high == *sim.high.match_id(axis) {
+                return;
+            }
+            sim.edges += 1;
+            *sim.high.match_id_mut(axis) = high;
+            if high != *sim.step_invert.match_id(axis) {
+                assert!(sim.enabled.match_id(axis), "{:?} stepped with its driver off.", axis);
+                let step = if *sim.negative.match_id(axis) { -1 } else { 1 };
+                if axis != XYZId::Y {
+                    *sim.position.match_id_mut(axis) += step;
+                    return;
+                }
+                if !sim.locked[0] {
+                    sim.position.y += step;
+                }
+                if !sim.locked[1] {
+                    sim.y2 += step;
+                }
+            }
+        }
+        fn dir(&mut self, axis: XYZId, direction: bool) { *self.sim.borrow_mut().negative.match_id_mut(axis) = direction; }
+        fn enable(&mut self, axis: XYZId, on: bool) { *self.sim.borrow_mut().enabled.match_id_mut(axis) = on; }
+        fn lock_motor(&mut self, axis: XYZId, motor: u8, idle: Option<bool>) {
+            if axis == XYZId::Y {
+                self.sim.borrow_mut().locked[motor as usize] = idle.is_some();
+            }
+        }
+    }
+    #[derive(Default, Clone, Copy, Debug)]
+    struct TestCoolant {
+        pub state: CoolantState,
+    }
+    impl Coolant for TestCoolant {
+        fn mist(&mut self, on: bool) { self.state.mist = on; }
+        fn flood(&mut self, on: bool) { self.state.flood = on; }
+    }
     #[derive(Default, Clone, Copy, Debug)]
-    struct CounterStepper {
-        pub current_step: u32,
-        pub current_dir: bool,
+    struct TestIo {
+        pub digital: [bool; AUX_PORTS],
+        pub analog: [f32; AUX_PORTS],
+        pub inputs: [bool; AUX_PORTS],
+    }
+    impl AuxIo for TestIo {
+        fn digital_out(&mut self, port: u8, on: bool) { self.digital[port as usize] = on; }
+        fn analog_out(&mut self, port: u8, value: f32) { self.analog[port as usize] = value; }
+        fn digital_in(&mut self, port: u8) -> bool { self.inputs[port as usize] }
+    }
+
+    /// Switches at fixed physical positions.
+    #[derive(Default, Clone, Debug)]
+    struct TestLimits {
+        sim: Sim,
+        pub min: XYZData<Option<i32>>,
+        pub max: XYZData<Option<i32>>,
+    }
+    impl LimitInputs for TestLimits {
+        fn triggered(&mut self, axis: XYZId, side: LimitSide) -> bool {
+            (0..self.motors(axis)).any(|motor| self.motor_triggered(axis, motor, side))
+        }
+        fn motors(&mut self, axis: XYZId) -> u8 {
+            if axis == XYZId::Y && self.sim.borrow().y2_min.is_some() { 2 } else { 1 }
+        }
+        fn motor_triggered(&mut self, axis: XYZId, motor: u8, side: LimitSide) -> bool {
+            let sim = self.sim.borrow();
+            if motor == 1 {
+                return side == LimitSide::Min && sim.y2_min.is_some_and(|s| sim.y2 <= s);
+            }
+            let p = *sim.position.match_id(axis);
+            match side {
+                LimitSide::Min => self.min.match_id(axis).is_some_and(|s| p <= s),
+                LimitSide::Max => self.max.match_id(axis).is_some_and(|s| p >= s),
+            }
+        }
+    }
+
+    type TestStorage = MemoryStorage<STORAGE_SIZE>;
+    type TestMachine = Machine<SimStepper, TestCoolant, TestIo, TestLimits, TestStorage>;
+
+    /// Compare timer for the executor, fires early when the delay is longer than `max_delay_us`.
+    struct TestTimer {
+        scheduled: Option<u32>,
+        max_delay_us: u32,
     }
-    impl StepDir for CounterStepper {
-        fn step(&mut self, _: XYZId) { self.current_step += 1; }
-        fn dir(&mut self, _: XYZId, direction: bool) { self.current_dir = direction; }
+    impl StepTimer for TestTimer {
+        fn schedule(&mut self, delay_us: u32) { self.scheduled = Some(delay_us.min(self.max_delay_us)); }
+        fn stop(&mut self) { self.scheduled = None; }
+    }
+
+    /// The step interrupt's end of segment stepping, run by hand.
+    struct TestSegments {
+        ring: Producer<'static, Segment, 8>,
+        source: Consumer<'static, Segment, 8>,
+        executor: SegmentExecutor<SimStepper, TestLimits>,
+        timer: TestTimer,
+    }
+    impl SegmentOutput for TestSegments {
+        fn push(&mut self, segment: Segment) { self.ring.send(segment).unwrap(); }
+        fn is_full(&self) -> bool { self.ring.is_full() }
+        fn is_busy(&self) -> bool { !self.ring.is_empty() || self.executor.is_busy() }
+        fn abort(&mut self) -> XYZData<i32> { self.executor.abort(&self.source) }
+        fn configure(&mut self, signal: StepSignal, step_invert: XYZData<bool>, dir_invert: XYZData<bool>) { self.executor.configure(signal, step_invert, dir_invert) }
+        fn watch(&mut self, watch: LimitWatch) { self.executor.watch(watch) }
+        fn tripped(&self) -> Option<LimitSwitch> { self.executor.tripped() }
+    }
+
+    fn sim_machine(settings: Settings, min: XYZData<Option<i32>>, max: XYZData<Option<i32>>) -> (TestMachine, TestSegments, Sim) {
+        let sim = Sim::default();
+        sim.borrow_mut().step_invert = settings.step_invert;
+        let limits = TestLimits { sim: sim.clone(), min, max };
+        let mut storage = TestStorage::default();
+        save_settings(&mut storage, &settings);
+        let machine = Machine::new(SimStepper { sim: sim.clone() }, TestCoolant::default(), TestIo::default(), limits.clone(), storage);
+        let timer = TestTimer { scheduled: None, max_delay_us: u32::MAX };
+        let (ring, source) = std::boxed::Box::leak(std::boxed::Box::new(SegmentRing::new())).split();
+        let output = TestSegments { ring, source, executor: SegmentExecutor::new(SimStepper { sim: sim.clone() }, limits), timer };
+        (machine, output, sim)
+    }
+
+    /// The channel from the parser, input end then the machine's end.
+    fn gcode_queue() -> (Producer<'static, GcodeCommand, 3>, Consumer<'static, GcodeCommand, 3>) {
+        std::boxed::Box::leak(std::boxed::Box::new(SpscQueue::new())).split()
+    }
+
+    fn test_machine() -> (TestMachine, TestSegments) {
+        let (machine, output, _) = sim_machine(Settings::default(), Default::default(), Default::default());
+        (machine, output)
+    }
+
+    /// Main loop with `prep_segments`, the interrupt in between. Runs until idle or `until` says so,
+    /// returns the time of the last poll.
+    fn run_segments(machine: &mut TestMachine, output: &mut TestSegments, gcode_channel: &impl CanRecieve<GcodeCommand>, until: impl Fn(&TestMachine) -> bool) -> u32 {
+        let mut now = 1;
+        for _ in 0..10_000_000 {
+            machine.poll_task(now, gcode_channel);
+            machine.prep_segments(output);
+            if until(machine) || (machine.state() == MachineState::Idle && !output.is_busy()) {
+                break;
+            }
+            if let Some(delay) = output.timer.scheduled {
+                now = now.wrapping_add(delay);
+            }
+            output.executor.step_interrupt(now, &output.source, &mut output.timer);
+            if output.timer.scheduled.is_none() {
+                // Nothing stepping, dwells and input waits still need the clock.
+                now = now.wrapping_add(20);
+            }
+        }
+        now
+    }
+
+    fn run_until_idle(machine: &mut TestMachine, output: &mut TestSegments, gcode_channel: &impl CanRecieve<GcodeCommand>) -> u32 {
+        run_segments(machine, output, gcode_channel, |m| matches!(m.state(), MachineState::Alarm | MachineState::Hold))
+    }
+
+    fn run_homing(machine: &mut TestMachine, output: &mut TestSegments) {
+        let (_, gcode_channel) = gcode_queue();
+        run_segments(machine, output, &gcode_channel, |m| m.state() != MachineState::Home);
     }
 
     #[test]
     pub fn machine_can_init() {
-        let gcode_channel = SplitChannel::new(crate::Channel::<GcodeCommand, 3>::default());
-        let gcode_input = gcode_channel.create_sender();
+        let (gcode_input, gcode_channel) = gcode_queue();
         let mut gcode: GcodeCommand = Default::default();
         gcode.command_id = CommandId{ mnumonic: CommandMnumonics::G, major: 0, minor: 0 };
         let mut x_arg: CommandArgument = Default::default();
@@ -174,11 +973,14 @@ mod tests {
         x_arg.value.minor = 23;
         gcode.arguments.push(x_arg);
         let _ = gcode_input.send(gcode);
-        let mut machine = Machine::new(CounterStepper::default());
-        machine.poll_task(&gcode_channel);
-        machine.step_monitor(1, XYZId::X);
-        let x_first_time = machine.steppers.x.timing.next_update_time.clone() as u32;
-        assert_eq!(x_first_time, ACC_CURVE[0] + 1, "Straight move. First delay in acc curve.");
+        let (mut machine, mut output) = test_machine();
+        machine.poll_task(0, &gcode_channel);
+        machine.prep_segments(&mut output);
+        let segments: std::vec::Vec<Segment> = core::iter::from_fn(|| output.source.recieve()).collect();
+        let (index, first) = segments.iter().enumerate().find(|(_, s)| s.axes.x.steps != 0).unwrap();
+        let x_first_time = index as u32 * SEGMENT_US + first.axes.x.first_us as u32;
+        let mut ramp: Ramp = machine.settings().ramp(XYZId::X);
+        assert_eq!(x_first_time, ramp.delay(0), "Straight move. First delay in acc curve.");
         assert!(!machine.steppers.x.on_target(), "Move requires movement.");
     }
 
@@ -197,67 +999,37 @@ mod tests {
 
     #[test]
     pub fn machine_forward_and_back() {
-        let gcode_channel = SplitChannel::new(crate::Channel::<GcodeCommand, 3>::default());
-        let gcode_input = gcode_channel.create_sender();
+        let (gcode_input, gcode_channel) = gcode_queue();
         let gcode_x1: GcodeCommand = move_command(XYZId::X, 1.0);
         let gcode_x0: GcodeCommand = move_command(XYZId::X, 0.0);
-        let mut machine = Machine::new(CounterStepper::default());
+        let (mut machine, mut output, sim) = sim_machine(Settings::default(), Default::default(), Default::default());
 
         let _ = gcode_input.send(gcode_x1);
-        machine.poll_task(&gcode_channel);
-        machine.step_monitor(1, XYZId::X);
+        machine.poll_task(0, &gcode_channel);
         assert!(!machine.steppers.x.on_target(), "Move requires movement. 1");
-        for i in 2..100000 {
-            machine.step_monitor(i * 10, XYZId::X);
-            if machine.steppers.x.on_target() {
-                break;
-            }
-        }
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
         assert!(machine.steppers.x.on_target(), "should be on target 10.");
+        assert_eq!(sim.borrow().position.x, RESOLUTION as i32);
 
         let _ = gcode_input.send(gcode_x0);
-        machine.poll_task(&gcode_channel);
-        machine.step_monitor(1, XYZId::X);
+        machine.poll_task(0, &gcode_channel);
         assert!(!machine.steppers.x.on_target(), "Move requires movement. 0");
-        for i in 2..100000 {
-            machine.step_monitor(i * 10, XYZId::X);
-            if machine.steppers.x.on_target() {
-                break;
-            }
-        }
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
         assert!(machine.steppers.x.on_target(), "should be on target 0.");
+        assert_eq!(sim.borrow().position.x, 0);
     }
 
     #[test]
     pub fn machine_xy() {
-        let gcode_channel = SplitChannel::new(crate::Channel::<GcodeCommand, 3>::default());
-        let gcode_input = gcode_channel.create_sender();
+        let (gcode_input, gcode_channel) = gcode_queue();
         let mut gcode: GcodeCommand = move_command(XYZId::X, 1.0);
         gcode.arguments.push(move_command(XYZId::Y, 10.0).arguments.first().unwrap().clone());
-        let mut machine = Machine::new(CounterStepper::default());
+        let (mut machine, mut output, sim) = sim_machine(Settings::default(), Default::default(), Default::default());
         let _ = gcode_input.send(gcode);
-        machine.poll_task(&gcode_channel);
-        machine.step_monitor(1, XYZId::X);
-        machine.step_monitor(1, XYZId::Y);
+        machine.poll_task(0, &gcode_channel);
         assert!(!machine.steppers.x.on_target(), "Move requires movement. 1");
-        let mut i_x = 0;
-        let mut i_y = 0;
-        for i in 2..100000 {
-            machine.step_monitor(i * 10, XYZId::X);
-            machine.step_monitor(i * 10, XYZId::Y);
-            if machine.steppers.x.on_target() {
-                i_x = i;
-            }
-            if machine.steppers.y.on_target() {
-                i_y = i;
-            }
-            if machine.steppers.x.on_target() && machine.steppers.y.on_target() {
-                break;
-            }
-        }
-        assert_eq!(i_x, i_y, "X and Y should end near each other.");
-        assert_ne!(i_x, 0, "X should end at a non zero iteration");
-        assert_ne!(i_y, 0, "Y should end at a non zero iteration");
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(sim.borrow().position, XYZData { x: RESOLUTION as i32, y: 10 * RESOLUTION as i32, z: 0 }, "Both axes stepped out.");
         assert_eq!(machine.steppers.x.get_position(), 1 * RESOLUTION as i32, "XPosition");
         assert_eq!(machine.steppers.y.get_position(), 10 * RESOLUTION as i32, "YPosition");
         assert!(machine.steppers.x.on_target(), "should be on target 10.");
@@ -265,18 +1037,708 @@ mod tests {
 
     #[test]
     pub fn machine_specify_feedrate() {
-        let gcode_channel = SplitChannel::new(crate::Channel::<GcodeCommand, 3>::default());
-        let gcode_input = gcode_channel.create_sender();
+        let (gcode_input, gcode_channel) = gcode_queue();
         let mut gcode: GcodeCommand = move_command(XYZId::X, 10.0);
         gcode.arguments.push(CommandArgument { mnumonic: ArgumentMnumonic::F, value: MajorMinorNumber { major: 100, minor: 0, float: 100.0 } });
-        let mut machine = Machine::new(CounterStepper::default());
-        let default_feed_rate = machine.max_feed_rate.clone();
+        let (mut machine, _) = test_machine();
+        let default_feed_rate = machine.feed_rate;
         let _ = gcode_input.send(gcode);
-        assert_ne!(default_feed_rate, (100.0 * 60.0 / RES_F32) as u32, "Debug test assert, test feed rate should not be default.");
-        machine.poll_task(&gcode_channel);
-        machine.step_monitor(1, XYZId::X);
+        assert_ne!(default_feed_rate, Some(100.0), "Debug test assert, test feed rate should not be default.");
+        machine.poll_task(0, &gcode_channel);
 
         assert_ne!(machine.steppers.x.get_target(), 0, "Debug test assert. Target needs to be set for feed rate.");
         assert_ne!(machine.feed_rate, default_feed_rate, "Machine feed rate should be changed.");
     }
+
+    fn m_command(major: u16) -> GcodeCommand {
+        GcodeCommand { command_id: CommandId{ mnumonic: CommandMnumonics::M, major, minor: 0 }, arguments: Default::default() }
+    }
+
+    fn run_commands(machine: &mut TestMachine, commands: &[GcodeCommand]) {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        for command in commands {
+            let _ = gcode_input.send(command.clone());
+            machine.poll_task(0, &gcode_channel);
+            machine.poll_task(0, &gcode_channel);
+        }
+    }
+
+    #[test]
+    pub fn machine_coolant_mist_flood_off() {
+        let (mut machine, _) = test_machine();
+        run_commands(&mut machine, &[m_command(7)]);
+        assert_eq!(machine.coolant.state, CoolantState { mist: true, flood: false });
+        run_commands(&mut machine, &[m_command(8)]);
+        assert_eq!(machine.coolant.state, CoolantState { mist: true, flood: true });
+        assert_eq!(machine.status().coolant, CoolantState { mist: true, flood: true });
+        run_commands(&mut machine, &[m_command(9)]);
+        assert_eq!(machine.coolant.state, CoolantState::OFF);
+        assert_eq!(machine.status().coolant, CoolantState::OFF);
+    }
+
+    #[test]
+    pub fn machine_coolant_off_on_program_end() {
+        let (mut machine, _) = test_machine();
+        run_commands(&mut machine, &[m_command(8), m_command(30)]);
+        assert!(machine.coolant.state.is_off(), "M30 should stop coolant.");
+        run_commands(&mut machine, &[m_command(7), m_command(2)]);
+        assert!(machine.coolant.state.is_off(), "M2 should stop coolant.");
+    }
+
+    #[test]
+    pub fn machine_coolant_off_on_alarm_and_reset() {
+        let (_, gcode_channel) = gcode_queue();
+        let (mut machine, _) = test_machine();
+        run_commands(&mut machine, &[m_command(8)]);
+        machine.alarm(AlarmCode::HomingFailApproach);
+        assert!(machine.coolant.state.is_off(), "Alarm should stop coolant.");
+        assert_eq!(machine.state(), MachineState::Alarm);
+
+        machine.reset(&gcode_channel);
+        assert_eq!(machine.state(), MachineState::Idle);
+        run_commands(&mut machine, &[m_command(7)]);
+        machine.reset(&gcode_channel);
+        assert!(machine.coolant.state.is_off(), "Reset should stop coolant.");
+    }
+
+    #[test]
+    pub fn machine_reset_stops_motion() {
+        let (mut machine, mut output, sim) = sim_machine(Settings::default(), Default::default(), Default::default());
+        let (_, gcode_channel) = gcode_queue();
+        run_commands(&mut machine, &[move_command(XYZId::X, 10.0)]);
+        run_segments(&mut machine, &mut output, &gcode_channel, |_| sim.borrow().position.x >= 100);
+        assert_eq!(machine.state(), MachineState::Run);
+        assert_eq!(machine.status().planner_free, PLANNER_BLOCKS - 1);
+        machine.reset(&gcode_channel);
+        machine.prep_segments(&mut output);
+        assert_eq!(machine.state(), MachineState::Idle);
+        assert_eq!(machine.status().planner_free, PLANNER_BLOCKS);
+        assert!(machine.steppers.x.on_target(), "Reset should abandon the move.");
+        assert_eq!(machine.steppers.x.get_position(), sim.borrow().position.x, "Steps made before the reset are kept.");
+    }
+
+    fn m_command_args(major: u16, args: &[(ArgumentMnumonic, f32)]) -> GcodeCommand {
+        let mut gcode = m_command(major);
+        for &(mnumonic, float) in args {
+            gcode.arguments.push(CommandArgument { mnumonic, value: MajorMinorNumber { major: float as i32, minor: 0, float } });
+        }
+        gcode
+    }
+
+    #[test]
+    pub fn machine_synced_output_waits_for_move() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let (mut machine, mut output, sim) = sim_machine(Settings::default(), Default::default(), Default::default());
+        let _ = gcode_input.send(move_command(XYZId::X, 1.0));
+        let _ = gcode_input.send(m_command_args(62, &[(ArgumentMnumonic::P, 1.0)]));
+        let _ = gcode_input.send(move_command(XYZId::X, 2.0));
+        machine.poll_task(0, &gcode_channel);
+        machine.poll_task(0, &gcode_channel);
+        assert!(!machine.aux_io.digital[1], "M62 should not act during the previous move.");
+        run_segments(&mut machine, &mut output, &gcode_channel, |m| m.aux_io.digital[1]);
+        assert!(machine.aux_io.digital[1], "M62 should act when the next move starts.");
+        assert_eq!(sim.borrow().position.x, RESOLUTION as i32, "Not before the first move's steps are out.");
+        assert!(machine.aux().digital[1]);
+        assert!(!machine.steppers.x.on_target(), "Output should switch at the start of the second move.");
+        assert_eq!(machine.steppers.x.get_target(), 2 * RESOLUTION as i32);
+    }
+
+    #[test]
+    pub fn machine_immediate_output_during_move() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let (mut machine, _) = test_machine();
+        let _ = gcode_input.send(move_command(XYZId::X, 10.0));
+        let _ = gcode_input.send(m_command_args(64, &[(ArgumentMnumonic::P, 2.0)]));
+        let _ = gcode_input.send(m_command_args(68, &[(ArgumentMnumonic::E, 0.0), (ArgumentMnumonic::Q, 128.0)]));
+        machine.poll_task(0, &gcode_channel);
+        machine.poll_task(0, &gcode_channel);
+        machine.poll_task(0, &gcode_channel);
+        assert_eq!(machine.state(), MachineState::Run);
+        assert!(machine.aux_io.digital[2], "M64 should not wait for the move.");
+        assert_eq!(machine.aux_io.analog[0], 128.0, "M68 should not wait for the move.");
+
+        let _ = gcode_input.send(m_command_args(65, &[(ArgumentMnumonic::P, 2.0)]));
+        machine.poll_task(0, &gcode_channel);
+        assert!(!machine.aux_io.digital[2]);
+    }
+
+    #[test]
+    pub fn machine_synced_analog_output() {
+        let (mut machine, _) = test_machine();
+        run_commands(&mut machine, &[m_command_args(67, &[(ArgumentMnumonic::E, 1.0), (ArgumentMnumonic::Q, 0.5)])]);
+        assert_eq!(machine.aux_io.analog[1], 0.0);
+        run_commands(&mut machine, &[move_command(XYZId::Y, 1.0)]);
+        assert_eq!(machine.aux_io.analog[1], 0.5);
+    }
+
+    #[test]
+    pub fn machine_wait_for_input() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let (mut machine, _) = test_machine();
+        let _ = gcode_input.send(m_command_args(66, &[(ArgumentMnumonic::P, 3.0), (ArgumentMnumonic::L, 3.0)]));
+        let _ = gcode_input.send(m_command(8));
+        for i in 0..10 {
+            machine.poll_task(i, &gcode_channel);
+        }
+        assert_eq!(machine.state(), MachineState::Run, "Should still be waiting on the input.");
+        assert!(!machine.coolant.state.flood, "Commands after M66 wait for it.");
+        machine.aux_io.inputs[3] = true;
+        machine.poll_task(10, &gcode_channel);
+        machine.poll_task(11, &gcode_channel);
+        assert!(machine.coolant.state.flood);
+    }
+
+    #[test]
+    pub fn machine_wait_for_input_timeout() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let (mut machine, _) = test_machine();
+        let _ = gcode_input.send(m_command_args(66, &[(ArgumentMnumonic::P, 0.0), (ArgumentMnumonic::L, 1.0), (ArgumentMnumonic::Q, 0.5)]));
+        machine.poll_task(1_000, &gcode_channel);
+        machine.poll_task(400_000, &gcode_channel);
+        assert_eq!(machine.state(), MachineState::Run);
+        machine.poll_task(501_000, &gcode_channel);
+        assert_eq!(machine.state(), MachineState::Idle, "Wait should end after the Q timeout.");
+    }
+
+    #[test]
+    pub fn machine_reset_clears_outputs() {
+        let (_, gcode_channel) = gcode_queue();
+        let (mut machine, _) = test_machine();
+        run_commands(&mut machine, &[m_command_args(64, &[(ArgumentMnumonic::P, 0.0)]), m_command_args(62, &[(ArgumentMnumonic::P, 1.0)])]);
+        assert!(machine.aux_io.digital[0]);
+        machine.reset(&gcode_channel);
+        assert!(!machine.aux_io.digital[0]);
+        assert!(!machine.aux().has_pending(), "Synced outputs are dropped with the abandoned moves.");
+    }
+
+    fn g_command(major: u16, minor: u16, args: &[(ArgumentMnumonic, f32)]) -> GcodeCommand {
+        let mut gcode = m_command_args(major, args);
+        gcode.command_id = CommandId{ mnumonic: CommandMnumonics::G, major, minor };
+        gcode
+    }
+
+    fn fast_homing_config() -> Settings {
+        let homing = HomingConfig { seek_rate: 1000.0, locate_rate: 200.0, pull_off: 0.5, ..Default::default() };
+        Settings { homing, max_travel: XYZData::from_clone(10.0), ..Default::default() }
+    }
+
+    #[test]
+    pub fn machine_homing_sets_zero() {
+        let min = XYZData { x: Some(-300), y: Some(-150), z: None };
+        let max = XYZData { x: None, y: None, z: Some(200) };
+        let (mut machine, mut output, sim) = sim_machine(fast_homing_config(), min, max);
+        assert_eq!(machine.system_command(SystemCommand::Home), Ok(()));
+        assert_eq!(machine.state(), MachineState::Home);
+        run_homing(&mut machine, &mut output);
+        let physical = sim.borrow().position;
+        assert_eq!(machine.state(), MachineState::Idle);
+        assert!(machine.is_homed());
+        assert_eq!(machine.steppers.map(|s| s.get_position()), XYZData { x: 0, y: 0, z: 0 }, "Pulled off position is machine zero.");
+        let pull_off = (0.5 * RES_F32) as i32;
+        assert!((physical.x - (-300 + pull_off)).abs() <= 1, "X should end pulled off its min switch, got {}", physical.x);
+        assert!((physical.y - (-150 + pull_off)).abs() <= 1, "Y should end pulled off its min switch, got {}", physical.y);
+        assert!((physical.z - (200 - pull_off)).abs() <= 1, "Z should end pulled off its max switch, got {}", physical.z);
+    }
+
+    #[test]
+    pub fn machine_homing_squares_gang() {
+        let mut settings = fast_homing_config();
+        settings.homing.trim[1].y = 0.1;
+        let min = XYZData { x: Some(-300), y: Some(-150), z: None };
+        let max = XYZData { x: None, y: None, z: Some(200) };
+        let (mut machine, mut output, sim) = sim_machine(settings, min, max);
+        sim.borrow_mut().y2_min = Some(-180);
+        machine.system_command(SystemCommand::Home).unwrap();
+        run_homing(&mut machine, &mut output);
+        assert!(machine.is_homed());
+        assert_eq!(machine.steppers.y.get_position(), 0);
+        let pull_off = (0.5 * RES_F32) as i32;
+        let trim = (0.1 * RES_F32) as i32;
+        let (y, y2) = (sim.borrow().position.y, sim.borrow().y2);
+        assert!((y - (-150 + pull_off)).abs() <= 1, "Y motor off its own switch, got {}", y);
+        assert!((y2 - (-180 + pull_off + trim)).abs() <= 1, "Y2 off its own switch and trimmed, got {}", y2);
+        assert_eq!(sim.borrow().locked, [false; 2]);
+
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::Y, 1.0)]));
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(sim.borrow().y2 - y2, sim.borrow().position.y - y, "Both motors step together in moves.");
+    }
+
+    #[test]
+    pub fn machine_homing_fail_approach() {
+        let min = XYZData { x: Some(-300), y: Some(-150), z: None };
+        let (mut machine, mut output, _) = sim_machine(fast_homing_config(), min, Default::default());
+        machine.system_command(SystemCommand::Home).unwrap();
+        run_homing(&mut machine, &mut output);
+        assert_eq!(machine.state(), MachineState::Alarm);
+        assert_eq!(machine.status().alarm, Some(AlarmCode::HomingFailApproach));
+        assert!(!machine.is_homed());
+    }
+
+    #[test]
+    pub fn machine_homing_reset_alarms() {
+        let (_, gcode_channel) = gcode_queue();
+        let (mut machine, _) = test_machine();
+        machine.system_command(SystemCommand::Home).unwrap();
+        machine.poll_task(0, &gcode_channel);
+        machine.reset(&gcode_channel);
+        assert_eq!(machine.status().alarm, Some(AlarmCode::HomingFailReset));
+        assert_eq!(machine.system_command(SystemCommand::Home), Ok(()), "Homing is allowed from alarm.");
+        assert_eq!(machine.state(), MachineState::Home);
+    }
+
+    #[test]
+    pub fn machine_homing_requires_idle() {
+        let (mut machine, _) = test_machine();
+        run_commands(&mut machine, &[move_command(XYZId::X, 10.0)]);
+        assert_eq!(machine.system_command(SystemCommand::Home), Err(ErrorCode::IdleError));
+    }
+
+    #[test]
+    pub fn machine_g28_stored_position() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let (mut machine, mut output) = test_machine();
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, 2.0), (ArgumentMnumonic::Y, 1.0)]));
+        let _ = gcode_input.send(g_command(28, 1, &[]));
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, 0.0), (ArgumentMnumonic::Y, 0.0)]));
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(machine.status().position_um, XYZData { x: 0, y: 0, z: 0 });
+        assert_eq!(machine.g28_position, XYZData { x: 2 * RESOLUTION as i32, y: RESOLUTION as i32, z: 0 });
+
+        let _ = gcode_input.send(g_command(28, 0, &[]));
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(machine.status().position_um, XYZData { x: 2000, y: 1000, z: 0 }, "G28 goes to the stored position.");
+    }
+
+    #[test]
+    pub fn machine_g30_intermediate_point() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let (mut machine, mut output) = test_machine();
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, 3.0), (ArgumentMnumonic::Y, 3.0)]));
+        let _ = gcode_input.send(g_command(30, 1, &[]));
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, 0.0), (ArgumentMnumonic::Y, 0.0)]));
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+
+        // Through Y1 on the way, then only Y to the stored position.
+        let _ = gcode_input.send(g_command(30, 0, &[(ArgumentMnumonic::Y, 1.0)]));
+        machine.poll_task(0, &gcode_channel);
+        assert_eq!(machine.steppers.y.get_target(), RESOLUTION as i32, "First move is to the intermediate point.");
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(machine.status().position_um, XYZData { x: 0, y: 3000, z: 0 });
+    }
+
+    #[test]
+    pub fn machine_hard_limit_alarms() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let max = XYZData { x: Some(40), y: None, z: None };
+        let (mut machine, mut output, sim) = sim_machine(fast_homing_config(), Default::default(), max);
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, 1.0)]));
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, 2.0)]));
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(machine.state(), MachineState::Alarm);
+        assert_eq!(machine.active_alarm(), Some(AlarmCode::HardLimit));
+        assert_eq!(machine.tripped_limit(), Some(LimitSwitch { axis: XYZId::X, side: LimitSide::Max }));
+        assert!(machine.position_lost());
+        assert_eq!(sim.borrow().position.x, 40, "No steps after the switch.");
+        assert!(machine.steppers.all(|s| s.on_target()));
+        assert!(!machine.motors_enabled(), "Drivers are released on alarm.");
+        assert_eq!(sim.borrow().enabled, XYZData::from_clone(false));
+    }
+
+    #[test]
+    pub fn machine_motors_released_after_idle_delay() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let (mut machine, mut output, sim) = sim_machine(Settings::default(), Default::default(), Default::default());
+        assert!(!machine.motors_enabled());
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::Y, 1.0)]));
+        let now = run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(sim.borrow().position.y, 80);
+        assert_eq!(sim.borrow().enabled, XYZData::from_clone(true));
+        machine.poll_task(now + 24_000, &gcode_channel);
+        assert!(machine.motors_enabled());
+        machine.poll_task(now + 25_000, &gcode_channel);
+        assert!(!machine.motors_enabled());
+        assert_eq!(sim.borrow().enabled, XYZData::from_clone(false));
+    }
+
+    #[test]
+    pub fn machine_motors_held() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let settings = Settings { step_idle_delay: 255, ..Default::default() };
+        let (mut machine, mut output, _) = sim_machine(settings, Default::default(), Default::default());
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, 1.0)]));
+        let now = run_until_idle(&mut machine, &mut output, &gcode_channel);
+        machine.poll_task(now.wrapping_add(u32::MAX / 2), &gcode_channel);
+        assert!(machine.motors_enabled(), "$1=255 never releases.");
+
+        let (mut machine, _) = test_machine();
+        run_commands(&mut machine, &[m_command(17)]);
+        machine.poll_task(1_000_000, &gcode_channel);
+        assert!(machine.motors_enabled(), "M17 holds through idle.");
+        run_commands(&mut machine, &[m_command(18)]);
+        assert!(!machine.motors_enabled());
+        run_commands(&mut machine, &[m_command(17), m_command(84)]);
+        assert!(!machine.motors_enabled());
+    }
+
+    #[test]
+    pub fn machine_reports_alarm_once() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let mut responses = SpscQueue::<Response, 4>::new();
+        let (response_output, response_input) = responses.split();
+        let max = XYZData { x: Some(40), y: None, z: None };
+        let (mut machine, mut output, _) = sim_machine(fast_homing_config(), Default::default(), max);
+        machine.report_task(&response_output);
+        assert_eq!(response_input.recieve(), None);
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, 1.0)]));
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        machine.report_task(&response_output);
+        machine.report_task(&response_output);
+        assert_eq!(response_input.recieve(), Some(Response::Alarm(AlarmCode::HardLimit)));
+        let limit = LimitSwitch { axis: XYZId::X, side: LimitSide::Max };
+        assert_eq!(response_input.recieve(), Some(Response::Message(Message::Limit(limit))));
+        assert_eq!(response_input.recieve(), None);
+    }
+
+    #[test]
+    pub fn machine_hard_limit_drive_off_switch() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let max = XYZData { x: Some(40), y: None, z: None };
+        let (mut machine, mut output, sim) = sim_machine(fast_homing_config(), Default::default(), max);
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, 1.0)]));
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        machine.reset(&gcode_channel);
+        assert_eq!(machine.state(), MachineState::Idle);
+        assert!(machine.position_lost(), "Only homing recovers the position.");
+
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, 0.0)]));
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(machine.state(), MachineState::Idle, "Moving away from the switch is allowed.");
+        assert_eq!(sim.borrow().position.x, 0);
+
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::Y, 0.5)]));
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(machine.state(), MachineState::Idle);
+    }
+
+    #[test]
+    pub fn machine_hard_limits_disabled() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let max = XYZData { x: Some(40), y: None, z: None };
+        let (mut machine, mut output, sim) = sim_machine(fast_homing_config(), Default::default(), max);
+        machine.system_command(SystemCommand::SetSetting(21, 0.0)).unwrap();
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, 1.0)]));
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(machine.state(), MachineState::Idle);
+        assert_eq!(sim.borrow().position.x, RESOLUTION as i32);
+    }
+
+    #[test]
+    pub fn machine_homing_clears_position_lost() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let min = XYZData { x: Some(-300), y: Some(-150), z: None };
+        let max = XYZData { x: None, y: None, z: Some(200) };
+        let (mut machine, mut output, _) = sim_machine(fast_homing_config(), min, max);
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::Z, 4.0)]));
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(machine.active_alarm(), Some(AlarmCode::HardLimit));
+        assert_eq!(machine.tripped_limit(), Some(LimitSwitch { axis: XYZId::Z, side: LimitSide::Max }));
+        machine.system_command(SystemCommand::Home).unwrap();
+        run_homing(&mut machine, &mut output);
+        assert!(machine.is_homed());
+        assert!(!machine.position_lost());
+        assert_eq!(machine.active_alarm(), None);
+    }
+
+    fn homed_sim_machine() -> (TestMachine, TestSegments, Sim) {
+        let min = XYZData { x: Some(-300), y: Some(-150), z: None };
+        let max = XYZData { x: None, y: None, z: Some(200) };
+        let (mut machine, mut output, sim) = sim_machine(fast_homing_config(), min, max);
+        machine.system_command(SystemCommand::Home).unwrap();
+        run_homing(&mut machine, &mut output);
+        assert!(machine.is_homed());
+        (machine, output, sim)
+    }
+
+    #[test]
+    pub fn machine_soft_limit_rejects_move() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let mut responses = SpscQueue::<Response, 4>::new();
+        let (response_output, response_input) = responses.split();
+        let (mut machine, mut output, sim) = homed_sim_machine();
+        let start = sim.borrow().position;
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, 11.0)]));
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(machine.state(), MachineState::Hold);
+        assert_eq!(machine.active_alarm(), None, "Not locked out.");
+        assert_eq!(sim.borrow().position, start, "Rejected before any motion.");
+        assert!(!machine.position_lost());
+        assert!(machine.is_homed());
+        machine.report_task(&response_output);
+        assert_eq!(response_input.recieve(), Some(Response::Message(Message::SoftLimit)));
+        machine.report_task(&response_output);
+        assert_eq!(response_input.recieve(), None, "Said once.");
+        machine.cycle_start();
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(machine.state(), MachineState::Idle);
+        assert_eq!(sim.borrow().position, start);
+    }
+
+    #[test]
+    pub fn machine_soft_limit_holds_stream() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let (mut machine, mut output, _) = homed_sim_machine();
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, 1.0), (ArgumentMnumonic::Z, -1.0)]));
+        let _ = gcode_input.send(g_command(91, 0, &[]));
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::Z, 2.0)]));
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(machine.state(), MachineState::Hold, "Relative move above Z max.");
+        assert_eq!(machine.status().position_um, XYZData { x: 1000, y: 0, z: -1000 });
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::Z, 0.5)]));
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(machine.status().position_um.z, -1000, "Queued moves wait for the resume.");
+        assert_eq!(machine.system_command(SystemCommand::SetSetting(100, 1.0)), Err(ErrorCode::IdleError));
+        machine.cycle_start();
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(machine.state(), MachineState::Idle);
+        assert_eq!(machine.status().position_um, XYZData { x: 1000, y: 0, z: -500 });
+    }
+
+    #[test]
+    pub fn machine_soft_limit_needs_homing() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let (mut machine, mut output) = test_machine();
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, -1.0)]));
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(machine.state(), MachineState::Idle);
+        assert_eq!(machine.status().position_um.x, -1000);
+    }
+
+    #[test]
+    pub fn machine_soft_limit_disabled() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let (mut machine, mut output, _) = homed_sim_machine();
+        machine.system_command(SystemCommand::SetSetting(20, 0.0)).unwrap();
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::Y, -0.25)]));
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(machine.state(), MachineState::Idle);
+        assert_eq!(machine.status().position_um.y, -250);
+    }
+
+    #[test]
+    pub fn machine_settings_steps_per_mm() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let (mut machine, mut output) = test_machine();
+        assert_eq!(machine.system_command(SystemCommand::SetSetting(101, 200.0)), Ok(()));
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, 1.0), (ArgumentMnumonic::Y, 1.0)]));
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(machine.steppers.map(|s| s.get_position()), XYZData { x: 80, y: 200, z: 0 });
+        assert_eq!(machine.status().position_um, XYZData { x: 1000, y: 1000, z: 0 });
+    }
+
+    #[test]
+    pub fn machine_settings_need_idle() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let (mut machine, _) = test_machine();
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, 1.0)]));
+        machine.poll_task(0, &gcode_channel);
+        assert_eq!(machine.system_command(SystemCommand::SetSetting(100, 1.0)), Err(ErrorCode::IdleError));
+        assert_eq!(machine.system_command(SystemCommand::ReportSettings), Ok(()));
+        assert_eq!(machine.settings(), &Settings::default());
+    }
+
+    #[test]
+    pub fn machine_system_command_responses() {
+        let mut responses = SpscQueue::<Response, 64>::new();
+        let (response_output, response_input) = responses.split();
+        let (mut machine, _) = test_machine();
+        machine.respond_system_command(SystemCommand::ReportSettings, &response_output);
+        for (id, value) in Settings::default().iter() {
+            assert_eq!(response_input.recieve(), Some(Response::Feedback(Feedback::Setting(id, value))));
+        }
+        assert_eq!(response_input.recieve(), Some(Response::Ok));
+        machine.respond_system_command(SystemCommand::SetSetting(100, -1.0), &response_output);
+        assert_eq!(response_input.recieve(), Some(Response::Error(ErrorCode::NegativeValue)));
+        assert_eq!(response_input.recieve(), None);
+    }
+
+    #[test]
+    pub fn machine_system_reply_waits_for_room() {
+        let mut responses = SpscQueue::<Response, 4>::new();
+        let (response_output, response_input) = responses.split();
+        let (mut machine, _) = test_machine();
+        machine.respond_system_command(SystemCommand::ReportSettings, &response_output);
+        assert!(machine.replying());
+        let mut sent = std::vec::Vec::new();
+        while machine.replying() {
+            while let Some(response) = response_input.recieve() {
+                sent.push(response);
+            }
+            machine.report_task(&response_output);
+        }
+        sent.extend(core::iter::from_fn(|| response_input.recieve()));
+        let settings = Settings::default();
+        let expected = settings.iter().map(|(id, value)| Response::Feedback(Feedback::Setting(id, value))).chain([Response::Ok]);
+        assert!(sent.into_iter().eq(expected), "Every setting in order, then ok.");
+    }
+
+    #[test]
+    pub fn machine_settings_homing_disabled() {
+        let (mut machine, _) = test_machine();
+        machine.system_command(SystemCommand::SetSetting(22, 0.0)).unwrap();
+        assert_eq!(machine.system_command(SystemCommand::Home), Err(ErrorCode::HomingDisabled));
+        assert_eq!(machine.state(), MachineState::Idle);
+    }
+
+    #[test]
+    pub fn machine_feed_held_to_max_rate() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let (mut machine, _) = test_machine();
+        machine.system_command(SystemCommand::SetSetting(111, 60.0)).unwrap();
+        let mut gcode = g_command(1, 0, &[(ArgumentMnumonic::X, 1.0), (ArgumentMnumonic::Y, 1.0)]);
+        gcode.arguments.push(CommandArgument { mnumonic: ArgumentMnumonic::F, value: MajorMinorNumber { major: 6000, minor: 0, float: 6000.0 } });
+        let _ = gcode_input.send(gcode);
+        machine.poll_task(0, &gcode_channel);
+        // Y max rate is 1mm/s, 80 steps/s. X covers the same distance so it is slowed to match.
+        assert_eq!(machine.steppers.y.step_iter.slew_delay_us(), 1_000_000 / 80);
+        assert_eq!(machine.steppers.x.step_iter.slew_delay_us(), 1_000_000 / 80);
+    }
+
+    fn power_cycle(machine: TestMachine) -> TestMachine {
+        // Same axes, the executor keeps stepping them.
+        let sim = machine.limits.sim.clone();
+        Machine::new(SimStepper { sim: sim.clone() }, TestCoolant::default(), TestIo::default(), TestLimits { sim, ..Default::default() }, machine.storage)
+    }
+
+    #[test]
+    pub fn machine_settings_persist() {
+        let (mut machine, _) = test_machine();
+        machine.system_command(SystemCommand::SetSetting(101, 200.0)).unwrap();
+        assert_eq!(machine.system_command(SystemCommand::SetSetting(100, -1.0)), Err(ErrorCode::NegativeValue));
+        let machine = power_cycle(machine);
+        assert_eq!(machine.settings().steps_per_mm, XYZData { x: 80.0, y: 200.0, z: 80.0 });
+    }
+
+    #[test]
+    pub fn machine_offsets_and_tools_persist() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let (mut machine, mut output) = test_machine();
+        let _ = gcode_input.send(g_command(9, 2, &[(ArgumentMnumonic::X, 1.5)]));
+        let _ = gcode_input.send(g_command(10, 0, &[(ArgumentMnumonic::L, 1.0), (ArgumentMnumonic::P, 2.0), (ArgumentMnumonic::Z, 25.0)]));
+        let _ = gcode_input.send(g_command(10, 0, &[(ArgumentMnumonic::L, 1.0), (ArgumentMnumonic::P, 2.0), (ArgumentMnumonic::R, 3.0)]));
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(machine.tools().get(2), Some(&ToolEntry { length: 25.0, diameter: 6.0 }));
+
+        let mut machine = power_cycle(machine);
+        assert_eq!(machine.tools().get(2), Some(&ToolEntry { length: 25.0, diameter: 6.0 }));
+        assert_eq!(machine.home_offset, XYZData { x: 120, y: 0, z: 0 });
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, 1.0)]));
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(machine.status().position_um.x, 2500, "Moves are relative to the restored offset.");
+    }
+
+    #[test]
+    #[cfg(not(any(feature = "computed-ramp", feature = "s-curve")))]
+    pub fn machine_acc_table_per_axis() {
+        let (mut machine, _) = test_machine();
+        assert_eq!(machine.steppers.x.step_iter.acc_table(), ACC_CURVE.as_slice(), "Defaults match the compile time ramp.");
+        machine.system_command(SystemCommand::SetSetting(122, 150.0)).unwrap();
+        let x = machine.steppers.x.step_iter.acc_table();
+        let z = machine.steppers.z.step_iter.acc_table();
+        assert_eq!(x, ACC_CURVE.as_slice());
+        assert!(z[0] > x[0] * 19 / 10, "Z ramps up slower.");
+    }
+
+    #[test]
+    pub fn machine_segments_move() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let (mut machine, mut output, sim) = sim_machine(Settings::default(), Default::default(), Default::default());
+        let _ = gcode_input.send(g_command(1, 0, &[(ArgumentMnumonic::X, 1.0), (ArgumentMnumonic::Y, 3.0), (ArgumentMnumonic::F, 300.0)]));
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, 0.0), (ArgumentMnumonic::Z, -1.0)]));
+        run_segments(&mut machine, &mut output, &gcode_channel, |_| false);
+        assert_eq!(sim.borrow().position, XYZData { x: 0, y: 3 * RESOLUTION as i32, z: -(RESOLUTION as i32) });
+        assert_eq!(machine.status().position_um, XYZData { x: 0, y: 3000, z: -1000 });
+    }
+
+    #[test]
+    pub fn machine_segments_wait_for_steps() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let (mut machine, mut output, sim) = sim_machine(Settings::default(), Default::default(), Default::default());
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, 2.0)]));
+        let _ = gcode_input.send(m_command_args(8, &[]));
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, 0.0)]));
+        run_segments(&mut machine, &mut output, &gcode_channel, |m| m.coolant_state.flood);
+        assert_eq!(sim.borrow().position.x, 2 * RESOLUTION as i32, "Coolant comes on once the queued steps are out.");
+    }
+
+    #[test]
+    pub fn machine_segments_hard_limit() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let max = XYZData { x: Some(40), y: None, z: None };
+        let mut settings = fast_homing_config();
+        settings.dir_invert.y = true;
+        let (mut machine, mut output, sim) = sim_machine(settings, Default::default(), max);
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, 1.0), (ArgumentMnumonic::Y, 1.0)]));
+        run_until_idle(&mut machine, &mut output, &gcode_channel);
+        assert_eq!(machine.active_alarm(), Some(AlarmCode::HardLimit));
+        assert_eq!(sim.borrow().position.x, 40, "Queued segments are dropped at the switch.");
+        let position = machine.steppers.map(|s| s.get_position());
+        let physical = sim.borrow().position;
+        assert_eq!(position, XYZData { y: -physical.y, ..physical }, "Steps thrown away come off the position, Y is inverted.");
+        assert!(machine.steppers.all(|s| s.on_target()));
+    }
+
+    #[test]
+    pub fn machine_segments_step_invert() {
+        let (gcode_input, gcode_channel) = gcode_queue();
+        let mut settings = Settings::default();
+        settings.step_invert.x = true;
+        settings.step_pulse = 5;
+        let (mut machine, mut output, sim) = sim_machine(settings, Default::default(), Default::default());
+        assert!(sim.borrow().high.x, "X idles high from the start.");
+        let start = sim.borrow().position;
+        let _ = gcode_input.send(g_command(0, 0, &[(ArgumentMnumonic::X, 1.0), (ArgumentMnumonic::Y, 1.0)]));
+        run_segments(&mut machine, &mut output, &gcode_channel, |_| false);
+        let moved = sim.borrow().position - start;
+        assert_eq!(moved, XYZData { x: RESOLUTION as i32, y: RESOLUTION as i32, z: 0 }, "Low pulses on X, high on Y.");
+        assert!(sim.borrow().high.x && !sim.borrow().high.y, "Each back at its idle level.");
+    }
+
+    #[test]
+    pub fn machine_segments_homing() {
+        let min = XYZData { x: Some(-300), y: Some(-150), z: None };
+        let max = XYZData { x: None, y: None, z: Some(200) };
+        let (mut machine, mut output, sim) = sim_machine(fast_homing_config(), min, max);
+        let (_, gcode_channel) = gcode_queue();
+        machine.system_command(SystemCommand::Home).unwrap();
+        run_segments(&mut machine, &mut output, &gcode_channel, |m| m.state() != MachineState::Home);
+        assert!(machine.is_homed());
+        assert_eq!(machine.steppers.map(|s| s.get_position()), XYZData { x: 0, y: 0, z: 0 });
+        let pull_off = (0.5 * RES_F32) as i32;
+        let physical = sim.borrow().position;
+        assert!((physical.x - (-300 + pull_off)).abs() <= 1, "X should end pulled off its min switch, got {}", physical.x);
+        assert!((physical.z - (200 - pull_off)).abs() <= 1, "Z should end pulled off its max switch, got {}", physical.z);
+    }
+
+    #[test]
+    pub fn machine_segments_homing_squares_gang() {
+        let mut settings = fast_homing_config();
+        settings.homing.trim[0].y = 0.1;
+        let min = XYZData { x: Some(-300), y: Some(-180), z: None };
+        let max = XYZData { x: None, y: None, z: Some(200) };
+        let (mut machine, mut output, sim) = sim_machine(settings, min, max);
+        sim.borrow_mut().y2_min = Some(-150);
+        let (_, gcode_channel) = gcode_queue();
+        machine.system_command(SystemCommand::Home).unwrap();
+        run_segments(&mut machine, &mut output, &gcode_channel, |m| m.state() != MachineState::Home);
+        assert!(machine.is_homed());
+        let pull_off = (0.5 * RES_F32) as i32;
+        let trim = (0.1 * RES_F32) as i32;
+        let (y, y2) = (sim.borrow().position.y, sim.borrow().y2);
+        assert!((y - (-180 + pull_off + trim)).abs() <= 1, "Y motor off its own switch and trimmed, got {}", y);
+        assert!((y2 - (-150 + pull_off)).abs() <= 1, "Y2 off its own switch, got {}", y2);
+    }
 }
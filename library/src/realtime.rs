@@ -0,0 +1,33 @@
+/// Single byte commands that bypass the line buffer and act immediately.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RealtimeCommand {
+    StatusReport,
+    Reset,
+    /// `~`, resume after a feed hold.
+    CycleStart,
+}
+
+impl RealtimeCommand {
+    pub fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            b'?' => Some(RealtimeCommand::StatusReport),
+            0x18 => Some(RealtimeCommand::Reset), // ctrl-x
+            b'~' => Some(RealtimeCommand::CycleStart),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn realtime_from_byte() {
+        assert_eq!(RealtimeCommand::from_byte(b'?'), Some(RealtimeCommand::StatusReport));
+        assert_eq!(RealtimeCommand::from_byte(0x18), Some(RealtimeCommand::Reset));
+        assert_eq!(RealtimeCommand::from_byte(b'~'), Some(RealtimeCommand::CycleStart));
+        assert_eq!(RealtimeCommand::from_byte(b'G'), None);
+        assert_eq!(RealtimeCommand::from_byte(b'\n'), None);
+    }
+}
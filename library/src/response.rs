@@ -0,0 +1,192 @@
+use ufmt::{uWrite, uwrite};
+
+use crate::{AlarmCode, ErrorCode, LimitSide, LimitSwitch, MachineState, MachineStatus, SettingValue, XYZId};
+
+/// `[MSG:...]` lines, information that isn't the response to a line.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Message {
+    Text(&'static str),
+    /// The switch behind a hard limit alarm.
+    Limit(LimitSwitch),
+    /// Input was dropped, the sender is out of step with the RX buffer.
+    RxOverflow,
+    /// A move outside the travel envelope was dropped and the stream is on feed hold.
+    SoftLimit,
+}
+
+/// Data asked for by a command, sent before its `ok`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Feedback {
+    /// `$$`, one per setting.
+    Setting(u16, SettingValue),
+    /// `$I`, tells streaming senders the buffer sizes.
+    BuildInfo { planner_blocks: usize, rx_size: usize },
+    /// Start up and after a reset, a sender knows the RX buffer is empty from here.
+    Welcome,
+}
+
+/// Everything the firmware says to the host, `write_response` turns it into grbl's text protocol.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Response {
+    Ok,
+    Error(ErrorCode),
+    Alarm(AlarmCode),
+    Message(Message),
+    /// Free RX bytes are added as `Bf:` when `$10` asks for them.
+    StatusReport(MachineStatus, Option<usize>),
+    Feedback(Feedback),
+}
+
+/// Fixed point with three decimals, `um` micrometers print as millimeters.
+fn write_um<W: uWrite + ?Sized>(w: &mut W, um: i32) -> Result<(), W::Error> {
+    let sign = if um < 0 { "-" } else { "" };
+    let um = um.unsigned_abs();
+    let frac = um % 1000;
+    let pad = if frac < 10 { "00" } else if frac < 100 { "0" } else { "" };
+    uwrite!(w, "{}{}.{}{}", sign, um / 1000, pad, frac)
+}
+
+/// e.g. `<Idle|MPos:1.000,0.000,-2.500|A:F|Bf:2,128>`.
+fn write_status<W: uWrite + ?Sized>(w: &mut W, status: &MachineStatus, rx_free: Option<usize>) -> Result<(), W::Error> {
+    let state = match status.state {
+        MachineState::Idle => "Idle",
+        MachineState::Run => "Run",
+        MachineState::Home => "Home",
+        MachineState::Hold => "Hold",
+        MachineState::Alarm => "Alarm",
+    };
+    uwrite!(w, "<{}|MPos:", state)?;
+    write_um(w, status.position_um.x)?;
+    w.write_str(",")?;
+    write_um(w, status.position_um.y)?;
+    w.write_str(",")?;
+    write_um(w, status.position_um.z)?;
+    if !status.coolant.is_off() {
+        w.write_str("|A:")?;
+        if status.coolant.flood {
+            w.write_str("F")?;
+        }
+        if status.coolant.mist {
+            w.write_str("M")?;
+        }
+    }
+    if let Some(rx_free) = rx_free {
+        uwrite!(w, "|Bf:{},{}", status.planner_free, rx_free)?;
+    }
+    w.write_str(">")
+}
+
+fn write_message<W: uWrite + ?Sized>(w: &mut W, message: &Message) -> Result<(), W::Error> {
+    match message {
+        Message::Text(text) => {
+            uwrite!(w, "[MSG:{}", *text)?;
+        },
+        Message::Limit(limit) => {
+            let axis = match limit.axis {
+                XYZId::X => "X",
+                XYZId::Y => "Y",
+                XYZId::Z => "Z",
+            };
+            let side = match limit.side {
+                LimitSide::Min => "min",
+                LimitSide::Max => "max",
+            };
+            uwrite!(w, "[MSG:Limit {} {}, position lost, home with $H", axis, side)?;
+        },
+        // Not tied to a line, anything starting `error` a counting sender would take as one line's response.
+        Message::RxOverflow => w.write_str("[MSG:rx overflow")?,
+        Message::SoftLimit => w.write_str("[MSG:Soft limit, move dropped, ~ to resume")?,
+    }
+    w.write_str("]")
+}
+
+fn write_feedback<W: uWrite + ?Sized>(w: &mut W, feedback: &Feedback) -> Result<(), W::Error> {
+    match feedback {
+        Feedback::Setting(id, value) => {
+            uwrite!(w, "${}=", *id)?;
+            match value {
+                SettingValue::Integer(v) => uwrite!(w, "{}", *v),
+                SettingValue::Float(v) => write_um(w, (v * 1000.0) as i32),
+            }
+        },
+        Feedback::BuildInfo { planner_blocks, rx_size } => {
+            uwrite!(w, "[VER:cnc_driver]\r\n[OPT:,{},{}]", *planner_blocks, *rx_size)
+        },
+        Feedback::Welcome => w.write_str("\r\ncnc_driver ['$' for help]"),
+    }
+}
+
+/// One response as grbl style text, including the line ending.
+pub fn write_response<W: uWrite + ?Sized>(w: &mut W, response: &Response) -> Result<(), W::Error> {
+    match response {
+        Response::Ok => w.write_str("ok")?,
+        Response::Error(code) => uwrite!(w, "error:{}", code.code())?,
+        Response::Alarm(code) => uwrite!(w, "ALARM:{}", code.code())?,
+        Response::Message(message) => write_message(w, message)?,
+        Response::StatusReport(status, rx_free) => write_status(w, status, *rx_free)?,
+        Response::Feedback(feedback) => write_feedback(w, feedback)?,
+    }
+    w.write_str("\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::string::String;
+    use crate::{CoolantState, XYZData};
+    use super::*;
+
+    struct Text(String);
+
+    impl uWrite for Text {
+        type Error = ();
+
+        fn write_str(&mut self, s: &str) -> Result<(), ()> {
+            self.0.push_str(s);
+            Ok(())
+        }
+    }
+
+    fn text(response: Response) -> String {
+        let mut w = Text(String::new());
+        write_response(&mut w, &response).unwrap();
+        w.0
+    }
+
+    fn status(state: MachineState, position_um: XYZData<i32>, coolant: CoolantState) -> MachineStatus {
+        MachineStatus { state, position_um, coolant, alarm: None, planner_free: 1 }
+    }
+
+    #[test]
+    fn response_codes() {
+        assert_eq!(text(Response::Ok), "ok\r\n");
+        assert_eq!(text(Response::Error(ErrorCode::LineOverflow)), "error:11\r\n");
+        assert_eq!(text(Response::Alarm(AlarmCode::HardLimit)), "ALARM:1\r\n");
+    }
+
+    #[test]
+    fn response_messages() {
+        assert_eq!(text(Response::Message(Message::Text("hi"))), "[MSG:hi]\r\n");
+        let limit = LimitSwitch { axis: XYZId::Y, side: LimitSide::Max };
+        assert_eq!(text(Response::Message(Message::Limit(limit))), "[MSG:Limit Y max, position lost, home with $H]\r\n");
+        assert_eq!(text(Response::Message(Message::RxOverflow)), "[MSG:rx overflow]\r\n");
+        assert_eq!(text(Response::Message(Message::SoftLimit)), "[MSG:Soft limit, move dropped, ~ to resume]\r\n");
+    }
+
+    #[test]
+    fn response_status_report() {
+        let idle = status(MachineState::Idle, XYZData { x: 1000, y: 0, z: -2500 }, CoolantState::OFF);
+        assert_eq!(text(Response::StatusReport(idle, None)), "<Idle|MPos:1.000,0.000,-2.500>\r\n");
+        let run = status(MachineState::Run, XYZData { x: 5, y: -60, z: 123456 }, CoolantState { mist: true, flood: true });
+        assert_eq!(text(Response::StatusReport(run, Some(128))), "<Run|MPos:0.005,-0.060,123.456|A:FM|Bf:1,128>\r\n");
+    }
+
+    #[test]
+    fn response_feedback() {
+        assert_eq!(text(Response::Feedback(Feedback::Setting(3, SettingValue::Integer(5)))), "$3=5\r\n");
+        assert_eq!(text(Response::Feedback(Feedback::Setting(100, SettingValue::Float(80.5)))), "$100=80.500\r\n");
+        let info = Feedback::BuildInfo { planner_blocks: 2, rx_size: 128 };
+        assert_eq!(text(Response::Feedback(info)), "[VER:cnc_driver]\r\n[OPT:,2,128]\r\n");
+        assert_eq!(text(Response::Feedback(Feedback::Welcome)), "\r\ncnc_driver ['$' for help]\r\n");
+    }
+}
@@ -428,4 +428,30 @@ mod test {
             assert_eq!(parsed.command_id.minor, 0);
         }
     }
+
+    #[test]
+    fn test_m66_wait_arguments() {
+        let source = "M66 P1 L3 Q2.5\n";
+        let parsed = parse(source);
+        assert!(matches!(parsed, Ok(ParseUnion::GCodeCommand(_))));
+        if let Ok(ParseUnion::GCodeCommand(parsed)) = parsed {
+            assert!(parsed.command_id.mnumonic == CommandMnumonics::M);
+            assert_eq!(parsed.command_id.major, 66);
+            assert_eq!(parsed.argument(ArgumentMnumonic::P).map(|v| v.major), Some(1));
+            assert_eq!(parsed.argument(ArgumentMnumonic::L).map(|v| v.major), Some(3));
+            assert_eq!(parsed.argument(ArgumentMnumonic::Q).map(|v| v.float), Some(2.5));
+        }
+    }
+
+    #[test]
+    fn test_m67_analog_arguments() {
+        let source = "M67 E0 Q128\n";
+        let parsed = parse(source);
+        assert!(matches!(parsed, Ok(ParseUnion::GCodeCommand(_))));
+        if let Ok(ParseUnion::GCodeCommand(parsed)) = parsed {
+            assert_eq!(parsed.command_id.major, 67);
+            assert_eq!(parsed.argument(ArgumentMnumonic::E).map(|v| v.major), Some(0));
+            assert_eq!(parsed.argument(ArgumentMnumonic::Q).map(|v| v.float), Some(128.0));
+        }
+    }
 }
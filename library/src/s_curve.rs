@@ -0,0 +1,205 @@
+#[allow(unused)]
+use micromath::F32Ext;
+
+use crate::{AccProfile, AccTable};
+
+/// Third order motion from standstill to `max_speed`: acceleration ramps up at the jerk limit, holds at
+/// `peak_acc`, then ramps back down so the move arrives at speed without a step in acceleration.
+/// Units are steps and seconds.
+#[derive(Clone, Copy, Debug)]
+struct SCurve {
+    jerk: f32,
+    peak_acc: f32,
+    max_speed: f32,
+    /// Length of each jerk phase.
+    jerk_time: f32,
+    /// Length of the constant acceleration phase, 0 when the speed is reached before `acc` is.
+    acc_time: f32,
+}
+
+impl SCurve {
+    fn new(acc: f32, jerk: f32, max_speed: f32) -> Self {
+        if max_speed * jerk >= acc * acc {
+            Self { jerk, peak_acc: acc, max_speed, jerk_time: acc / jerk, acc_time: max_speed / acc - acc / jerk }
+        }
+        else {
+            let jerk_time = (max_speed / jerk).sqrt();
+            Self { jerk, peak_acc: jerk * jerk_time, max_speed, jerk_time, acc_time: 0.0 }
+        }
+    }
+
+    /// Position and velocity `t` seconds into the ramp.
+    fn state(&self, t: f32) -> (f32, f32) {
+        let (j, a) = (self.jerk, self.peak_acc);
+        let t1 = self.jerk_time;
+        let t2 = t1 + self.acc_time;
+        let (s1, v1) = (j * t1 * t1 * t1 / 6.0, j * t1 * t1 / 2.0);
+        let (s2, v2) = (s1 + v1 * self.acc_time + a * self.acc_time * self.acc_time / 2.0, v1 + a * self.acc_time);
+        if t <= t1 {
+            (j * t * t * t / 6.0, j * t * t / 2.0)
+        }
+        else if t <= t2 {
+            let t = t - t1;
+            (s1 + v1 * t + a * t * t / 2.0, v1 + a * t)
+        }
+        else if t <= t2 + t1 {
+            let t = t - t2;
+            (s2 + v2 * t + a * t * t / 2.0 - j * t * t * t / 6.0, v2 + a * t - j * t * t / 2.0)
+        }
+        else {
+            let (s3, _) = self.state(t2 + t1);
+            (s3 + self.max_speed * (t - t2 - t1), self.max_speed)
+        }
+    }
+
+    /// Time the ramp passes `position`, Newton's method from a guess past it. Position is convex in time
+    /// while accelerating so the guess walks down onto the answer without overshooting.
+    fn time_at(&self, position: f32, guess: f32) -> f32 {
+        let mut t = guess;
+        for _ in 0..6 {
+            let (s, v) = self.state(t);
+            if v <= 0.0 || s - position < 1e-4 {
+                break;
+            }
+            t -= (s - position) / v;
+        }
+        t
+    }
+}
+
+/// Jerk limited ramp. The delays come out of `SCurve` when it's built and are kept in a table like
+/// `AccTable`, so it has the same length limit.
+#[derive(Clone, Debug)]
+pub struct SCurveRamp {
+    delays: AccTable,
+}
+
+impl SCurveRamp {
+    /// `acc` in steps/s^2 and `jerk` in steps/s^3, up to the speed of one step every `slew_delay_us`.
+    pub fn new(acc: u32, jerk: u32, slew_delay_us: u32) -> Self {
+        let curve = SCurve::new(acc.max(1) as f32, jerk.max(1) as f32, 1_000_000.0 / slew_delay_us.max(1) as f32);
+        let mut delays = AccTable::new();
+        // Standing start, the first step is in the first jerk phase.
+        let mut time = (6.0 / curve.jerk).powf(1.0 / 3.0);
+        // Whole microseconds since the start, rounding each step time instead of each delay keeps the
+        // rounding from adding up.
+        let mut last_us = 0;
+        while !delays.is_full() {
+            let time_us = (time * 1_000_000.0 + 0.5) as u32;
+            let delay = time_us - last_us;
+            if delay <= slew_delay_us && !delays.is_empty() {
+                break;
+            }
+            delays.push(delay);
+            last_us = time_us;
+            let (_, speed) = curve.state(time);
+            time = curve.time_at((delays.len() + 1) as f32, time + 1.0 / speed);
+        }
+        Self { delays }
+    }
+
+    pub fn delays(&self) -> &[u32] { &self.delays }
+}
+
+impl AccProfile for SCurveRamp {
+    fn build(acc: u32, jerk: u32, slew_delay_us: u32) -> Self { Self::new(acc, jerk, slew_delay_us) }
+    fn len(&self) -> usize { self.delays.len() }
+    fn delay(&mut self, index: usize) -> u32 { self.delays[index] }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::vec::Vec;
+    use crate::{build_acc_table, StepIterator};
+    use super::*;
+
+    const ACC: u32 = 600 * 80;
+    const JERK: u32 = 6000 * 80;
+    const SLEW: u32 = 1_000_000 / (80 * 15);
+
+    /// Speed, acceleration and jerk of a delay sequence. Speeds are averaged over `STRIDE` steps so the
+    /// whole microsecond rounding of each delay doesn't swamp the differences.
+    fn derivatives(delays: &[u32]) -> (Vec<f32>, Vec<f32>, Vec<f32>) {
+        const STRIDE: usize = 16;
+        let mut step_times = Vec::new();
+        let mut time = 0.0;
+        for &d in delays {
+            step_times.push(time);
+            time += d as f32 / 1_000_000.0;
+        }
+        step_times.push(time);
+        let samples: Vec<f32> = step_times.iter().step_by(STRIDE).copied().collect();
+        let speeds: Vec<f32> = samples.windows(2).map(|t| STRIDE as f32 / (t[1] - t[0])).collect();
+        let mid_times: Vec<f32> = samples.windows(2).map(|t| (t[0] + t[1]) / 2.0).collect();
+        let diff = |values: &[f32], times: &[f32]| -> (Vec<f32>, Vec<f32>) {
+            let rates = values.windows(2).zip(times.windows(2)).map(|(v, t)| (v[1] - v[0]) / (t[1] - t[0])).collect();
+            let mids = times.windows(2).map(|t| (t[0] + t[1]) / 2.0).collect();
+            (rates, mids)
+        };
+        let (accs, acc_times) = diff(&speeds, &mid_times);
+        let (jerks, _) = diff(&accs, &acc_times);
+        (speeds, accs, jerks)
+    }
+
+    fn max_abs(values: &[f32]) -> f32 { values.iter().fold(0.0, |m, v| m.max(v.abs())) }
+
+    fn run_move(ramp: SCurveRamp, target: i32, slew: u32) -> Vec<u32> {
+        let mut iter = StepIterator::with_profile(ramp);
+        iter.set_target(target, slew, 0);
+        iter.collect()
+    }
+
+    #[test]
+    fn s_curve_within_bounds() {
+        for (acc, jerk) in [(ACC, JERK), (ACC, JERK * 10), (ACC / 4, JERK / 4)] {
+            let delays = run_move(SCurveRamp::new(acc, jerk, SLEW), 2000, SLEW);
+            let (speeds, accs, jerks) = derivatives(&delays);
+            assert!(max_abs(&speeds) <= 1_000_000.0 / SLEW as f32 * 1.01);
+            assert!(max_abs(&accs) <= acc as f32 * 1.05, "acc {} > {}", max_abs(&accs), acc);
+            assert!(max_abs(&jerks) <= jerk as f32 * 1.10, "jerk {} > {}", max_abs(&jerks), jerk);
+        }
+    }
+
+    #[test]
+    fn s_curve_reaches_speed() {
+        // Low enough acceleration for a constant acceleration phase.
+        let ramp = SCurveRamp::new(ACC / 4, JERK, SLEW);
+        assert!(ramp.len() < crate::ACC_TABLE_CAPACITY);
+        assert!(ramp.delays().windows(2).all(|w| w[1] <= w[0]), "Delays only shrink while accelerating.");
+        let delays = run_move(ramp.clone(), 2000, SLEW);
+        assert_eq!(delays.len(), 2000);
+        let cruise = &delays[ramp.len()..2000 - ramp.len()];
+        assert!(cruise.iter().all(|&d| d == cruise[0] && d <= SLEW + SLEW / 100), "Cruises at the slew speed.");
+        let (_, accs, _) = derivatives(&delays);
+        assert!(max_abs(&accs) > ACC as f32 / 4.0 * 0.9, "Reaches the acceleration limit.");
+    }
+
+    #[test]
+    fn s_curve_short_ramp() {
+        // Low max speed, the acceleration limit is never reached.
+        let slow = SLEW * 8;
+        let delays = run_move(SCurveRamp::new(ACC, JERK, slow), 500, slow);
+        let (speeds, accs, jerks) = derivatives(&delays);
+        assert!(max_abs(&speeds) <= 1_000_000.0 / slow as f32 * 1.01);
+        assert!(max_abs(&accs) < ACC as f32 * 0.5);
+        assert!(max_abs(&jerks) <= JERK as f32 * 1.10);
+    }
+
+    #[test]
+    fn s_curve_smoother_than_trapezoid() {
+        let trapezoid = {
+            let mut iter = StepIterator::new(&build_acc_table(ACC, SLEW));
+            iter.set_target(2000, SLEW, 0);
+            iter.collect::<Vec<_>>()
+        };
+        let (_, trapezoid_accs, trapezoid_jerks) = derivatives(&trapezoid);
+        // Same peak acceleration, reached over a tenth of a second.
+        let acc = max_abs(&trapezoid_accs) as u32;
+        let jerk = acc * 10;
+        let (_, accs, jerks) = derivatives(&run_move(SCurveRamp::new(acc, jerk, SLEW), 2000, SLEW));
+        assert!(max_abs(&accs) <= acc as f32 * 1.05);
+        assert!(max_abs(&jerks) <= jerk as f32 * 1.10, "jerk {} > {}", max_abs(&jerks), jerk);
+        assert!(max_abs(&trapezoid_jerks) > jerk as f32 * 2.0, "The trapezoid's acceleration jumps at the corners.");
+    }
+}
@@ -0,0 +1,666 @@
+use crate::{time_reached, CanRecieve, Gang, LimitInputs, LimitSide, LimitSwitch, SpscQueue, StepDir, StepSignal, StepTimer, XYZData, XYZId, XYZ_ID_LIST};
+
+/// Length of every segment. Short enough that the steps inside one are close to evenly spaced, long
+/// enough that the main loop only has to keep a few of them queued.
+pub const SEGMENT_US: u32 = 4000;
+
+/// One axis for the length of a segment. The first and last step land where the planner put them, the
+/// ones between are spread evenly, `extra` of them one microsecond later to use up the remainder.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AxisSegment {
+    pub steps: u8,
+    /// Direction pin level for every step in the segment.
+    pub negative: bool,
+    /// Rising edge of the first step, us from the start of the segment.
+    pub first_us: u16,
+    pub period_us: u16,
+    pub extra: u8,
+}
+
+impl AxisSegment {
+    pub const EMPTY: Self = Self { steps: 0, negative: false, first_us: 0, period_us: 0, extra: 0 };
+
+    /// Rising edges between `first_us` and `last_us`.
+    fn spread(steps: u8, negative: bool, first_us: u32, last_us: u32) -> Self {
+        let (period_us, extra) = match steps {
+            0 | 1 => (0, 0),
+            _ => ((last_us - first_us) / (steps - 1) as u32, ((last_us - first_us) % (steps - 1) as u32) as u8),
+        };
+        Self { steps, negative, first_us: first_us as u16, period_us: period_us as u16, extra }
+    }
+
+    /// Delay from step `index` to the next one.
+    pub fn period_after(&self, index: u8) -> u32 {
+        self.period_us as u32 + (index < self.extra) as u32
+    }
+
+    /// Rising edge of step `index`, us from the start of the segment.
+    pub fn step_time(&self, index: u8) -> u32 {
+        (0..index).map(|i| self.period_after(i)).sum::<u32>() + self.first_us as u32
+    }
+}
+
+impl Default for AxisSegment {
+    fn default() -> Self { Self::EMPTY }
+}
+
+/// `SEGMENT_US` of motion.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Segment {
+    pub axes: XYZData<AxisSegment>,
+}
+
+impl Segment {
+    pub const EMPTY: Self = Self { axes: XYZData { x: AxisSegment::EMPTY, y: AxisSegment::EMPTY, z: AxisSegment::EMPTY } };
+
+    /// Steps in the segment with their direction.
+    pub fn moves(&self) -> XYZData<i32> {
+        self.axes.map(|a| if a.negative { -(a.steps as i32) } else { a.steps as i32 })
+    }
+}
+
+/// Queue of segments between the main loop and the step interrupt. `split` it once, the main loop keeps the
+/// producer and the interrupt the consumer.
+pub type SegmentRing<const N: usize> = SpscQueue<Segment, N>;
+
+/// Where `Machine::prep_segments` puts its segments, the main loop's view of the step interrupt.
+pub trait SegmentOutput {
+    /// Only called when not full.
+    fn push(&mut self, segment: Segment);
+    fn is_full(&self) -> bool;
+    /// Segments queued or steps still going out.
+    fn is_busy(&self) -> bool;
+    /// Drop everything not stepped yet. Returns the steps thrown away, signed by direction.
+    fn abort(&mut self) -> XYZData<i32>;
+    /// New pulse timing, step pin polarity and direction pin polarity, only while not busy.
+    fn configure(&mut self, signal: StepSignal, step_invert: XYZData<bool>, dir_invert: XYZData<bool>);
+    /// Switches to stop at from the next step on.
+    fn watch(&mut self, watch: LimitWatch);
+    /// The switch the step interrupt stopped at, until `abort`.
+    fn tripped(&self) -> Option<LimitSwitch>;
+}
+
+/// Switches the step interrupt checks before every step.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum LimitWatch {
+    #[default]
+    Off,
+    /// Hard limits, the switch on the side each axis is stepping toward.
+    Hard,
+    /// Homing `axis` toward its `side` switch. Each motor of a ganged axis is parked at its own switch, the
+    /// axis stops once they all are.
+    Homing { axis: XYZId, side: LimitSide },
+}
+
+/// Foreground half of step generation. Pulls step delays from the planner and cuts them into
+/// `SEGMENT_US` segments on one shared timeline, so all the ramp maths stays out of the interrupt.
+#[derive(Clone, Default, Debug)]
+pub struct SegmentPrep {
+    /// Start of the next segment, us.
+    time: u64,
+    /// Last rising edge per axis. `None` once the axis runs out, its next move starts in the segment that
+    /// picks it up.
+    last_step: XYZData<Option<u64>>,
+    /// A step already taken from the planner that falls in a later segment.
+    held: XYZData<Option<(u64, bool)>>,
+    /// Direction pin level of the last segment with steps per axis, `None` when it's not known.
+    last_negative: XYZData<Option<bool>>,
+    signal: StepSignal,
+}
+
+impl SegmentPrep {
+    pub fn new() -> Self { Default::default() }
+
+    pub fn set_signal(&mut self, signal: StepSignal) { self.signal = signal; }
+
+    /// Closest two rising edges on one axis can be, the step pin is active then idle for a pulse each.
+    fn min_step_spacing(&self) -> u32 { self.signal.pulse_us * 2 }
+
+    /// No steps held back for a later segment.
+    pub fn is_idle(&self) -> bool { self.held.all(|h| h.is_none()) }
+
+    /// Forget the steps held back, e.g. when motion is aborted. Returns them signed by direction.
+    pub fn reset(&mut self) -> XYZData<i32> {
+        let dropped = self.held.map(|h| match h {
+            Some((_, negative)) => if *negative { -1 } else { 1 },
+            None => 0,
+        });
+        self.held = Default::default();
+        self.last_step = Default::default();
+        // Whatever the executor had loaded is gone too.
+        self.last_negative = Default::default();
+        dropped
+    }
+
+    /// The next segment, `None` once every axis is out of steps. `next_step` gives the delay to an axis'
+    /// next step and its direction pin level, like `Stepper::next_step`.
+    pub fn prep(&mut self, mut next_step: impl FnMut(XYZId) -> Option<(u32, bool)>) -> Option<Segment> {
+        let end = self.time + SEGMENT_US as u64;
+        let min_spacing = self.min_step_spacing();
+        // Less than a segment, or a reversal would never fit in one.
+        let dir_setup = self.signal.dir_setup_us.min(SEGMENT_US / 2) as u64;
+        let mut segment = Segment::EMPTY;
+        for axis in XYZ_ID_LIST {
+            let (mut steps, mut negative, mut first, mut last) = (0u8, false, 0, 0);
+            // A full count waits for the next segment, the axis runs slower than asked instead of losing steps.
+            while steps < u8::MAX {
+                let held = self.held.match_id_mut(axis);
+                if held.is_none() {
+                    let Some((delay, step_negative)) = next_step(axis) else {
+                        *self.last_step.match_id_mut(axis) = None;
+                        break;
+                    };
+                    let from = self.last_step.match_id(axis).unwrap_or(self.time);
+                    *held = Some((from + delay.max(min_spacing) as u64, step_negative));
+                }
+                let Some((time, step_negative)) = *held else { break };
+                // The executor writes the direction pin at the start of the segment.
+                let reversal = steps == 0 && *self.last_negative.match_id(axis) != Some(step_negative);
+                let time = time.max(self.time + if reversal { dir_setup } else { 0 });
+                // A reversal waits for the next segment, each one only has a single direction per axis.
+                if time >= end || (steps != 0 && step_negative != negative) {
+                    break;
+                }
+                *held = None;
+                *self.last_step.match_id_mut(axis) = Some(time);
+                if steps == 0 {
+                    (negative, first) = (step_negative, time);
+                    *self.last_negative.match_id_mut(axis) = Some(step_negative);
+                }
+                last = time;
+                steps += 1;
+            }
+            if steps != 0 {
+                *segment.axes.match_id_mut(axis) = AxisSegment::spread(steps, negative, (first - self.time) as u32, (last - self.time) as u32);
+            }
+        }
+        if segment.axes.all(|a| a.steps == 0) && self.is_idle() {
+            return None;
+        }
+        self.time = end;
+        Some(segment)
+    }
+}
+
+/// Interrupt half of step generation. Plays back segments from the ring, only adding up delays and
+/// toggling pins. Stops dead at a watched switch, so a limit is never more than one step late.
+pub struct SegmentExecutor<SD: StepDir, L: LimitInputs> {
+    step_dir_fn: SD,
+    limits: L,
+    segment: Option<Segment>,
+    /// When the current segment started.
+    start: u32,
+    /// Per axis, the next step of the segment and its rising edge from `start`.
+    next_step: XYZData<(u8, u32)>,
+    /// Falling edge due, the step pin is high until then.
+    fall_time: XYZData<Option<u32>>,
+    pulse_us: u32,
+    step_invert: XYZData<bool>,
+    /// Direction pin level that moves each axis toward its max.
+    dir_invert: XYZData<bool>,
+    watch: LimitWatch,
+    /// Motors of the homing axis parked at their switch.
+    gang: Gang,
+    /// Stopped at this switch, nothing more steps until `abort`.
+    tripped: Option<LimitSwitch>,
+    /// Steps thrown away when it tripped, handed back by `abort`.
+    dropped: XYZData<i32>,
+}
+
+impl<SD: StepDir, L: LimitInputs> SegmentExecutor<SD, L> {
+    pub fn new(step_dir_fn: SD, limits: L) -> Self {
+        Self {
+            step_dir_fn,
+            limits,
+            segment: None,
+            start: 0,
+            next_step: Default::default(),
+            fall_time: Default::default(),
+            pulse_us: StepSignal::default().pulse_us,
+            step_invert: Default::default(),
+            dir_invert: Default::default(),
+            watch: LimitWatch::Off,
+            gang: Gang::default(),
+            tripped: None,
+            dropped: Default::default(),
+        }
+    }
+
+    /// Only while not busy, the step pins go to their new idle level.
+    pub fn configure(&mut self, signal: StepSignal, step_invert: XYZData<bool>, dir_invert: XYZData<bool>) {
+        self.pulse_us = signal.pulse_us;
+        self.step_invert = step_invert;
+        self.dir_invert = dir_invert;
+        for axis in XYZ_ID_LIST {
+            self.write_step(axis, false);
+        }
+    }
+
+    /// A new homing pass starts with none of its motors parked.
+    pub fn watch(&mut self, watch: LimitWatch) {
+        self.watch = watch;
+        self.gang = Gang::default();
+    }
+
+    pub fn tripped(&self) -> Option<LimitSwitch> { self.tripped }
+
+    /// The switch in the way of the next step on `axis`, parking the motors of a ganged homing axis that
+    /// have reached theirs.
+    fn blocked(&mut self, axis: XYZId, negative: bool) -> Option<LimitSwitch> {
+        let side = if negative != *self.dir_invert.match_id(axis) { LimitSide::Min } else { LimitSide::Max };
+        let stop = match self.watch {
+            LimitWatch::Off => false,
+            LimitWatch::Hard => self.limits.triggered(axis, side),
+            LimitWatch::Homing { axis: homing, side: toward } if homing == axis && toward == side => {
+                let motors = self.limits.motors(axis);
+                if motors == 1 {
+                    self.limits.triggered(axis, side)
+                }
+                else {
+                    for motor in 0..motors {
+                        if !self.gang.is_locked(motor) && self.limits.motor_triggered(axis, motor, side) {
+                            self.gang.lock(motor);
+                            self.step_dir_fn.lock_motor(axis, motor, Some(*self.step_invert.match_id(axis)));
+                        }
+                    }
+                    self.gang.all_locked(motors)
+                }
+            },
+            LimitWatch::Homing { .. } => false,
+        };
+        stop.then_some(LimitSwitch { axis, side })
+    }
+
+    fn write_step(&mut self, axis: XYZId, high: bool) {
+        let invert = *self.step_invert.match_id(axis);
+        self.step_dir_fn.step(axis, high != invert);
+    }
+
+    /// A segment is running or a step pin is still high.
+    pub fn is_busy(&self) -> bool { self.segment.is_some() || !self.fall_time.all(|f| f.is_none()) }
+
+    /// Direction pins go out at the start of a segment, the previous step on the axis was a full step
+    /// delay ago at least.
+    fn load(&mut self, segment: Option<Segment>, start: u32) {
+        self.segment = segment;
+        self.start = start;
+        if let Some(segment) = segment {
+            for axis in XYZ_ID_LIST {
+                let a = segment.axes.match_id(axis);
+                if a.steps != 0 {
+                    self.step_dir_fn.dir(axis, a.negative);
+                }
+                *self.next_step.match_id_mut(axis) = (0, a.first_us as u32);
+            }
+        }
+    }
+
+    fn segment_end(&self) -> u32 { self.start.wrapping_add(SEGMENT_US) }
+
+    /// Called from the timer interrupt, `now` being the time of the event handled. When stopped, calling it
+    /// starts the next segment at `now`.
+    pub fn step_interrupt(&mut self, now: u32, ring: &impl CanRecieve<Segment>, timer: &mut impl StepTimer) {
+        if self.segment.is_none() && self.tripped.is_none() {
+            self.load(ring.recieve(), now);
+        }
+        loop {
+            for axis in XYZ_ID_LIST {
+                let fall = self.fall_time.match_id_mut(axis);
+                if fall.is_some_and(|f| time_reached(now, f)) {
+                    *fall = None;
+                    self.write_step(axis, false);
+                }
+            }
+            let Some(segment) = self.segment else { break };
+            for axis in XYZ_ID_LIST {
+                let a = segment.axes.match_id(axis);
+                let (index, time) = *self.next_step.match_id(axis);
+                if index < a.steps && time_reached(now, self.start.wrapping_add(time)) && self.fall_time.match_id(axis).is_none() {
+                    if let Some(switch) = self.blocked(axis, a.negative) {
+                        self.tripped = Some(switch);
+                        self.dropped = self.take_queued(ring);
+                        break;
+                    }
+                    self.write_step(axis, true);
+                    *self.fall_time.match_id_mut(axis) = Some(now.wrapping_add(self.pulse_us));
+                    *self.next_step.match_id_mut(axis) = (index + 1, time + a.period_after(index));
+                }
+            }
+            if self.tripped.is_some() || !time_reached(now, self.segment_end()) {
+                break;
+            }
+            self.load(ring.recieve(), self.segment_end());
+        }
+        let rises = self.segment.iter().flat_map(|segment| {
+            XYZ_ID_LIST.into_iter()
+                .filter(|&axis| self.next_step.match_id(axis).0 < segment.axes.match_id(axis).steps)
+                .map(|axis| self.start.wrapping_add(self.next_step.match_id(axis).1))
+                .chain(Some(self.segment_end()))
+        });
+        let next = self.fall_time.iter().flatten().copied().chain(rises).map(|t| t.wrapping_sub(now) as i32).min();
+        match next {
+            Some(delay) => timer.schedule(delay.max(1) as u32),
+            None => timer.stop(),
+        }
+    }
+
+    /// Stop after the current pulses. Drops the rest of the segment and everything queued, returns the steps
+    /// thrown away signed by direction. Also what gets it going again after it tripped.
+    pub fn abort(&mut self, ring: &impl CanRecieve<Segment>) -> XYZData<i32> {
+        self.tripped = None;
+        self.gang = Gang::default();
+        core::mem::take(&mut self.dropped) + self.take_queued(ring)
+    }
+
+    fn take_queued(&mut self, ring: &impl CanRecieve<Segment>) -> XYZData<i32> {
+        let mut dropped = XYZData::<i32>::default();
+        if let Some(segment) = self.segment.take() {
+            for axis in XYZ_ID_LIST {
+                let a = segment.axes.match_id(axis);
+                let left = (a.steps - self.next_step.match_id(axis).0) as i32;
+                *dropped.match_id_mut(axis) += if a.negative { -left } else { left };
+            }
+        }
+        while let Some(segment) = ring.recieve() {
+            dropped = dropped + segment.moves();
+        }
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use core::cell::RefCell;
+    use std::{boxed::Box, rc::Rc, vec::Vec};
+    use crate::{CanSend, Consumer, Producer, StepIterator, ACC_CURVE, SIGNAL_LENGTH};
+    use super::*;
+
+    const SLEW: u32 = 1_000_000 / (80 * 15);
+
+    #[test]
+    fn axis_segment_spread() {
+        let a = AxisSegment::spread(4, false, 100, 3105);
+        let times: Vec<u32> = (0..4).map(|i| a.step_time(i)).collect();
+        assert_eq!(times, [100, 1102, 2104, 3105], "First and last exact, remainder on the first gaps.");
+        assert_eq!(AxisSegment::spread(1, true, 10, 10).step_time(0), 10);
+    }
+
+    /// Rising edges and their time, as the driver would put them out.
+    #[derive(Default, Debug)]
+    struct EdgeLog {
+        now: u32,
+        high: XYZData<bool>,
+        negative: XYZData<bool>,
+        rises: XYZData<Vec<(u32, bool)>>,
+    }
+    #[derive(Default, Clone, Debug)]
+    struct LogStepper(Rc<RefCell<EdgeLog>>);
+    impl StepDir for LogStepper {
+        fn step(&mut self, axis: XYZId, high: bool) {
+            let mut log = self.0.borrow_mut();
+            let rising = high && !*log.high.match_id(axis);
+            *log.high.match_id_mut(axis) = high;
+            if rising {
+                let rise = (log.now, *log.negative.match_id(axis));
+                log.rises.match_id_mut(axis).push(rise);
+            }
+        }
+        fn dir(&mut self, axis: XYZId, negative: bool) { *self.0.borrow_mut().negative.match_id_mut(axis) = negative; }
+        fn enable(&mut self, _: XYZId, _: bool) {}
+    }
+
+    /// X max switch, pressed from the given number of X steps on.
+    #[derive(Clone, Default, Debug)]
+    struct Switches {
+        log: LogStepper,
+        x_max: Option<usize>,
+    }
+    impl LimitInputs for Switches {
+        fn triggered(&mut self, axis: XYZId, side: LimitSide) -> bool {
+            axis == XYZId::X && side == LimitSide::Max && self.x_max.is_some_and(|at| self.log.0.borrow().rises.x.len() >= at)
+        }
+    }
+
+    struct TestTimer(Option<u32>);
+    impl StepTimer for TestTimer {
+        fn schedule(&mut self, delay_us: u32) { self.0 = Some(delay_us); }
+        fn stop(&mut self) { self.0 = None; }
+    }
+
+    fn iterators(targets: XYZData<i32>) -> XYZData<StepIterator> {
+        targets.map(|&target| {
+            let mut iter = StepIterator::new(&ACC_CURVE);
+            iter.set_target(target, SLEW, 0);
+            iter
+        })
+    }
+
+    fn next_step(iter: &mut StepIterator) -> Option<(u32, bool)> {
+        let delay = iter.next()?;
+        Some((delay, iter.direction < 0))
+    }
+
+    /// Rising edges straight from the iterators, the first delay counted from 0.
+    fn reference(targets: XYZData<i32>) -> XYZData<Vec<(u64, bool)>> {
+        let mut iters = iterators(targets);
+        let mut rises = XYZData::<Vec<(u64, bool)>>::default();
+        for axis in XYZ_ID_LIST {
+            let iter = iters.match_id_mut(axis);
+            let mut time = 0;
+            while let Some((delay, negative)) = next_step(iter) {
+                time += delay as u64;
+                rises.match_id_mut(axis).push((time, negative));
+            }
+        }
+        rises
+    }
+
+    struct Run {
+        iters: XYZData<StepIterator>,
+        ring: Producer<'static, Segment, 8>,
+        source: Consumer<'static, Segment, 8>,
+        prep: SegmentPrep,
+        executor: SegmentExecutor<LogStepper, Switches>,
+        log: LogStepper,
+        timer: TestTimer,
+        start: u32,
+    }
+
+    impl Run {
+        fn new(targets: XYZData<i32>) -> Self { Self::starting_at(targets, 1000) }
+
+        fn starting_at(targets: XYZData<i32>, start: u32) -> Self {
+            let log = LogStepper::default();
+            log.0.borrow_mut().now = start;
+            let executor = SegmentExecutor::new(log.clone(), Switches { log: log.clone(), x_max: None });
+            let (ring, source) = Box::leak(Box::new(SegmentRing::new())).split();
+            Self { iters: iterators(targets), ring, source, prep: SegmentPrep::new(), executor, log, timer: TestTimer(None), start }
+        }
+
+        fn fill(&mut self) {
+            while !self.ring.is_full() {
+                match self.prep.prep(|axis| next_step(self.iters.match_id_mut(axis))) {
+                    Some(segment) => self.ring.send(segment).unwrap(),
+                    None => break,
+                }
+            }
+        }
+
+        /// Main loop tops the ring up between interrupts. Stops after `max_us` or once everything's out.
+        fn run(&mut self, max_us: u64) {
+            self.fill();
+            let now = self.log.0.borrow().now;
+            self.executor.step_interrupt(now, &self.source, &mut self.timer);
+            let mut time = 0;
+            while let Some(delay) = self.timer.0 {
+                time += delay as u64;
+                if time > max_us {
+                    break;
+                }
+                let now = self.log.0.borrow().now.wrapping_add(delay);
+                self.log.0.borrow_mut().now = now;
+                self.executor.step_interrupt(now, &self.source, &mut self.timer);
+                self.fill();
+            }
+        }
+
+        /// Relative to the start, the clock may have wrapped since.
+        fn rises(&self) -> XYZData<Vec<(u64, bool)>> {
+            self.log.0.borrow().rises.map(|r| r.iter().map(|&(t, negative)| (t.wrapping_sub(self.start) as u64, negative)).collect())
+        }
+    }
+
+    /// Largest difference between the edges and the reference, both need the same number of steps.
+    fn max_error(rises: &[(u64, bool)], reference: &[(u64, bool)]) -> u64 {
+        assert_eq!(rises.len(), reference.len());
+        rises.iter().zip(reference).map(|(&(t, n), &(r, rn))| {
+            assert_eq!(n, rn, "Same direction.");
+            t.abs_diff(r)
+        }).max().unwrap_or(0)
+    }
+
+    #[test]
+    fn segments_match_step_iterator() {
+        let targets = XYZData { x: 1500, y: 0, z: 0 };
+        let mut run = Run::new(targets);
+        run.run(u64::MAX);
+        let (rises, reference) = (run.rises(), reference(targets));
+        assert_eq!(rises.x.len(), 1500, "Every step comes out.");
+        assert_eq!(rises.x.last(), reference.x.last(), "Same length of move.");
+        // Steps are spread evenly inside a segment, so they drift by the change in speed over a segment.
+        let error = max_error(&rises.x, &reference.x);
+        assert!(error <= 20, "error {}us", error);
+        let cruise = reference.x.len() / 2;
+        assert_eq!(rises.x[cruise - 10..cruise + 10], reference.x[cruise - 10..cruise + 10], "Exact at constant speed.");
+        assert!(!run.executor.is_busy());
+    }
+
+    #[test]
+    fn segments_across_clock_wrap() {
+        let targets = XYZData { x: 700, y: -300, z: 0 };
+        let mut wrapping = Run::starting_at(targets, u32::MAX - 50_000);
+        wrapping.run(u64::MAX);
+        let mut plain = Run::new(targets);
+        plain.run(u64::MAX);
+        assert!(wrapping.log.0.borrow().now < 1_000_000, "The clock wrapped during the move.");
+        assert_eq!(wrapping.rises(), plain.rises());
+    }
+
+    #[test]
+    fn segments_axes_and_directions() {
+        let targets = XYZData { x: 700, y: -300, z: 40 };
+        let mut run = Run::new(targets);
+        run.run(u64::MAX);
+        let (rises, reference) = (run.rises(), reference(targets));
+        for axis in XYZ_ID_LIST {
+            let error = max_error(rises.match_id(axis), reference.match_id(axis));
+            assert!(error <= 20, "{:?} error {}us", axis, error);
+            assert_eq!(rises.match_id(axis).last(), reference.match_id(axis).last());
+        }
+        assert!(rises.y.iter().all(|&(_, negative)| negative));
+    }
+
+    #[test]
+    fn segments_keep_step_spacing() {
+        // Faster than the pulse allows, the steps are held two signal lengths apart.
+        let mut run = Run::new(XYZData { x: 0, y: 0, z: 0 });
+        run.iters.x = StepIterator::new(&[10]);
+        run.iters.x.set_target(200, 10, 0);
+        run.run(u64::MAX);
+        let rises = run.rises();
+        assert_eq!(rises.x.len(), 200);
+        assert!(rises.x.windows(2).all(|w| w[1].0 - w[0].0 >= SIGNAL_LENGTH as u64 * 2));
+    }
+
+    #[test]
+    fn segments_dir_setup_before_reversal() {
+        let mut prep = SegmentPrep::new();
+        prep.set_signal(StepSignal { pulse_us: 5, dir_setup_us: 100 });
+        let mut steps = [(10, false), (10, false), (10, true), (10, true)].into_iter();
+        let mut next = || prep.prep(|axis| if axis == XYZId::X { steps.next() } else { None }).unwrap().axes.x;
+        let first = next();
+        assert_eq!((first.steps, first.negative, first.first_us), (2, false, 100), "Direction unknown at the start.");
+        assert_eq!(first.step_time(1), 110);
+        let reversed = next();
+        assert_eq!((reversed.steps, reversed.negative, reversed.first_us), (2, true, 100));
+    }
+
+    #[test]
+    fn segments_step_count_fits() {
+        let mut prep = SegmentPrep::new();
+        prep.set_signal(StepSignal { pulse_us: 1, dir_setup_us: 0 });
+        let mut left = 1000;
+        let mut segments = Vec::new();
+        while let Some(segment) = prep.prep(|axis| (axis == XYZId::X && left > 0).then(|| { left -= 1; (1, false) })) {
+            segments.push(segment.axes.x);
+        }
+        assert_eq!(segments.iter().map(|a| a.steps as u32).sum::<u32>(), 1000, "No steps lost.");
+        assert_eq!(segments[0].steps, u8::MAX);
+        assert_eq!((segments[0].first_us, segments[0].step_time(254)), (2, 510), "Every 2us at $0=1.");
+        assert_eq!(segments[1].first_us, 0, "The rest goes on at the start of the next segment.");
+    }
+
+    #[test]
+    fn segments_step_invert() {
+        let mut run = Run::new(XYZData { x: 300, y: -20, z: 0 });
+        run.executor.configure(StepSignal { pulse_us: 5, dir_setup_us: 0 }, XYZData { x: true, y: false, z: false }, Default::default());
+        assert!(run.log.0.borrow().high.x, "Idle level written straight away.");
+        run.log.0.borrow_mut().rises.x.clear();
+        run.run(u64::MAX);
+        // The log sees the pin go high at the end of each pulse on X.
+        assert_eq!(run.rises().map(|r| r.len()), XYZData { x: 300, y: 20, z: 0 });
+        let log = run.log.0.borrow();
+        assert!(log.high.x && !log.high.y);
+    }
+
+    #[test]
+    fn segments_abort_accounts_for_every_step() {
+        let targets = XYZData { x: 1500, y: -800, z: 0 };
+        let mut run = Run::new(targets);
+        run.run(60_000);
+        assert!(run.executor.is_busy());
+        let dropped = run.executor.abort(&run.source) + run.prep.reset();
+        let planned = run.iters.map(|i| i.position);
+        let stepped = run.rises().map(|r| r.iter().map(|&(_, negative)| if negative { -1 } else { 1 }).sum::<i32>());
+        assert!(stepped.x > 0 && stepped.x < 1500);
+        assert_eq!(stepped + dropped, planned, "Position planned is what went out plus what was thrown away.");
+        // The pulse in flight finishes, then nothing.
+        run.iters.iter_mut().for_each(|i| i.stop());
+        run.run(u64::MAX);
+        assert_eq!(run.rises().map(|r| r.len() as i32), stepped.map(|s| s.abs()));
+        assert!(!run.executor.is_busy());
+    }
+
+    #[test]
+    fn segments_stop_at_switch() {
+        let targets = XYZData { x: 1500, y: -800, z: 0 };
+        let mut run = Run::new(targets);
+        run.executor = SegmentExecutor::new(run.log.clone(), Switches { log: run.log.clone(), x_max: Some(100) });
+        run.executor.watch(LimitWatch::Hard);
+        run.run(u64::MAX);
+        assert_eq!(run.executor.tripped(), Some(LimitSwitch { axis: XYZId::X, side: LimitSide::Max }));
+        assert_eq!(run.rises().x.len(), 100, "Not a step past the switch.");
+        assert!(run.ring.is_full(), "Nothing more taken off the ring.");
+        let dropped = run.executor.abort(&run.source) + run.prep.reset();
+        let stepped = run.rises().map(|r| r.iter().map(|&(_, negative)| if negative { -1 } else { 1 }).sum::<i32>());
+        assert_eq!(stepped + dropped, run.iters.map(|i| i.position));
+        assert_eq!(run.executor.tripped(), None);
+    }
+
+    #[test]
+    fn segments_switch_behind() {
+        // X heads for min with its max switch pressed.
+        let mut run = Run::new(XYZData { x: -200, y: 0, z: 0 });
+        run.executor = SegmentExecutor::new(run.log.clone(), Switches { log: run.log.clone(), x_max: Some(0) });
+        run.executor.watch(LimitWatch::Hard);
+        run.run(u64::MAX);
+        assert_eq!(run.rises().x.len(), 200, "Free to leave the switch.");
+        let mut run = Run::new(XYZData { x: -200, y: 0, z: 0 });
+        run.executor = SegmentExecutor::new(run.log.clone(), Switches { log: run.log.clone(), x_max: Some(0) });
+        run.executor.configure(StepSignal::default(), Default::default(), XYZData { x: true, y: false, z: false });
+        run.executor.watch(LimitWatch::Hard);
+        run.run(u64::MAX);
+        assert!(run.rises().x.is_empty(), "Inverted, the same pin level heads into it.");
+    }
+}
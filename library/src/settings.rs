@@ -1,4 +1,280 @@
+use crate::{AccProfile, ErrorCode, HomingConfig, LimitSide, StepSignal, XYZData, XYZId, SIGNAL_LENGTH, XYZ_ID_LIST};
+
+// Defaults for `Settings`.
 pub static STEPPER_SPEED: u32 = 15;
 pub static ACCELERATION: u32 = 600;
+pub static JERK: u32 = 6000;
 pub static RESOLUTION:u32 = 80; // 360/(1.8deg * 5mm lead) * 2 microstepping
 //pub static RESOLUTION:f32 = 40.0; // 360/(1.8deg * 5mm lead)
+
+/// Setting numbers, same as grbl's `$n`. Also the order `$$` lists them in.
+pub static SETTING_IDS: [u16; 34] = [0, 1, 2, 3, 10, 20, 21, 22, 23, 24, 25, 27, 29, 100, 101, 102, 110, 111, 112, 120, 121, 122, 130, 131, 132, 140, 141, 142, 150, 151, 152, 160, 161, 162];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SettingValue {
+    /// Flags and axis masks.
+    Integer(u8),
+    Float(f32),
+}
+
+impl SettingValue {
+    pub fn as_f32(&self) -> f32 {
+        match self {
+            SettingValue::Integer(v) => *v as f32,
+            SettingValue::Float(v) => *v,
+        }
+    }
+}
+
+fn to_mask(data: XYZData<bool>) -> u8 {
+    data.x as u8 | (data.y as u8) << 1 | (data.z as u8) << 2
+}
+
+fn from_mask(mask: u8) -> XYZData<bool> {
+    XYZData { x: mask & 1 != 0, y: mask & 2 != 0, z: mask & 4 != 0 }
+}
+
+/// The `$1xx` settings are grouped per axis, the last digit picks the axis.
+fn axis_setting(id: u16) -> Option<XYZId> {
+    XYZ_ID_LIST.get((id % 10) as usize).copied()
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Settings {
+    pub steps_per_mm: XYZData<f32>,
+    /// mm/min
+    pub max_rate: XYZData<f32>,
+    /// mm/s^2
+    pub acceleration: XYZData<f32>,
+    /// mm
+    pub max_travel: XYZData<f32>,
+    /// mm/s^3, only used by the `s-curve` ramp.
+    pub jerk: XYZData<f32>,
+    /// `$0` us, step pulse length.
+    pub step_pulse: u8,
+    /// `$2` mask, step pins active low.
+    pub step_invert: XYZData<bool>,
+    pub dir_invert: XYZData<bool>,
+    /// `$29` us between a direction change and the next step pulse, like grblHAL's pulse delay.
+    pub dir_setup: u8,
+    /// `$1` ms idle before the drivers are released, 255 keeps them energised.
+    pub step_idle_delay: u8,
+    /// `$10` mask, bit 1 adds `Bf:` to status reports. Positions are always machine positions.
+    pub status_report: u8,
+    pub soft_limits: bool,
+    pub hard_limits: bool,
+    pub homing_enabled: bool,
+    pub homing: HomingConfig,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            steps_per_mm: XYZData::from_clone(RESOLUTION as f32),
+            max_rate: XYZData::from_clone((STEPPER_SPEED * 60) as f32),
+            acceleration: XYZData::from_clone(ACCELERATION as f32),
+            max_travel: XYZData::from_clone(200.0),
+            jerk: XYZData::from_clone(JERK as f32),
+            step_pulse: SIGNAL_LENGTH as u8,
+            step_invert: XYZData::from_clone(false),
+            dir_invert: XYZData::from_clone(false),
+            dir_setup: 0,
+            step_idle_delay: 25,
+            status_report: 1,
+            soft_limits: true,
+            hard_limits: true,
+            homing_enabled: true,
+            homing: HomingConfig::default(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn mm_to_steps(&self, axis: XYZId, mm: f32) -> i32 { (mm * self.steps_per_mm.match_id(axis)) as i32 }
+    pub fn steps_to_mm(&self, axis: XYZId, steps: i32) -> f32 { steps as f32 / self.steps_per_mm.match_id(axis) }
+    /// Steps per second, never 0.
+    pub fn mm_per_min_to_steps(&self, axis: XYZId, rate: f32) -> u32 { ((rate * self.steps_per_mm.match_id(axis) / 60.0) as u32).max(1) }
+
+    /// Acceleration ramp for one axis, from standstill up to its max rate.
+    pub fn ramp<P: AccProfile>(&self, axis: XYZId) -> P {
+        let acc = (self.acceleration.match_id(axis) * self.steps_per_mm.match_id(axis)) as u32;
+        let jerk = (self.jerk.match_id(axis) * self.steps_per_mm.match_id(axis)) as u32;
+        let slew_delay_us = 1_000_000 / self.mm_per_min_to_steps(axis, *self.max_rate.match_id(axis));
+        P::build(acc.max(1), jerk.max(1), slew_delay_us)
+    }
+
+    pub fn step_signal(&self) -> StepSignal {
+        StepSignal { pulse_us: self.step_pulse as u32, dir_setup_us: self.dir_setup as u32 }
+    }
+
+    pub fn report_buffer(&self) -> bool {
+        self.status_report & 2 != 0
+    }
+
+    /// `$23`, a set bit homes that axis toward its min switch.
+    fn homing_dir_mask(&self) -> u8 {
+        to_mask(self.homing.side.map(|s| *s == LimitSide::Min))
+    }
+
+    pub fn get(&self, id: u16) -> Option<SettingValue> {
+        let value = match id {
+            0 => SettingValue::Integer(self.step_pulse),
+            1 => SettingValue::Integer(self.step_idle_delay),
+            2 => SettingValue::Integer(to_mask(self.step_invert)),
+            3 => SettingValue::Integer(to_mask(self.dir_invert)),
+            10 => SettingValue::Integer(self.status_report),
+            20 => SettingValue::Integer(self.soft_limits as u8),
+            21 => SettingValue::Integer(self.hard_limits as u8),
+            22 => SettingValue::Integer(self.homing_enabled as u8),
+            23 => SettingValue::Integer(self.homing_dir_mask()),
+            24 => SettingValue::Float(self.homing.locate_rate),
+            25 => SettingValue::Float(self.homing.seek_rate),
+            27 => SettingValue::Float(self.homing.pull_off),
+            29 => SettingValue::Integer(self.dir_setup),
+            100..=102 => SettingValue::Float(*self.steps_per_mm.match_id(axis_setting(id)?)),
+            110..=112 => SettingValue::Float(*self.max_rate.match_id(axis_setting(id)?)),
+            120..=122 => SettingValue::Float(*self.acceleration.match_id(axis_setting(id)?)),
+            130..=132 => SettingValue::Float(*self.max_travel.match_id(axis_setting(id)?)),
+            140..=142 => SettingValue::Float(*self.jerk.match_id(axis_setting(id)?)),
+            150..=152 => SettingValue::Float(*self.homing.trim[0].match_id(axis_setting(id)?)),
+            160..=162 => SettingValue::Float(*self.homing.trim[1].match_id(axis_setting(id)?)),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    pub fn set(&mut self, id: u16, value: f32) -> Result<(), ErrorCode> {
+        // `$100=NaN` and `$110=inf` parse, neither is a setting.
+        if !value.is_finite() {
+            return Err(ErrorCode::InvalidStatement);
+        }
+        if value < 0.0 {
+            return Err(ErrorCode::NegativeValue);
+        }
+        let flag = value != 0.0;
+        let mask = value as u8;
+        match id {
+            0 if mask == 0 => return Err(ErrorCode::InvalidStatement), // no pulse at all.
+            0 => self.step_pulse = mask,
+            1 => self.step_idle_delay = mask,
+            2 => self.step_invert = from_mask(mask),
+            3 => self.dir_invert = from_mask(mask),
+            10 => self.status_report = mask,
+            20 => self.soft_limits = flag,
+            21 => self.hard_limits = flag,
+            22 => self.homing_enabled = flag,
+            23 => self.homing.side = from_mask(mask).map(|min| if *min { LimitSide::Min } else { LimitSide::Max }),
+            24 => self.homing.locate_rate = value,
+            25 => self.homing.seek_rate = value,
+            27 => self.homing.pull_off = value,
+            29 => self.dir_setup = mask,
+            100..=162 => {
+                let axis = axis_setting(id).ok_or(ErrorCode::InvalidStatement)?;
+                let target = match id / 10 {
+                    10 => &mut self.steps_per_mm,
+                    11 => &mut self.max_rate,
+                    12 => &mut self.acceleration,
+                    13 => &mut self.max_travel,
+                    14 => &mut self.jerk,
+                    15 => &mut self.homing.trim[0],
+                    16 => &mut self.homing.trim[1],
+                    _ => return Err(ErrorCode::InvalidStatement),
+                };
+                if value == 0.0 && !matches!(id / 10, 13 | 15 | 16) {
+                    return Err(ErrorCode::InvalidStatement); // would divide by zero.
+                }
+                *target.match_id_mut(axis) = value;
+            },
+            _ => return Err(ErrorCode::InvalidStatement),
+        }
+        Ok(())
+    }
+
+    /// Every setting in `$$` order.
+    pub fn iter(&self) -> impl Iterator<Item=(u16, SettingValue)> + '_ {
+        SETTING_IDS.iter().filter_map(|&id| self.get(id).map(|v| (id, v)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_round_trip() {
+        let mut settings = Settings::default();
+        for (id, value) in Settings::default().iter() {
+            assert_eq!(settings.set(id, value.as_f32()), Ok(()), "${}", id);
+        }
+        assert_eq!(settings, Settings::default());
+        assert_eq!(settings.iter().count(), SETTING_IDS.len());
+    }
+
+    #[test]
+    fn settings_axis_numbering() {
+        let mut settings = Settings::default();
+        settings.set(101, 160.0).unwrap();
+        settings.set(112, 300.0).unwrap();
+        settings.set(130, 250.0).unwrap();
+        assert_eq!(settings.steps_per_mm, XYZData { x: 80.0, y: 160.0, z: 80.0 });
+        assert_eq!(settings.max_rate.z, 300.0);
+        assert_eq!(settings.max_travel.x, 250.0);
+        assert_eq!(settings.get(101), Some(SettingValue::Float(160.0)));
+        settings.set(161, 0.25).unwrap();
+        assert_eq!(settings.homing.trim[1], XYZData { x: 0.0, y: 0.25, z: 0.0 });
+        assert_eq!(settings.set(150, 0.0), Ok(()), "No trim is fine.");
+    }
+
+    #[test]
+    fn settings_masks() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.get(23), Some(SettingValue::Integer(0b011)), "X and Y home to min.");
+        settings.set(3, 5.0).unwrap();
+        assert_eq!(settings.dir_invert, XYZData { x: true, y: false, z: true });
+        assert_eq!(settings.get(1), Some(SettingValue::Integer(25)));
+        settings.set(1, 300.0).unwrap();
+        assert_eq!(settings.step_idle_delay, 255, "Saturates at hold forever.");
+        assert!(!settings.report_buffer());
+        settings.set(10, 3.0).unwrap();
+        assert!(settings.report_buffer());
+        settings.set(23, 4.0).unwrap();
+        assert_eq!(settings.homing.side, XYZData { x: LimitSide::Max, y: LimitSide::Max, z: LimitSide::Min });
+        settings.set(2, 2.0).unwrap();
+        assert_eq!(settings.step_invert, XYZData { x: false, y: true, z: false });
+    }
+
+    #[test]
+    fn settings_step_signal() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.step_signal(), StepSignal::default());
+        settings.set(0, 5.0).unwrap();
+        settings.set(29, 8.0).unwrap();
+        assert_eq!(settings.step_signal(), StepSignal { pulse_us: 5, dir_setup_us: 8 });
+        assert_eq!(settings.set(0, 0.0), Err(ErrorCode::InvalidStatement));
+    }
+
+    #[test]
+    fn settings_rejects() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.set(1000, 1.0), Err(ErrorCode::InvalidStatement));
+        assert_eq!(settings.set(103, 1.0), Err(ErrorCode::InvalidStatement));
+        assert_eq!(settings.set(100, 0.0), Err(ErrorCode::InvalidStatement));
+        assert_eq!(settings.set(141, 0.0), Err(ErrorCode::InvalidStatement));
+        assert_eq!(settings.set(110, -1.0), Err(ErrorCode::NegativeValue));
+        assert_eq!(settings.set(100, f32::NAN), Err(ErrorCode::InvalidStatement));
+        assert_eq!(settings.set(110, f32::INFINITY), Err(ErrorCode::InvalidStatement));
+        assert_eq!(settings.set(120, f32::NEG_INFINITY), Err(ErrorCode::InvalidStatement));
+        assert_eq!(settings, Settings::default());
+    }
+
+    #[test]
+    fn settings_unit_conversion() {
+        let mut settings = Settings::default();
+        settings.set(102, 400.0).unwrap();
+        assert_eq!(settings.mm_to_steps(XYZId::X, 1.5), 120);
+        assert_eq!(settings.mm_to_steps(XYZId::Z, 1.5), 600);
+        assert_eq!(settings.steps_to_mm(XYZId::Z, 200), 0.5);
+        assert_eq!(settings.mm_per_min_to_steps(XYZId::X, 600.0), 800);
+        assert_eq!(settings.mm_per_min_to_steps(XYZId::X, 0.0), 1);
+    }
+}
@@ -1,87 +1,212 @@
-use crate::{StepIterator, XYZId};
+use crate::{time_reached, AccProfile, AccTable, StepIterator, XYZId};
 
 #[derive(Clone, Default)]
 pub struct StepperTiming {
-    pub next_update_time: u64,
+    /// `None` while stopped.
+    pub next_update_time: Option<u32>,
 }
 
-const SIGNAL_LENGTH: u32 = 30;
+/// How long the step pin is held high, and low at least, unless `$0` says otherwise.
+pub const SIGNAL_LENGTH: u32 = 30;
+
+/// Step pulse timing the drivers need, A4988 modules are happy with anything while external drivers like
+/// the DM542 want longer pulses and time for the direction to settle.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct StepSignal {
+    /// us the step pin is held active, and idle at least.
+    pub pulse_us: u32,
+    /// us from a direction change to the next rising edge.
+    pub dir_setup_us: u32,
+}
+
+impl Default for StepSignal {
+    fn default() -> Self { Self { pulse_us: SIGNAL_LENGTH, dir_setup_us: 0 } }
+}
 
 impl StepperTiming {
-    pub fn update_needed(&self, now: u64) -> bool {
-        self.next_update_time != 0 && now >= self.next_update_time
+    pub fn update_needed(&self, now: u32) -> bool {
+        self.next_update_time.is_some_and(|t| time_reached(now, t))
     }
 
     pub fn is_uninitialized(&self) -> bool {
-        self.next_update_time == 0
+        self.next_update_time.is_none()
     }
 
     pub fn uninit(&mut self) {
-        self.next_update_time = 0;
+        self.next_update_time = None;
+    }
+
+    pub fn start(&mut self, now: u32) {
+        self.next_update_time = Some(now);
     }
 
+    /// Only moves a started timing on.
     pub fn update(&mut self, delay: u32) {
-        self.next_update_time = self.next_update_time + delay as u64;
+        if let Some(t) = self.next_update_time.as_mut() {
+            *t = t.wrapping_add(delay);
+        }
     }
 }
 
 pub trait StepDir: Clone {
-    fn step(&mut self, axis: XYZId);
+    /// Write the step pin level, `Stepper` and `SegmentExecutor` have already applied the step invert.
+    fn step(&mut self, axis: XYZId, high: bool);
     fn dir(&mut self, axis: XYZId, direction: bool);
+    /// Energise the driver, off lets the motor turn freely.
+    fn enable(&mut self, axis: XYZId, on: bool);
+    /// `Some` parks one motor of a ganged axis with its step pin at that idle level, it sits out the axis'
+    /// pulses until `None`. For squaring, clones share it like they share the pins.
+    fn lock_motor(&mut self, _axis: XYZId, _motor: u8, _idle: Option<bool>) {}
+}
+
+/// Compare timer that runs `SegmentExecutor::step_interrupt`.
+pub trait StepTimer {
+    /// Fire the next interrupt `delay_us` after the one being handled, or from now if the timer was stopped.
+    /// A timer that can't count that far may fire early, the interrupt then only reschedules.
+    fn schedule(&mut self, delay_us: u32);
+    /// Nothing left to step.
+    fn stop(&mut self);
 }
 
-pub struct Stepper<SD: StepDir> {
+pub struct Stepper<SD: StepDir, P: AccProfile = AccTable> {
     axis: XYZId,
     step_dir_fn: SD,
     cycle_high: bool,
+    dir_invert: bool,
+    step_invert: bool,
+    signal: StepSignal,
     pub timing: StepperTiming,
-    pub step_iter: StepIterator,
+    pub step_iter: StepIterator<P>,
 }
 
 impl<SD: StepDir> Stepper<SD>{
-    pub fn new(axis: XYZId, step_dir_fn: SD, acc_table: &'static [u32]) -> Self {
+    pub fn new(axis: XYZId, step_dir_fn: SD, acc_table: &[u32]) -> Self {
+        Self::with_step_iter(axis, step_dir_fn, StepIterator::new(acc_table))
+    }
+}
+
+impl<SD: StepDir, P: AccProfile> Stepper<SD, P>{
+    pub fn with_profile(axis: XYZId, step_dir_fn: SD, profile: P) -> Self {
+        Self::with_step_iter(axis, step_dir_fn, StepIterator::with_profile(profile))
+    }
+
+    fn with_step_iter(axis: XYZId, step_dir_fn: SD, step_iter: StepIterator<P>) -> Self {
         return Self {
             axis,
             step_dir_fn,
             cycle_high: false,
+            dir_invert: false,
+            step_invert: false,
+            signal: StepSignal::default(),
             timing: Default::default(),
-            step_iter: StepIterator::new(acc_table),
+            step_iter,
         };
     }
 
     pub fn on_target(&self) -> bool { self.get_target() == self.get_position() && self.timing.is_uninitialized() }
-    pub fn get_target(&self) -> i32 { self.step_iter.target }
+    pub fn get_target(&self) -> i32 { self.step_iter.final_target() }
     pub fn get_position(&self) -> i32 { self.step_iter.position }
 
+    /// Redefine the current position, e.g. after homing. Only valid while stopped.
+    pub fn set_position(&mut self, position: i32) {
+        self.step_iter.position = position;
+        self.step_iter.stop();
+    }
+
+    pub fn set_profile(&mut self, profile: P) { self.step_iter.set_profile(profile); }
+
+    pub fn enable(&mut self, on: bool) { self.step_dir_fn.enable(self.axis, on); }
+
+    /// Flip the direction pin, for motors wired the other way round.
+    pub fn set_dir_invert(&mut self, invert: bool) { self.dir_invert = invert; }
+
+    /// Active low step inputs, e.g. external drivers wired common anode. Puts the pin at the new idle level
+    /// unless a pulse is out.
+    pub fn set_step_invert(&mut self, invert: bool) {
+        self.step_invert = invert;
+        if !self.cycle_high {
+            self.write_step(false);
+        }
+    }
+
+    pub fn set_signal(&mut self, signal: StepSignal) { self.signal = signal; }
+
+    /// Hold one motor of a ganged axis still while the others step.
+    pub fn lock_motor(&mut self, motor: u8, locked: bool) {
+        self.step_dir_fn.lock_motor(self.axis, motor, locked.then_some(self.step_invert));
+    }
+
+    fn write_step(&mut self, high: bool) {
+        self.step_dir_fn.step(self.axis, high != self.step_invert);
+    }
+
+    /// Safe mid-move, a reversal ramps down to a stop before the direction pin changes.
     pub fn set_target(&mut self, target_step: i32, speed: u32) {
         let slew_delay_us = 1_000_000 / speed;
         self.step_iter.set_target(target_step, slew_delay_us, 0);
-        self.step_dir_fn.dir(self.axis, self.step_iter.direction.is_negative());
+        self.write_dir();
+    }
+
+    fn write_dir(&mut self) {
+        self.step_dir_fn.dir(self.axis, self.step_iter.direction.is_negative() != self.dir_invert);
+    }
+
+    /// Abandon the current move. A step that was counted but not yet pulsed is taken back off the position.
+    pub fn stop(&mut self) {
+        if self.cycle_high {
+            self.write_step(false);
+            self.cycle_high = false;
+        }
+        else if !self.timing.is_uninitialized() {
+            self.step_iter.position -= self.step_iter.direction as i32;
+        }
+        self.step_iter.stop();
+        self.timing.uninit();
     }
 
     fn step(&mut self) {
-        self.step_dir_fn.step(self.axis);
-        if !self.cycle_high {
-            self.cycle_high = true;
-            self.timing.update(SIGNAL_LENGTH);
+        let pulse = self.signal.pulse_us;
+        self.cycle_high = !self.cycle_high;
+        self.write_step(self.cycle_high);
+        if self.cycle_high {
+            self.timing.update(pulse);
         }
-        else if let Some(delay) = self.step_iter.next() {
-            self.timing.update((delay.saturating_sub(SIGNAL_LENGTH)).max(SIGNAL_LENGTH));
-            self.cycle_high = false;
+        else if let Some(delay) = self.next_delay() {
+            self.timing.update((delay.saturating_sub(pulse)).max(pulse));
         }
         else {
             self.timing.uninit();
-            self.cycle_high = false;
         }
     }
 
-    pub fn poll_task(&mut self, now: u64) {
+    /// The step pin is low here, a reversal's direction change gets at least the setup time before the next
+    /// rising edge. The delay counts from the last rising edge, so the pulse is added on.
+    fn next_delay(&mut self) -> Option<u32> {
+        let direction = self.step_iter.direction;
+        let delay = self.step_iter.next()?;
+        if self.step_iter.direction != direction {
+            self.write_dir();
+            return Some(delay.max(self.signal.pulse_us + self.signal.dir_setup_us));
+        }
+        Some(delay)
+    }
+
+    /// For `SegmentPrep`, the delay to the next step and the direction pin level for it. The pins are left
+    /// to `SegmentExecutor`.
+    pub fn next_step(&mut self) -> Option<(u32, bool)> {
+        let delay = self.step_iter.next()?;
+        Some((delay, self.step_iter.direction.is_negative() != self.dir_invert))
+    }
+
+    pub fn poll_task(&mut self, now: u32) {
         if self.timing.update_needed(now) {
             self.step();
         }
         else if self.timing.is_uninitialized() && !self.on_target() { // first step calc.
-            self.timing.next_update_time = now;
-            self.timing.update(self.step_iter.next().unwrap_or(0));
+            self.timing.start(now);
+            // The direction may only just have been written by `set_target`.
+            let delay = self.next_delay().unwrap_or(0);
+            self.timing.update(delay.max(self.signal.dir_setup_us));
             self.cycle_high = false;
         }
     }
@@ -97,6 +222,9 @@ mod tests {
         let mut timing = StepperTiming::default();
         assert_eq!(timing.is_uninitialized(), true);
         timing.update(10);
+        assert!(timing.is_uninitialized(), "Only a started timing moves on.");
+        timing.start(0);
+        timing.update(10);
         assert_eq!(timing.is_uninitialized(), false);
         timing.uninit();
         assert_eq!(timing.is_uninitialized(), true);
@@ -107,6 +235,7 @@ mod tests {
         let mut timing = StepperTiming::default();
         assert_eq!(timing.update_needed(0), false);
         assert_eq!(timing.update_needed(1000), false);
+        timing.start(0);
         timing.update(110);
         assert_eq!(timing.update_needed(99), false);
         assert_eq!(timing.update_needed(100), false);
@@ -120,6 +249,7 @@ mod tests {
         let mut timing = StepperTiming::default();
         assert_eq!(timing.update_needed(0), false);
         assert_eq!(timing.update_needed(1000), false);
+        timing.start(0);
         timing.update(100);
         timing.update(10);
         assert_eq!(timing.update_needed(99), false);
@@ -129,14 +259,32 @@ mod tests {
         assert_eq!(timing.update_needed(111), true);
     }
 
+    #[test]
+    fn timing_updates_across_wrap() {
+        let mut timing = StepperTiming::default();
+        timing.start(u32::MAX - 50);
+        timing.update(100);
+        assert_eq!(timing.next_update_time, Some(49));
+        assert!(!timing.update_needed(u32::MAX));
+        assert!(!timing.update_needed(48));
+        assert!(timing.update_needed(49));
+        assert!(timing.update_needed(1000));
+    }
+
     #[derive(Default, Clone, Copy, Debug)]
     struct CounterStepper {
         pub current_step: u32,
         pub current_dir: bool,
+        pub enabled: bool,
+        pub level: bool,
     }
     impl StepDir for CounterStepper {
-        fn step(&mut self, _: XYZId) { self.current_step += 1; }
+        fn step(&mut self, _: XYZId, high: bool) {
+            self.current_step += 1;
+            self.level = high;
+        }
         fn dir(&mut self, _: XYZId, direction: bool) { self.current_dir = direction; }
+        fn enable(&mut self, _: XYZId, on: bool) { self.enabled = on; }
     }
 
     static ACC_TABLE: &[u32] = &[10, 9, 8, 7, 6, 5, 4, 3, 2, 1 ];
@@ -158,22 +306,22 @@ mod tests {
         // first check should set the first update time, but not increase step counter.
         stepper.poll_task(100); // start at 100 time.
         assert_eq!(stepper.step_dir_fn.current_step, 0);
-        assert_eq!(stepper.timing.next_update_time, 200);
+        assert_eq!(stepper.timing.next_update_time, Some(200));
         assert_eq!(stepper.cycle_high, false);
         // check early, don't step
         stepper.poll_task(110);
         assert_eq!(stepper.step_dir_fn.current_step, 0);
-        assert_eq!(stepper.timing.next_update_time, 200);
+        assert_eq!(stepper.timing.next_update_time, Some(200));
         assert_eq!(stepper.cycle_high, false);
         // at time for step, should not be at target because of off signal length pulse time.
         stepper.poll_task(200);
         assert_eq!(stepper.step_dir_fn.current_step, 1);
         assert_eq!(stepper.step_dir_fn.current_dir, false);
         assert_eq!(stepper.cycle_high, true);
-        assert_eq!(stepper.timing.next_update_time as u32, 200 + SIGNAL_LENGTH);
+        assert_eq!(stepper.timing.next_update_time, Some(200 + SIGNAL_LENGTH));
         assert_eq!(stepper.on_target(), false);
         // toggle pin off, should clear time.
-        stepper.poll_task(200 + SIGNAL_LENGTH as u64);
+        stepper.poll_task(200 + SIGNAL_LENGTH);
         assert_eq!(stepper.step_dir_fn.current_step, 2);
         assert_eq!(stepper.step_dir_fn.current_dir, false);
         assert_eq!(stepper.cycle_high, false);
@@ -198,21 +346,132 @@ mod tests {
         assert_eq!(stepper.timing.is_uninitialized(), true);
     }
 
+    #[test]
+    fn stepper_steps_across_wrap() {
+        let mut stepper = Stepper::<CounterStepper>::new(XYZId::X, CounterStepper::default(), ACC_TABLE);
+        stepper.set_target(2, 10_000); // 1 step every 100.
+        let start = u32::MAX - 150;
+        stepper.poll_task(start);
+        stepper.poll_task(start + 99);
+        assert_eq!(stepper.step_dir_fn.current_step, 0, "Not due yet, just before the wrap.");
+        stepper.poll_task(start + 100); // first rising edge.
+        stepper.poll_task(start + 130); // first falling edge.
+        assert_eq!(stepper.timing.next_update_time, Some(49), "Second step due past the wrap.");
+        stepper.poll_task(u32::MAX);
+        assert_eq!(stepper.step_dir_fn.current_step, 2);
+        stepper.poll_task(49); // second rising edge.
+        stepper.poll_task(79); // second falling edge.
+        assert_eq!(stepper.step_dir_fn.current_step, 4);
+        assert!(stepper.on_target());
+    }
+
     #[test]
     fn stepper_faster_than_signal_length() {
         let mut stepper = Stepper::<CounterStepper>::new(XYZId::X, CounterStepper::default(), ACC_TABLE);
         assert_eq!(stepper.on_target(), true);
         stepper.set_target(2, 100_000); // 1 step every 10.
         stepper.poll_task(100);
-        stepper.poll_task(100u64 + (SIGNAL_LENGTH * 1) as u64); // requested 10, bumped to 30 for signal length.
-        stepper.poll_task(100u64 + (SIGNAL_LENGTH * 2) as u64); // falling edge
-        stepper.poll_task(100u64 + (SIGNAL_LENGTH * 3) as u64); // last rising edge.
-        stepper.poll_task(100u64 + (SIGNAL_LENGTH * 4) as u64); // falling edge
+        stepper.poll_task(100 + SIGNAL_LENGTH * 1); // requested 10, bumped to 30 for signal length.
+        stepper.poll_task(100 + SIGNAL_LENGTH * 2); // falling edge
+        stepper.poll_task(100 + SIGNAL_LENGTH * 3); // last rising edge.
+        stepper.poll_task(100 + SIGNAL_LENGTH * 4); // falling edge
         assert_eq!(stepper.step_dir_fn.current_step, 4);
         assert_eq!(stepper.cycle_high, false);
         assert_eq!(stepper.on_target(), true);
     }
 
+    #[test]
+    fn stepper_stop_mid_move() {
+        let mut stepper = Stepper::<CounterStepper>::new(XYZId::X, CounterStepper::default(), ACC_TABLE);
+        stepper.set_target(5, 10_000);
+        stepper.poll_task(100); // first step scheduled, position counted.
+        stepper.poll_task(200); // rising edge.
+        stepper.poll_task(230); // falling edge, second step scheduled.
+        stepper.stop();
+        assert_eq!(stepper.get_position(), 1, "Only the pulsed step should count.");
+        assert!(stepper.on_target());
+        assert!(!stepper.cycle_high);
+
+        stepper.set_target(5, 10_000);
+        stepper.poll_task(300);
+        stepper.poll_task(400); // rising edge.
+        stepper.stop();
+        assert_eq!(stepper.step_dir_fn.current_step, 4, "Stopping while high should pull the step pin back low.");
+        assert_eq!(stepper.get_position(), 2);
+        assert!(stepper.on_target());
+    }
+
+    #[test]
+    fn stepper_dir_invert() {
+        let mut stepper = Stepper::<CounterStepper>::new(XYZId::X, CounterStepper::default(), ACC_TABLE);
+        stepper.set_target(-1, 10_000);
+        assert!(stepper.step_dir_fn.current_dir);
+        stepper.set_dir_invert(true);
+        stepper.set_target(-1, 10_000);
+        assert!(!stepper.step_dir_fn.current_dir);
+        stepper.set_target(1, 10_000);
+        assert!(stepper.step_dir_fn.current_dir);
+    }
+
+    #[test]
+    fn stepper_step_invert() {
+        let mut stepper = Stepper::<CounterStepper>::new(XYZId::X, CounterStepper::default(), ACC_TABLE);
+        stepper.set_step_invert(true);
+        assert!(stepper.step_dir_fn.level, "Idles high.");
+        stepper.set_target(1, 10_000);
+        stepper.poll_task(100);
+        stepper.poll_task(200);
+        assert!(!stepper.step_dir_fn.level, "Pulses low.");
+        stepper.poll_task(200 + SIGNAL_LENGTH);
+        assert!(stepper.step_dir_fn.level);
+        assert!(stepper.on_target());
+    }
+
+    #[test]
+    fn stepper_signal_timing() {
+        let mut stepper = Stepper::<CounterStepper>::new(XYZId::X, CounterStepper::default(), ACC_TABLE);
+        stepper.set_signal(StepSignal { pulse_us: 5, dir_setup_us: 150 });
+        stepper.set_target(1, 10_000); // 1 step every 100.
+        stepper.poll_task(100);
+        assert_eq!(stepper.timing.next_update_time, Some(250), "Direction setup before the first step.");
+        stepper.poll_task(250);
+        assert_eq!(stepper.timing.next_update_time, Some(255), "Pulse length from the signal.");
+        stepper.poll_task(255);
+        // Back the other way, after a full stop the first step waits for the direction again.
+        stepper.set_target(0, 10_000);
+        stepper.poll_task(1000);
+        assert!(stepper.step_dir_fn.current_dir);
+        assert_eq!(stepper.timing.next_update_time, Some(1150));
+    }
+
+    #[test]
+    fn stepper_reverse_mid_move() {
+        let mut stepper = Stepper::<CounterStepper>::new(XYZId::X, CounterStepper::default(), ACC_TABLE);
+        stepper.set_target(100, 1_000_000);
+        let mut now = 0;
+        while stepper.get_position() < 20 {
+            now += 10;
+            stepper.poll_task(now);
+        }
+        stepper.set_target(0, 1_000_000);
+        assert!(!stepper.step_dir_fn.current_dir, "Still heading up.");
+        assert_eq!(stepper.get_target(), 0);
+        let mut furthest = 0;
+        let mut flipped_at = None;
+        while !stepper.on_target() {
+            now += 10;
+            stepper.poll_task(now);
+            furthest = furthest.max(stepper.get_position());
+            if stepper.step_dir_fn.current_dir && flipped_at.is_none() {
+                flipped_at = Some(stepper.get_position());
+            }
+        }
+        assert!(furthest > 20, "Ramped down past the reversal point.");
+        assert_eq!(flipped_at, Some(furthest - 1), "Direction changes as the first step back is scheduled.");
+        assert_eq!(stepper.get_position(), 0);
+        assert_eq!(stepper.step_dir_fn.current_step, 4 * furthest as u32, "Every step pulsed, up and back.");
+    }
+
     #[test]
     fn stepper_step_loop() {
         let mut stepper = Stepper::<CounterStepper>::new(XYZId::X, CounterStepper::default(), ACC_TABLE);
@@ -1,3 +1,4 @@
+use arrayvec::ArrayVec;
 use const_soft_float::soft_f32;
 // https://www.littlechip.co.nz/blog/a-simple-stepper-motor-control-algorithm
 #[allow(unused)]
@@ -74,19 +75,158 @@ pub const fn inter_step_dec_delay(previous_delay: u32, step_number: u32) -> u32
     //previous_delay as f32 * ((fourx - 1)/(fourx + 1))
 //}
 
+/// Source of the acceleration ramp delays for `StepIterator`.
+pub trait AccProfile {
+    /// Ramp for `acc` in steps/s^2, from standstill until the delay reaches `slew_delay_us`. `jerk` in
+    /// steps/s^3 only limits the jerk limited profiles, the trapezoids jump straight to `acc`.
+    fn build(acc: u32, jerk: u32, slew_delay_us: u32) -> Self where Self: Sized;
+    /// Number of ramp steps.
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool { self.len() == 0 }
+    /// Delay in us for ramp step `index`. `StepIterator` only ever moves one step up or down the ramp.
+    fn delay(&mut self, index: usize) -> u32;
+    /// First ramp step at or below `delay_us`, 0 if there is none.
+    fn index_for_delay(&mut self, delay_us: u32) -> usize {
+        (0..self.len()).position(|i| self.delay(i) <= delay_us).unwrap_or(0)
+    }
+}
+
+/// Entries per axis ramp, 4 bytes each. A ramp that needs more is cut short, which caps the axis speed
+/// at the last entry.
+pub const ACC_TABLE_CAPACITY: usize = 192;
+pub type AccTable = ArrayVec<u32, ACC_TABLE_CAPACITY>;
+
+/// Same ramp as `create_array`, built at runtime for `acc` in steps/s^2 up to `slew_delay_us`.
+pub fn build_acc_table(acc: u32, slew_delay_us: u32) -> AccTable {
+    let mut table = AccTable::new();
+    let mut delay = first_step_delay::<1_000_000>(acc);
+    loop {
+        table.push(delay);
+        let next = inter_step_acc_delay(delay, table.len() as u32);
+        if next <= slew_delay_us || table.is_full() {
+            return table;
+        }
+        delay = next;
+    }
+}
+
+impl AccProfile for AccTable {
+    fn build(acc: u32, _jerk: u32, slew_delay_us: u32) -> Self { build_acc_table(acc, slew_delay_us) }
+    fn len(&self) -> usize { self.as_slice().len() }
+    fn delay(&mut self, index: usize) -> u32 { self[index] }
+}
+
+/// AVR446 ramp computed one step at a time, `c_n = c_{n-1} - 2c_{n-1}/(4n+1)`. The division remainder is
+/// carried into the next step so the integer maths doesn't drift. No table, so no RAM per ramp step and no
+/// length limit.
+#[derive(Clone, Debug)]
+pub struct ComputedRamp {
+    first_delay: u32,
+    len: usize,
+    index: usize,
+    delay: u32,
+    remainder: u32,
+    accelerating: bool,
+}
+
+impl ComputedRamp {
+    fn accelerate(delay: u32, remainder: u32, n: usize) -> (u32, u32) {
+        let numerator = 2 * delay + remainder;
+        let denominator = 4 * n as u32 + 1;
+        (delay - numerator / denominator, numerator % denominator)
+    }
+
+    /// Inverse of `accelerate`, from step `n` back to `n - 1`.
+    fn decelerate(delay: u32, remainder: u32, n: usize) -> (u32, u32) {
+        let numerator = 2 * delay + remainder;
+        let denominator = 4 * n as u32 - 1;
+        (delay + numerator / denominator, numerator % denominator)
+    }
+
+    fn seek(&mut self, index: usize) {
+        if index + 1 == self.index {
+            if self.accelerating {
+                self.remainder = 0;
+                self.accelerating = false;
+            }
+            (self.delay, self.remainder) = Self::decelerate(self.delay, self.remainder, self.index);
+        }
+        else {
+            if index != self.index + 1 || !self.accelerating {
+                // Anything but the next step up restarts from the bottom of the ramp.
+                (self.index, self.delay, self.remainder, self.accelerating) = (0, self.first_delay, 0, true);
+            }
+            while self.index < index {
+                (self.delay, self.remainder) = Self::accelerate(self.delay, self.remainder, self.index + 1);
+                self.index += 1;
+            }
+        }
+        self.index = index;
+    }
+}
+
+impl AccProfile for ComputedRamp {
+    fn build(acc: u32, _jerk: u32, slew_delay_us: u32) -> Self {
+        let first_delay = first_step_delay::<1_000_000>(acc);
+        let (mut delay, mut remainder, mut len) = (first_delay, 0, 1);
+        loop {
+            let (next, next_remainder) = Self::accelerate(delay, remainder, len);
+            // `acc_iteration` is a u16.
+            if next <= slew_delay_us || len == u16::MAX as usize {
+                break;
+            }
+            (delay, remainder, len) = (next, next_remainder, len + 1);
+        }
+        Self { first_delay, len, index: 0, delay: first_delay, remainder: 0, accelerating: true }
+    }
+
+    fn len(&self) -> usize { self.len }
+
+    fn delay(&mut self, index: usize) -> u32 {
+        if index != self.index {
+            self.seek(index);
+        }
+        self.delay
+    }
+
+    fn index_for_delay(&mut self, delay_us: u32) -> usize {
+        let mut walk = self.clone();
+        (0..self.len).position(|i| walk.delay(i) <= delay_us).unwrap_or(0)
+    }
+}
+
+/// The ramp `Machine` uses, a table per axis unless the `computed-ramp` feature trades it for maths per step
+/// or `s-curve` limits jerk.
+#[cfg(not(any(feature = "computed-ramp", feature = "s-curve")))]
+pub type Ramp = AccTable;
+#[cfg(all(feature = "computed-ramp", not(feature = "s-curve")))]
+pub type Ramp = ComputedRamp;
+#[cfg(feature = "s-curve")]
+pub type Ramp = crate::SCurveRamp;
+
 //#[derive()]
-pub struct StepIterator {
+pub struct StepIterator<P: AccProfile = AccTable> {
     pub target: i32,
     pub position: i32,
     pub direction: i8,
-    pub acc_iteration: u8,
-    acc_iteration_stop: u8,
+    pub acc_iteration: u16,
+    acc_iteration_stop: u16,
     slew_delay_us: u32,
-    acc_table: &'static [u32],
+    /// Target and `acc_iteration_stop` to carry on with once a reversal has come to a stop.
+    pending: Option<(i32, u16)>,
+    profile: P,
 }
 
-impl StepIterator {
-    pub fn new(acc_table: &'static [u32]) -> Self {
+impl StepIterator<AccTable> {
+    pub fn new(acc_table: &[u32]) -> Self {
+        Self::with_profile(acc_table.iter().copied().take(ACC_TABLE_CAPACITY).collect())
+    }
+
+    pub fn acc_table(&self) -> &[u32] { &self.profile }
+}
+
+impl<P: AccProfile> StepIterator<P> {
+    pub fn with_profile(profile: P) -> Self {
         Self {
             target: 0,
             position: 0,
@@ -94,43 +234,85 @@ impl StepIterator {
             acc_iteration: 0,
             acc_iteration_stop: 0,
             slew_delay_us: 0,
-            acc_table,
+            pending: None,
+            profile,
         }
     }
 
+    /// Swap the ramp, e.g. after an acceleration setting changed. Only while stopped.
+    pub fn set_profile(&mut self, profile: P) {
+        self.profile = profile;
+        self.acc_iteration = 0;
+    }
+
+    pub fn profile(&self) -> &P { &self.profile }
+
+    /// Can be called mid-move. A target behind the current position, or too close ahead to stop for, first
+    /// ramps down to a stop past the current position and only then heads for `target_step`.
     pub fn set_target(&mut self, target_step: i32, slew_delay_us: u32, stop_slew_us: u32) {
-        self.target = target_step;
-        let displacement = target_step - self.position;
-        self.direction = displacement.clamp(-1, 1) as i8;
+        let stop_iteration = if stop_slew_us == 0 { 0 } else { self.profile.index_for_delay(stop_slew_us) as u16 };
         self.slew_delay_us = slew_delay_us;
-        self.acc_iteration_stop = if stop_slew_us == 0 { 0 } else { self.acc_table.iter().position(|d| *d <= stop_slew_us).unwrap_or(0) as u8 };
+        let displacement = target_step - self.position;
+        let stopping_distance = self.acc_iteration as i32;
+        if self.direction != 0 && stopping_distance > 0 && displacement * (self.direction as i32) < stopping_distance {
+            self.pending = Some((target_step, stop_iteration));
+            self.target = self.position + self.direction as i32 * stopping_distance;
+            self.acc_iteration_stop = 0;
+        }
+        else {
+            self.pending = None;
+            self.aim(target_step, stop_iteration);
+        }
+    }
+
+    fn aim(&mut self, target_step: i32, stop_iteration: u16) {
+        self.target = target_step;
+        self.direction = (target_step - self.position).clamp(-1, 1) as i8;
+        self.acc_iteration_stop = stop_iteration;
+    }
+
+    /// Where the axis ends up, `target` may only be the stop before a reversal.
+    pub fn final_target(&self) -> i32 { self.pending.map_or(self.target, |(target, _)| target) }
+
+    pub fn slew_delay_us(&self) -> u32 { self.slew_delay_us }
+
+    pub fn stop(&mut self) {
+        self.target = self.position;
+        self.direction = 0;
+        self.acc_iteration = 0;
+        self.pending = None;
     }
 }
 
-impl Iterator for StepIterator {
+impl<P: AccProfile> Iterator for StepIterator<P> {
     type Item = u32;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.position == self.target {
-            return None;
+            let (target, stop_iteration) = self.pending.take()?;
+            self.aim(target, stop_iteration);
+            if self.position == self.target {
+                return None;
+            }
         }
         self.position += self.direction as i32;
         let distance = (self.target - self.position).abs();
+        let last = self.profile.len() as u16 - 1;
 
         // decelerating
         if distance <= (self.acc_iteration.saturating_sub(self.acc_iteration_stop)) as i32 - 1 {
-            self.acc_iteration = self.acc_iteration.saturating_sub(1).clamp(0, self.acc_table.len() as u8 - 1);
-            let delay = self.acc_table[self.acc_iteration as usize];
+            self.acc_iteration = self.acc_iteration.saturating_sub(1).clamp(0, last);
+            let delay = self.profile.delay(self.acc_iteration as usize);
             Some(delay)
         }
         // at speed
-        else if self.acc_table[self.acc_iteration as usize] <= self.slew_delay_us {
+        else if self.profile.delay(self.acc_iteration as usize) <= self.slew_delay_us {
             Some(self.slew_delay_us)
         }
         // accelerating
         else {
-            let delay = self.acc_table[self.acc_iteration as usize];
-            self.acc_iteration = self.acc_iteration.saturating_add(1).clamp(0, self.acc_table.len() as u8 - 1);
+            let delay = self.profile.delay(self.acc_iteration as usize);
+            self.acc_iteration = self.acc_iteration.saturating_add(1).clamp(0, last);
             Some(delay)
         }
     }
@@ -159,6 +341,7 @@ pub const fn create_array<const SIZE: usize>(acc: u32) -> [u32; SIZE] {
     return curve;
 }
 
+/// Ramp for the default settings.
 pub const ACC_CURVE_SIZE: usize = max_acc_size(ACCELERATION*RESOLUTION, 1_000_000 / (RESOLUTION * STEPPER_SPEED));
 pub const ACC_CURVE: [u32; ACC_CURVE_SIZE]  = create_array::<ACC_CURVE_SIZE>(ACCELERATION*RESOLUTION);
 
@@ -215,6 +398,55 @@ mod tests {
         assert_eq!(step_iter.next(), None);
     }
 
+    #[test]
+    fn iter_acc_reverse_mid_move() {
+        let mut step_iter = StepIterator::new(&[3, 2, 1]);
+        step_iter.set_target(6, 1, 0);
+        assert_eq!(step_iter.next(), Some(3));
+        assert_eq!(step_iter.next(), Some(2));
+        assert_eq!(step_iter.next(), Some(1));
+        step_iter.set_target(0, 1, 0);
+        assert_eq!(step_iter.direction, 1, "Keeps going until it has stopped.");
+        assert_eq!(step_iter.final_target(), 0);
+        assert_eq!(step_iter.next(), Some(2));
+        assert_eq!(step_iter.next(), Some(3));
+        assert_eq!(step_iter.position, 5);
+        assert_eq!(step_iter.next(), Some(3), "Reverses from a standstill.");
+        assert_eq!(step_iter.direction, -1);
+        let rest: ArrayVec<u32, 8> = step_iter.by_ref().collect();
+        assert_eq!(rest.as_slice(), &[2, 1, 2, 3]);
+        assert_eq!(step_iter.position, 0);
+        assert_eq!(step_iter.acc_iteration, 0);
+    }
+
+    #[test]
+    fn iter_acc_retarget_too_close() {
+        let mut step_iter = StepIterator::new(&[3, 2, 1]);
+        step_iter.set_target(6, 1, 0);
+        step_iter.next();
+        step_iter.next();
+        step_iter.next();
+        step_iter.set_target(4, 1, 0); // one step ahead, two needed to stop.
+        let delays: ArrayVec<u32, 8> = step_iter.by_ref().collect();
+        assert_eq!(delays.as_slice(), &[2, 3, 3]);
+        assert_eq!(step_iter.position, 4, "Overshoots to 5 and comes back.");
+    }
+
+    #[test]
+    fn iter_acc_retarget_ahead_while_stopping() {
+        let mut step_iter = StepIterator::new(&[3, 2, 1]);
+        step_iter.set_target(6, 1, 0);
+        step_iter.next();
+        step_iter.next();
+        step_iter.next();
+        step_iter.set_target(0, 1, 0);
+        assert_eq!(step_iter.next(), Some(2));
+        step_iter.set_target(10, 1, 0);
+        assert_eq!(step_iter.final_target(), 10, "Changed its mind, no reversal.");
+        assert_eq!(step_iter.next(), Some(2));
+        assert_eq!(step_iter.next(), Some(1));
+    }
+
     #[test]
     fn iter_acc_max_speed() {
         let mut step_iter = StepIterator::new(&[3, 2, 1]);
@@ -362,6 +594,107 @@ mod tests {
     const MAX_SIZE: usize = max_acc_size(ACC*RES, 1_000_000 / (RES*SPEED));
     const ACC_TEST_TABLE: [u32; MAX_SIZE]  = create_array::<MAX_SIZE>(ACC*RES);
 
+    #[test]
+    fn runtime_table_matches_const() {
+        let table = build_acc_table(ACC*RES, 1_000_000 / (RES*SPEED));
+        assert_eq!(table.as_slice(), ACC_TEST_TABLE.as_slice());
+        let table = build_acc_table(ACCELERATION*RESOLUTION, 1_000_000 / (RESOLUTION*STEPPER_SPEED));
+        assert_eq!(table.as_slice(), ACC_CURVE.as_slice());
+    }
+
+    #[test]
+    fn runtime_table_capped() {
+        let table = build_acc_table(10, 1);
+        assert_eq!(table.len(), ACC_TABLE_CAPACITY);
+        assert!(table.windows(2).all(|w| w[1] <= w[0]), "Delays only shrink while accelerating.");
+    }
+
+    #[test]
+    fn runtime_table_lower_acc() {
+        let fast = build_acc_table(600*80, 2000);
+        let slow = build_acc_table(150*80, 2000);
+        assert!(!slow.is_full());
+        assert!(slow[0].abs_diff(fast[0] * 2) <= 1, "A quarter of the acceleration doubles the first delay.");
+        assert!(slow.len() > fast.len() * 3, "and takes about four times the steps to reach speed.");
+    }
+
+    /// The recurrence in floating point, what both ramps approximate.
+    fn exact_ramp(acc: u32, len: usize) -> ArrayVec<f32, 4096> {
+        let mut delay = first_step_delay::<1_000_000>(acc) as f32;
+        (0..len).map(|n| {
+            if n > 0 {
+                delay *= (4 * n - 1) as f32 / (4 * n + 1) as f32;
+            }
+            delay
+        }).collect()
+    }
+
+    #[test]
+    fn computed_ramp_tracks_recurrence() {
+        for (acc, slew) in [(ACC*RES, 1_000_000 / (RES*SPEED)), (600*80, 500), (150*80, 2000), (50*400, 300)] {
+            let mut ramp = ComputedRamp::build(acc, 0, slew);
+            let exact = exact_ramp(acc, ramp.len() + 8);
+            let exact_len = exact.iter().position(|&d| d <= slew as f32).unwrap();
+            assert!(ramp.len().abs_diff(exact_len) <= exact_len / 100 + 2, "{} vs {} steps", ramp.len(), exact_len);
+            for (i, &expected) in exact.iter().enumerate().take(ramp.len()) {
+                let delay = ramp.delay(i) as f32;
+                assert!((delay - expected).abs() <= expected * 0.005 + 1.0, "step {}: {} vs {}", i, delay, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn computed_ramp_matches_table() {
+        // The table truncates every step, so it runs a little ahead of the computed ramp.
+        let slew = 1_000_000 / (RES*SPEED);
+        let table = build_acc_table(ACC*RES, slew);
+        let mut ramp = ComputedRamp::build(ACC*RES, 0, slew);
+        assert!(ramp.len() >= table.len() && ramp.len() <= table.len() * 115 / 100, "{} vs {} steps", ramp.len(), table.len());
+        for (i, &expected) in table.iter().enumerate() {
+            let delay = ramp.delay(i);
+            assert!(delay >= expected && delay - expected <= expected * 7 / 100, "step {}: {} vs {}", i, delay, expected);
+        }
+    }
+
+    #[test]
+    fn computed_ramp_past_table_capacity() {
+        let mut ramp = ComputedRamp::build(150*80, 0, 100);
+        assert!(ramp.len() > ACC_TABLE_CAPACITY * 2);
+        let mut last = ramp.delay(0);
+        for i in 1..ramp.len() {
+            let delay = ramp.delay(i);
+            assert!(delay <= last && delay > 100, "step {}", i);
+            last = delay;
+        }
+    }
+
+    #[test]
+    fn computed_ramp_walks_back_down() {
+        let mut ramp = ComputedRamp::build(ACC*RES, 0, 1_000_000 / (RES*SPEED));
+        let up: ArrayVec<u32, 200> = (0..ramp.len()).map(|i| ramp.delay(i)).collect();
+        for i in (0..ramp.len()).rev() {
+            assert!(ramp.delay(i).abs_diff(up[i]) <= up[i] / 100 + 2, "step {}", i);
+        }
+        assert_eq!(ramp.delay(5), up[5], "Jumps restart from the first step.");
+        assert_eq!(ramp.index_for_delay(up[10]), 10);
+    }
+
+    #[test]
+    fn computed_ramp_move_matches_table_move() {
+        let slew = 1_000_000 / (RES*SPEED);
+        let mut table_iter = StepIterator::new(&build_acc_table(ACC*RES, slew));
+        let mut ramp_iter = StepIterator::with_profile(ComputedRamp::build(ACC*RES, 0, slew));
+        for target in [1000, 900, 1050, 0] {
+            table_iter.set_target(target, slew, 0);
+            ramp_iter.set_target(target, slew, 0);
+            let table_time: u32 = table_iter.by_ref().sum();
+            let ramp_time: u32 = ramp_iter.by_ref().sum();
+            assert_eq!(ramp_iter.position, target);
+            assert!(ramp_time.abs_diff(table_time) <= table_time * 2 / 100, "{} vs {} us", ramp_time, table_time);
+            assert_eq!(ramp_iter.acc_iteration, 0);
+        }
+    }
+
     #[test]
     fn timing_init_then_uninit() {
         //let table = ACC_TEST_TABLE;
@@ -0,0 +1,252 @@
+use arrayvec::ArrayVec;
+use crate::{Settings, ToolEntry, ToolTable, XYZData, SETTING_IDS, TOOL_COUNT};
+
+/// Byte addressed non-volatile memory, e.g. EEPROM.
+pub trait Storage {
+    fn read(&mut self, offset: u16, buffer: &mut [u8]);
+    fn write(&mut self, offset: u16, data: &[u8]);
+}
+
+/// Storage in RAM for host tests. Starts out erased (0xFF) like a new EEPROM.
+pub struct MemoryStorage<const SIZE: usize> {
+    pub data: [u8; SIZE],
+}
+
+impl<const SIZE: usize> Default for MemoryStorage<SIZE> {
+    fn default() -> Self { Self { data: [0xFF; SIZE] } }
+}
+
+impl<const SIZE: usize> Storage for MemoryStorage<SIZE> {
+    fn read(&mut self, offset: u16, buffer: &mut [u8]) {
+        let offset = offset as usize;
+        buffer.copy_from_slice(&self.data[offset..offset + buffer.len()]);
+    }
+
+    fn write(&mut self, offset: u16, data: &[u8]) {
+        let offset = offset as usize;
+        self.data[offset..offset + data.len()].copy_from_slice(data);
+    }
+}
+
+/*
+* Layout, each part is its own block so saving one doesn't rewrite the others.
+*   0    settings
+*   256  work offset
+*   320  tool table
+* A block is [version][payload length][payload][crc16 low][crc16 high], the crc covers everything before it.
+*/
+pub const SETTINGS_ADDRESS: u16 = 0;
+pub const WORK_OFFSET_ADDRESS: u16 = 256;
+pub const TOOL_TABLE_ADDRESS: u16 = 320;
+pub const STORAGE_SIZE: usize = 512;
+
+pub const SETTINGS_VERSION: u8 = 1;
+pub const WORK_OFFSET_VERSION: u8 = 1;
+pub const TOOL_TABLE_VERSION: u8 = 1;
+
+const MAX_PAYLOAD: usize = 216;
+type Payload = ArrayVec<u8, MAX_PAYLOAD>;
+
+/// CRC-16/CCITT-FALSE
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn block_crc(version: u8, payload: &[u8]) -> u16 {
+    let mut header = ArrayVec::<u8, { MAX_PAYLOAD + 2 }>::new();
+    header.push(version);
+    header.push(payload.len() as u8);
+    header.try_extend_from_slice(payload).unwrap();
+    crc16(&header)
+}
+
+fn write_block<S: Storage>(storage: &mut S, address: u16, version: u8, payload: &[u8]) {
+    let crc = block_crc(version, payload).to_le_bytes();
+    storage.write(address, &[version, payload.len() as u8]);
+    storage.write(address + 2, payload);
+    storage.write(address + 2 + payload.len() as u16, &crc);
+}
+
+/// The block's version and payload, `None` when empty or corrupt.
+fn read_block<S: Storage>(storage: &mut S, address: u16) -> Option<(u8, Payload)> {
+    let mut header = [0u8; 2];
+    storage.read(address, &mut header);
+    let [version, length] = header;
+    if length as usize > MAX_PAYLOAD {
+        return None;
+    }
+    let mut payload = Payload::new();
+    for _ in 0..length {
+        payload.push(0);
+    }
+    storage.read(address + 2, &mut payload);
+    let mut crc = [0u8; 2];
+    storage.read(address + 2 + length as u16, &mut crc);
+    (u16::from_le_bytes(crc) == block_crc(version, &payload)).then_some((version, payload))
+}
+
+fn push_f32(payload: &mut Payload, value: f32) {
+    payload.try_extend_from_slice(&value.to_le_bytes()).unwrap();
+}
+
+fn f32_at(payload: &[u8], index: usize) -> Option<f32> {
+    let bytes = payload.get(index * 4..index * 4 + 4)?;
+    Some(f32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// Settings are stored as `$n` id and value pairs. Older versions migrate by applying what they hold,
+/// any id they are missing keeps its default and ids that no longer exist are skipped.
+pub fn load_settings<S: Storage>(storage: &mut S) -> Settings {
+    let mut settings = Settings::default();
+    let Some((version, payload)) = read_block(storage, SETTINGS_ADDRESS) else {
+        return settings;
+    };
+    if version > SETTINGS_VERSION {
+        return settings; // written by newer firmware, don't guess.
+    }
+    for pair in payload.chunks_exact(6) {
+        let id = u16::from_le_bytes([pair[0], pair[1]]);
+        let value = f32::from_le_bytes([pair[2], pair[3], pair[4], pair[5]]);
+        let _ = settings.set(id, value);
+    }
+    settings
+}
+
+pub fn save_settings<S: Storage>(storage: &mut S, settings: &Settings) {
+    let mut payload = Payload::new();
+    for (id, value) in settings.iter() {
+        payload.try_extend_from_slice(&id.to_le_bytes()).unwrap();
+        push_f32(&mut payload, value.as_f32());
+    }
+    debug_assert_eq!(payload.len(), SETTING_IDS.len() * 6);
+    write_block(storage, SETTINGS_ADDRESS, SETTINGS_VERSION, &payload);
+}
+
+/// mm
+pub fn load_work_offset<S: Storage>(storage: &mut S) -> XYZData<f32> {
+    match read_block(storage, WORK_OFFSET_ADDRESS) {
+        Some((WORK_OFFSET_VERSION, payload)) => XYZData {
+            x: f32_at(&payload, 0).unwrap_or_default(),
+            y: f32_at(&payload, 1).unwrap_or_default(),
+            z: f32_at(&payload, 2).unwrap_or_default(),
+        },
+        _ => Default::default(),
+    }
+}
+
+pub fn save_work_offset<S: Storage>(storage: &mut S, offset: &XYZData<f32>) {
+    let mut payload = Payload::new();
+    for v in offset.iter() {
+        push_f32(&mut payload, *v);
+    }
+    write_block(storage, WORK_OFFSET_ADDRESS, WORK_OFFSET_VERSION, &payload);
+}
+
+pub fn load_tool_table<S: Storage>(storage: &mut S) -> ToolTable {
+    let mut table = ToolTable::default();
+    if let Some((TOOL_TABLE_VERSION, payload)) = read_block(storage, TOOL_TABLE_ADDRESS) {
+        for (i, entry) in table.entries.iter_mut().enumerate() {
+            if let (Some(length), Some(diameter)) = (f32_at(&payload, i * 2), f32_at(&payload, i * 2 + 1)) {
+                *entry = ToolEntry { length, diameter };
+            }
+        }
+    }
+    table
+}
+
+pub fn save_tool_table<S: Storage>(storage: &mut S, table: &ToolTable) {
+    let mut payload = Payload::new();
+    for entry in table.entries.iter() {
+        push_f32(&mut payload, entry.length);
+        push_f32(&mut payload, entry.diameter);
+    }
+    debug_assert_eq!(payload.len(), TOOL_COUNT * 8);
+    write_block(storage, TOOL_TABLE_ADDRESS, TOOL_TABLE_VERSION, &payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type TestStorage = MemoryStorage<STORAGE_SIZE>;
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn storage_erased_loads_defaults() {
+        let mut storage = TestStorage::default();
+        assert_eq!(load_settings(&mut storage), Settings::default());
+        assert_eq!(load_work_offset(&mut storage), XYZData::default());
+        assert_eq!(load_tool_table(&mut storage), ToolTable::default());
+    }
+
+    #[test]
+    fn storage_settings_round_trip() {
+        let mut storage = TestStorage::default();
+        let mut settings = Settings::default();
+        settings.set(100, 160.0).unwrap();
+        settings.set(3, 2.0).unwrap();
+        settings.set(21, 0.0).unwrap();
+        save_settings(&mut storage, &settings);
+        assert_eq!(load_settings(&mut storage), settings);
+    }
+
+    #[test]
+    fn storage_corrupt_falls_back() {
+        let mut storage = TestStorage::default();
+        let mut settings = Settings::default();
+        settings.set(100, 160.0).unwrap();
+        save_settings(&mut storage, &settings);
+        storage.data[SETTINGS_ADDRESS as usize + 5] ^= 0x10;
+        assert_eq!(load_settings(&mut storage), Settings::default());
+    }
+
+    #[test]
+    fn storage_newer_version_falls_back() {
+        let mut storage = TestStorage::default();
+        let mut payload = Payload::new();
+        payload.try_extend_from_slice(&100u16.to_le_bytes()).unwrap();
+        push_f32(&mut payload, 160.0);
+        write_block(&mut storage, SETTINGS_ADDRESS, SETTINGS_VERSION + 1, &payload);
+        assert_eq!(load_settings(&mut storage), Settings::default());
+    }
+
+    #[test]
+    fn storage_migrates_partial_settings() {
+        // An older layout holding only some ids, plus one this firmware no longer knows.
+        let mut storage = TestStorage::default();
+        let mut payload = Payload::new();
+        for (id, value) in [(101u16, 200.0f32), (999, 1.0), (20, 0.0)] {
+            payload.try_extend_from_slice(&id.to_le_bytes()).unwrap();
+            push_f32(&mut payload, value);
+        }
+        write_block(&mut storage, SETTINGS_ADDRESS, SETTINGS_VERSION, &payload);
+        let settings = load_settings(&mut storage);
+        assert_eq!(settings.steps_per_mm.y, 200.0);
+        assert!(!settings.soft_limits);
+        assert_eq!(settings.max_rate, Settings::default().max_rate);
+    }
+
+    #[test]
+    fn storage_blocks_independent() {
+        let mut storage = TestStorage::default();
+        let offset = XYZData { x: 1.5, y: -2.0, z: 0.25 };
+        let mut tools = ToolTable::default();
+        *tools.get_mut(2).unwrap() = ToolEntry { length: 30.0, diameter: 6.0 };
+        save_work_offset(&mut storage, &offset);
+        save_tool_table(&mut storage, &tools);
+        save_settings(&mut storage, &Settings::default());
+        assert_eq!(load_work_offset(&mut storage), offset);
+        assert_eq!(load_tool_table(&mut storage), tools);
+    }
+}
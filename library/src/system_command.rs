@@ -0,0 +1,57 @@
+/// `$` prefixed commands. These are handled by the machine directly instead of being queued as gcode.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SystemCommand {
+    Home,
+    /// `$$`
+    ReportSettings,
+    /// `$I`
+    BuildInfo,
+    /// `$<n>=<value>`
+    SetSetting(u16, f32),
+}
+
+fn parse_setting(line: &[u8]) -> Option<SystemCommand> {
+    let line = core::str::from_utf8(line.strip_prefix(b"$")?).ok()?;
+    let (id, value) = line.split_once('=')?;
+    Some(SystemCommand::SetSetting(id.trim().parse().ok()?, value.trim().parse().ok()?))
+}
+
+pub fn parse_system_command(line: &[u8]) -> Option<SystemCommand> {
+    let line = line.trim_ascii();
+    match line {
+        b"$H" | b"$h" => Some(SystemCommand::Home),
+        b"$$" => Some(SystemCommand::ReportSettings),
+        b"$I" | b"$i" => Some(SystemCommand::BuildInfo),
+        _ => parse_setting(line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_home() {
+        assert_eq!(parse_system_command(b"$H"), Some(SystemCommand::Home));
+        assert_eq!(parse_system_command(b"$H\r"), Some(SystemCommand::Home));
+        assert_eq!(parse_system_command(b" $h "), Some(SystemCommand::Home));
+    }
+
+    #[test]
+    fn parse_unknown() {
+        assert_eq!(parse_system_command(b"$"), None);
+        assert_eq!(parse_system_command(b"$Q"), None);
+        assert_eq!(parse_system_command(b"G0 X1"), None);
+        assert_eq!(parse_system_command(b"$100"), None);
+        assert_eq!(parse_system_command(b"$x=1"), None);
+        assert_eq!(parse_system_command(b"$100=abc"), None);
+    }
+
+    #[test]
+    fn parse_settings() {
+        assert_eq!(parse_system_command(b"$$"), Some(SystemCommand::ReportSettings));
+        assert_eq!(parse_system_command(b"$I"), Some(SystemCommand::BuildInfo));
+        assert_eq!(parse_system_command(b"$100=160.5"), Some(SystemCommand::SetSetting(100, 160.5)));
+        assert_eq!(parse_system_command(b"$3 = 5\r"), Some(SystemCommand::SetSetting(3, 5.0)));
+    }
+}
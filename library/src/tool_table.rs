@@ -0,0 +1,40 @@
+pub const TOOL_COUNT: usize = 8;
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct ToolEntry {
+    /// mm
+    pub length: f32,
+    /// mm
+    pub diameter: f32,
+}
+
+/// Tools are numbered from 1 like `T1`, `T0` is no tool.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct ToolTable {
+    pub entries: [ToolEntry; TOOL_COUNT],
+}
+
+impl ToolTable {
+    pub fn get(&self, tool: u8) -> Option<&ToolEntry> {
+        self.entries.get((tool as usize).checked_sub(1)?)
+    }
+
+    pub fn get_mut(&mut self, tool: u8) -> Option<&mut ToolEntry> {
+        self.entries.get_mut((tool as usize).checked_sub(1)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tool_numbering() {
+        let mut table = ToolTable::default();
+        assert!(table.get(0).is_none());
+        assert!(table.get(TOOL_COUNT as u8 + 1).is_none());
+        table.get_mut(1).unwrap().length = 12.5;
+        assert_eq!(table.entries[0].length, 12.5);
+        assert_eq!(table.get(TOOL_COUNT as u8), Some(&ToolEntry::default()));
+    }
+}
@@ -2,7 +2,7 @@ use core::{iter::once, ops::{Add, Mul, Sub}};
 
 use crate::ArgumentMnumonic;
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub enum XYZId { X, Y, Z }
 pub static XYZ_ID_LIST:[XYZId;3] = [XYZId::X, XYZId::Y, XYZId::Z];
 
@@ -12,7 +12,7 @@ pub enum XYZOne<T> {
     Y(T),
     Z(T),
 }
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct XYZData<T> {
     pub x: T,
     pub y: T,
@@ -132,6 +132,10 @@ impl<T> XYZData<T> {
     pub fn map<TR>(&self, p: impl Fn(&T) -> TR) -> XYZData<TR> {
         XYZData { x: p(&self.x), y: p(&self.y), z: p(&self.z) }
     }
+
+    pub fn map_id<TR>(&self, p: impl Fn(XYZId, &T) -> TR) -> XYZData<TR> {
+        XYZData { x: p(XYZId::X, &self.x), y: p(XYZId::Y, &self.y), z: p(XYZId::Z, &self.z) }
+    }
 }
 
 impl XYZId {